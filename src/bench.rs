@@ -0,0 +1,200 @@
+use std::time::{Duration, Instant};
+
+use chip8::config::COSMAC_VIP;
+
+use machine::{Machine, Stop, Halt};
+use rng::{RngKind, new_rng};
+
+const CLASS_NAMES: [&'static str; 16] = ["0NNN sys/cls/ret",
+                                         "1NNN jump",
+                                         "2NNN call",
+                                         "3XNN skip eq",
+                                         "4XNN skip ne",
+                                         "5XY0 skip eq",
+                                         "6XNN load",
+                                         "7XNN add",
+                                         "8XYN alu",
+                                         "9XY0 skip ne",
+                                         "ANNN load i",
+                                         "BNNN jump v0",
+                                         "CXNN random",
+                                         "DXYN draw",
+                                         "EXNN key skip",
+                                         "FXNN misc"];
+
+/// Only one instruction in this many is timed on its own. Reading the clock costs more
+/// than most instructions, so timing them all would swamp the overall rate.
+const SAMPLE_EVERY: u64 = 64;
+
+
+pub enum Limit {
+    Seconds(u64),
+    Instructions(u64),
+}
+
+pub struct BenchOptions {
+    pub limit: Limit,
+    pub instructions_per_frame: usize,
+    pub json: bool,
+}
+
+#[derive(Clone, Copy)]
+struct ClassStats {
+    count: u64,
+    /// Instructions that were timed, and the time they took.
+    sampled: u64,
+    time: Duration,
+}
+
+pub struct BenchReport {
+    pub instructions: u64,
+    pub frames: u64,
+    pub elapsed: Duration,
+    /// Set if the program halted before the limit.
+    pub halt: Option<Halt>,
+    classes: [ClassStats; 16],
+}
+
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1000000000.0
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1000000000 + d.subsec_nanos() as u64
+}
+
+/// Runs the program uncapped, ticking timers every `instructions_per_frame` instructions so
+/// that programs waiting on the delay timer still make progress. Stops early if the program
+/// halts, since nothing runs after that.
+pub fn run(program: &[u8], options: &BenchOptions) -> BenchReport {
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(RngKind::XorShift, 0));
+    machine.load_program(program);
+
+    let mut classes = [ClassStats {
+        count: 0,
+        sampled: 0,
+        time: Duration::new(0, 0),
+    }; 16];
+    let mut halt = None;
+    let mut frames = 0;
+    let mut in_frame = 0;
+    let start = Instant::now();
+    loop {
+        let done = match options.limit {
+            Limit::Instructions(n) => machine.instructions() >= n,
+            // Checking the clock is comparatively slow, so only do it once per frame.
+            Limit::Seconds(s) => in_frame == 0 && start.elapsed() >= Duration::new(s, 0),
+        };
+        if done {
+            break;
+        }
+
+        let timed = machine.instructions() % SAMPLE_EVERY == 0;
+        let before = if timed { Some(Instant::now()) } else { None };
+        match machine.step() {
+            Some(Stop::Halted(h)) => {
+                halt = Some(h);
+                break;
+            }
+            _ => (),
+        }
+        let class = &mut classes[(machine.last_opcode() >> 12) as usize];
+        class.count += 1;
+        if let Some(before) = before {
+            class.sampled += 1;
+            class.time += before.elapsed();
+        }
+
+        in_frame += 1;
+        if in_frame >= options.instructions_per_frame {
+            in_frame = 0;
+            frames += 1;
+            machine.timer_tick();
+        }
+    }
+
+    BenchReport {
+        instructions: machine.instructions(),
+        frames: frames,
+        elapsed: start.elapsed(),
+        halt: halt,
+        classes: classes,
+    }
+}
+
+impl ClassStats {
+    fn average_ns(&self) -> Option<f64> {
+        if self.sampled > 0 {
+            Some(nanos(self.time) as f64 / self.sampled as f64)
+        } else {
+            None
+        }
+    }
+}
+
+impl BenchReport {
+    pub fn per_second(&self) -> f64 {
+        let secs = seconds(self.elapsed);
+        if secs > 0.0 {
+            self.instructions as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let millions = self.instructions as f64 / 1000000.0;
+        let per_sec = self.per_second();
+        let mut out = format!("{:.1}M in {:.1}s: {:.1} /sec = {:.1} /frame\n",
+                              millions,
+                              seconds(self.elapsed),
+                              per_sec,
+                              per_sec / 60.0);
+        if let Some(halt) = self.halt {
+            out.push_str(&format!("halted early: {:?}\n", halt));
+        }
+        for (n, class) in self.classes.iter().enumerate() {
+            if class.count > 0 {
+                out.push_str(&format!("  {:<18} {:>12} {:>10}\n",
+                                      CLASS_NAMES[n],
+                                      class.count,
+                                      match class.average_ns() {
+                                          Some(ns) => format!("{:.1} ns avg", ns),
+                                          None => "-".to_string(),
+                                      }));
+            }
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let per_sec = self.per_second();
+        let mut classes = Vec::new();
+        for (n, class) in self.classes.iter().enumerate() {
+            if class.count > 0 {
+                classes.push(format!("{{\"class\": \"{:X}\", \"name\": \"{}\", \"count\": {}, \
+                                      \"sampled\": {}, \"sampled_ns\": {}}}",
+                                     n,
+                                     CLASS_NAMES[n],
+                                     class.count,
+                                     class.sampled,
+                                     nanos(class.time)));
+            }
+        }
+        let halt = match self.halt {
+            Some(halt) => format!("\"{:?}\"", halt),
+            None => "null".to_string(),
+        };
+        format!("{{\"instructions\": {}, \"frames\": {}, \"seconds\": {:.6}, \
+                 \"instructions_per_second\": {:.1}, \"instructions_per_frame\": {:.1}, \
+                 \"halt\": {}, \"classes\": [{}]}}",
+                self.instructions,
+                self.frames,
+                seconds(self.elapsed),
+                per_sec,
+                per_sec / 60.0,
+                halt,
+                classes.join(", "))
+    }
+}
@@ -0,0 +1,231 @@
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+
+use debugger::lines::Source;
+use disasm::{decode, listing_line};
+use machine::{Machine, Access};
+use symbols::Symbols;
+
+const ADDRESSES: usize = 0x10000;
+/// Where programs are loaded.
+const PROGRAM_START: u16 = 0x200;
+
+
+pub struct CoverageOptions {
+    /// Where to write the annotated disassembly.
+    pub listing: Option<String>,
+    /// Where to write lcov tracefile data for the program's source lines.
+    pub lcov: Option<String>,
+}
+
+/// Records which addresses of the program were executed, read and written, and writes
+/// the reports when the emulator exits.
+pub struct Coverage {
+    options: CoverageOptions,
+    executed: Vec<u64>,
+    read: Vec<bool>,
+    written: Vec<bool>,
+    /// End of the loaded program; the reports cover the addresses from `PROGRAM_START`.
+    end: u16,
+    source: Option<Source>,
+    symbols: Arc<Symbols>,
+}
+
+/// An instruction, or a byte that can't be one because execution only ever reached the
+/// byte after it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    addr: u16,
+    len: u16,
+    executed: u64,
+    read: bool,
+    written: bool,
+}
+
+impl Entry {
+    /// Never executed, but used as data, so it doesn't count as missed code.
+    fn is_data(&self) -> bool {
+        self.executed == 0 && (self.read || self.written)
+    }
+}
+
+
+fn write_file(path: &str, text: &str) {
+    let written = File::create(path).and_then(|mut file| file.write_all(text.as_bytes()));
+    if let Err(e) = written {
+        println!("coverage: {}: {}", path, e);
+    }
+}
+
+impl Coverage {
+    pub fn new(options: CoverageOptions,
+               program_len: usize,
+               source: Option<Source>,
+               symbols: Arc<Symbols>)
+               -> Coverage {
+        Coverage {
+            options: options,
+            executed: vec![0; ADDRESSES],
+            read: vec![false; ADDRESSES],
+            written: vec![false; ADDRESSES],
+            end: PROGRAM_START.saturating_add(program_len as u16),
+            source: source,
+            symbols: symbols,
+        }
+    }
+
+    /// Called by the machine before it runs each instruction.
+    pub fn record(&mut self, machine: &mut Machine, pc: u16, codeword: u16) {
+        self.executed[pc as usize] += 1;
+        if let Some(access) = machine.memory_access(decode(codeword)) {
+            let marks = match access.access {
+                Access::Read => &mut self.read,
+                Access::Write => &mut self.written,
+                Access::Pointer => return,
+            };
+            for n in 0..access.len {
+                marks[access.addr.wrapping_add(n) as usize] = true;
+            }
+        }
+    }
+
+    /// Splits `start..end` into instructions, following the alignment execution used.
+    fn entries(&self, start: u16, end: u16) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        let mut addr = start;
+        while addr < end {
+            let next = addr as usize + 1;
+            let len = if self.executed[addr as usize] == 0 && next < end as usize &&
+                         self.executed[next] > 0 {
+                1
+            } else {
+                (end - addr).min(2)
+            };
+            let bytes = addr as usize..addr as usize + len as usize;
+            entries.push(Entry {
+                addr: addr,
+                len: len,
+                executed: self.executed[addr as usize],
+                read: bytes.clone().any(|a| self.read[a]),
+                written: bytes.clone().any(|a| self.written[a]),
+            });
+            addr += len;
+        }
+        entries
+    }
+
+    /// The program's disassembly with how often each instruction ran. Never executed
+    /// instructions are marked `#####` like gcov does; `R` and `W` mark data that was read
+    /// or written. Each symbol gets a label line before its address.
+    pub fn listing(&self, memory: &[u8]) -> String {
+        let entries = self.entries(PROGRAM_START, self.end);
+        let code: Vec<&Entry> = entries.iter().filter(|e| !e.is_data()).collect();
+        let hit = code.iter().filter(|e| e.executed > 0).count();
+        let mut text = format!("; {} of {} instructions executed ({:.1}%)\n",
+                               hit,
+                               code.len(),
+                               if code.is_empty() {
+                                   0.0
+                               } else {
+                                   hit as f64 * 100.0 / code.len() as f64
+                               });
+        for entry in &entries {
+            if let Some(name) = self.symbols.name_of(entry.addr) {
+                text.push_str(&format!("{}:\n", name));
+            }
+            let count = if entry.executed > 0 {
+                entry.executed.to_string()
+            } else if entry.is_data() {
+                "-".to_string()
+            } else {
+                "#####".to_string()
+            };
+            let access = format!("{}{}",
+                                 if entry.read { "R" } else { "-" },
+                                 if entry.written { "W" } else { "-" });
+            let byte = |a: u16| memory.get(a as usize).cloned().unwrap_or(0) as u16;
+            let first = byte(entry.addr);
+            let line = if entry.len == 2 {
+                listing_line(entry.addr, first << 8 | byte(entry.addr + 1), &self.symbols)
+            } else {
+                format!("{:#05X}: {:02X}    DB {:#04X}", entry.addr, first, first)
+            };
+            text.push_str(&format!("{:>10}  {}  {}\n", count, access, line));
+        }
+        text
+    }
+
+    /// lcov tracefile data for the source lines, if the program was loaded from source.
+    /// Lines holding only data aren't counted.
+    pub fn lcov(&self) -> Option<String> {
+        let source = match self.source {
+            Some(ref source) => source,
+            None => return None,
+        };
+        let mut text = format!("TN:\nSF:{}\n", source.path);
+        let (mut found, mut hit) = (0, 0);
+        for (line, start, end) in source.lines.ranges() {
+            let entries = self.entries(start, end);
+            if entries.iter().all(|e| e.is_data()) {
+                continue;
+            }
+            let count: u64 = entries.iter().map(|e| e.executed).fold(0, |sum, n| sum + n);
+            found += 1;
+            if count > 0 {
+                hit += 1;
+            }
+            text.push_str(&format!("DA:{},{}\n", line, count));
+        }
+        text.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", found, hit));
+        Some(text)
+    }
+
+    /// Writes the reports named in the options.
+    pub fn write(&self, memory: &[u8]) {
+        if let Some(ref path) = self.options.listing {
+            write_file(path, &self.listing(memory));
+        }
+        if let Some(ref path) = self.options.lcov {
+            match self.lcov() {
+                Some(text) => write_file(path, &text),
+                None => {
+                    println!("coverage: no source lines for lcov; load the program from hex \
+                              source")
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_coverage_reports() {
+    use debugger::lines::LineMap;
+
+    let source = Source {
+        path: "game.txt".to_string(),
+        lines: LineMap::from_hex_source("0xA2, 0x06,\n0xD0, 0x11,\n0x12, 0x04,\n0xFF, 0xFF\n"),
+    };
+    let mut coverage = Coverage::new(CoverageOptions {
+                                         listing: None,
+                                         lcov: None,
+                                     },
+                                     8,
+                                     Some(source),
+                                     Arc::new(Symbols::parse("sprite = 0x206").unwrap()));
+    coverage.executed[0x202] = 3;
+    coverage.executed[0x204] = 3;
+    coverage.read[0x206] = true;
+    let memory = [0, 0, 0xA2, 0x06, 0xD0, 0x11, 0x12, 0x04, 0xFF, 0xFF];
+    let memory: Vec<u8> = vec![0; 0x1FE].into_iter().chain(memory.iter().cloned()).collect();
+
+    let listing = coverage.listing(&memory);
+    assert!(listing.starts_with("; 2 of 3 instructions executed (66.7%)\n"));
+    assert!(listing.contains("     #####  --  0x200: A206  LD I, sprite\n"));
+    assert!(listing.contains("         3  --  0x202: D011  DRW V0, V1, 1\n"));
+    assert!(listing.contains("sprite:\n         -  R-  0x206: FFFF"));
+
+    assert_eq!(coverage.lcov(),
+               Some("TN:\nSF:game.txt\nDA:1,0\nDA:2,3\nDA:3,3\nLF:3\nLH:2\nend_of_record\n"
+                   .to_string()));
+}
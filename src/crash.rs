@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+
+use debugger::console::{format_registers, hex_dump};
+use disasm::listing_line;
+use machine::{Machine, MEMORY_SIZE};
+use symbols::Symbols;
+
+/// Instructions kept for the report's history section.
+const HISTORY: usize = 32;
+
+
+/// What to do with `0NNN` machine code calls and opcodes no platform defines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpcodePolicy {
+    /// Leave them to the chip8 core, which does what the emulated platform would.
+    Platform,
+    /// Skip over them as if they were no-ops.
+    Ignore,
+    /// Halt for good and write a crash report.
+    Halt,
+    /// Stop like a breakpoint and write a crash report. Continuing leaves the opcode to the
+    /// core.
+    Break,
+}
+
+impl OpcodePolicy {
+    pub fn from_name(name: &str) -> Option<OpcodePolicy> {
+        match name {
+            "platform" => Some(OpcodePolicy::Platform),
+            "ignore" => Some(OpcodePolicy::Ignore),
+            "halt" => Some(OpcodePolicy::Halt),
+            "break" => Some(OpcodePolicy::Break),
+            _ => None,
+        }
+    }
+}
+
+/// Remembers the last instructions run, and writes them with the rest of the machine state
+/// when the program runs an illegal opcode.
+pub struct CrashReporter {
+    path: String,
+    /// Instruction count, PC and codeword of the latest instructions, oldest first.
+    history: VecDeque<(u64, u16, u16)>,
+    symbols: Arc<Symbols>,
+}
+
+impl CrashReporter {
+    pub fn new(path: &str, symbols: Arc<Symbols>) -> CrashReporter {
+        CrashReporter {
+            path: path.to_string(),
+            history: VecDeque::with_capacity(HISTORY),
+            symbols: symbols,
+        }
+    }
+
+    /// Called by the machine before it runs each instruction.
+    pub fn record(&mut self, machine: &mut Machine, pc: u16, codeword: u16) {
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((machine.instructions(), pc, codeword));
+    }
+
+    /// The report for the illegal opcode `codeword` at `pc`: registers and call stack, the
+    /// instructions that led there, and all of memory.
+    pub fn report(&self, machine: &mut Machine, pc: u16, codeword: u16) -> String {
+        let mut text = format!("illegal opcode {:04X} at {} after {} instructions\n\n",
+                               codeword,
+                               self.symbols.describe(pc),
+                               machine.instructions());
+        text.push_str("registers:\n");
+        text.push_str(&format_registers(&machine.registers(), &self.symbols));
+        text.push_str(&format!("\nlast {} instructions:\n", self.history.len()));
+        for &(index, pc, codeword) in &self.history {
+            text.push_str(&format!("{:>10}  {}\n",
+                                   index,
+                                   listing_line(pc, codeword, &self.symbols)));
+        }
+        text.push_str("\nmemory:\n");
+        text.push_str(&hex_dump(0, &machine.read_memory(0, MEMORY_SIZE)));
+        text
+    }
+
+    pub fn write(&self, machine: &mut Machine, pc: u16, codeword: u16) {
+        let text = self.report(machine, pc, codeword);
+        let written = File::create(&self.path).and_then(|mut file| file.write_all(text.as_bytes()));
+        match written {
+            Ok(()) => {
+                println!("illegal opcode {:04X} at {:#05X}; wrote {}", codeword, pc, self.path)
+            }
+            Err(e) => println!("crash report: {}: {}", self.path, e),
+        }
+    }
+}
+
+
+#[test]
+fn test_crash_report() {
+    use chip8::config::COSMAC_VIP;
+    use rng::{new_rng, RngKind};
+
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(RngKind::XorShift, 0));
+    machine.load_program(&[0x60, 0x2A, 0x01, 0x23]);
+    let mut reporter = CrashReporter::new("crash.txt", Arc::new(Symbols::new()));
+    for _ in 0..HISTORY + 1 {
+        reporter.record(&mut machine, 0x200, 0x602A);
+    }
+    assert_eq!(reporter.history.len(), HISTORY);
+
+    let report = reporter.report(&mut machine, 0x202, 0x0123);
+    assert!(report.starts_with("illegal opcode 0123 at 0x202 after 0 instructions\n\n"));
+    assert!(report.contains("\nlast 32 instructions:\n         0  0x200: 602A"));
+    assert!(report.contains("\n200: 60 2A 01 23 00"));
+}
@@ -0,0 +1,471 @@
+use std::io::{self, BufRead, Write};
+use std::thread;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender};
+
+use debugger::{DebugRequest, DebugReply, parse_register, request};
+use debugger::expr::Expr;
+use disasm::listing_line;
+use emulator::Command;
+use machine::{Stop, Registers, Watchpoint, WatchKind, WatchHit, Access, Breakpoint,
+              MEMORY_SIZE};
+use options::parse_number;
+use symbols::Symbols;
+
+const HELP: &'static str = "\
+commands:
+  c, continue          run until a breakpoint or stop
+  p, pause             pause
+  s, step [N]          run N instructions (default 1)
+  n, next              step, running a CALL through to its return
+  finish               run until the current subroutine returns
+  rs, reverse-step [N] go back N instructions (default 1)
+  rc, reverse-continue go back to the last breakpoint or watchpoint hit
+  r, regs              show registers and the call stack
+  x ADDR [LEN]         show memory (default 64 bytes)
+  set ADDR BYTE...     write memory
+  set REG VALUE        write v0-vf, i, pc, dt or st
+  d, dis [ADDR] [N]    disassemble N instructions around ADDR (default: PC)
+  b, break ADDR [hits N] [if EXPR]
+                       add a breakpoint; it only stops when EXPR isn't 0, from
+                       the Nth time it would
+  delete ADDR          remove a breakpoint
+  breakpoints          list breakpoints
+  watch ADDR [END]     stop before ADDR..END is written
+  rwatch ADDR [END]    stop before it is read (FX65, DXYN)
+  awatch ADDR [END]    stop before it is read or written
+  iwatch ADDR [END]    stop before I is pointed into it (ANNN, FX1E)
+  unwatch ADDR [END]   remove the watchpoints on ADDR..END
+  watchpoints          list watchpoints
+  print EXPR           evaluate an expression, like [i+2] + v3 * 2
+  q, quit              quit vipchip
+an empty line repeats the last command; an ADDR can be a number, a symbol or SYMBOL+N
+expressions use v0-vf, i, pc, dt, st, numbers, symbols, [ADDR] for the byte at ADDR,
+and C's operators";
+
+
+/// Starts the debugger console on its own thread. It reads commands from stdin and drives
+/// the emulation thread through `commands`, while the window keeps showing the screen.
+pub fn spawn(commands: Sender<Command>, symbols: Arc<Symbols>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let (events_tx, events_rx) = mpsc::channel();
+        if commands.send(Command::Debug(DebugRequest::Subscribe, events_tx)).is_err() {
+            return;
+        }
+        let event_symbols = symbols.clone();
+        thread::spawn(move || {
+            for event in events_rx.iter() {
+                if let DebugReply::Stopped { stop, pc, codeword } = event {
+                    println!("\n{}", format_stop(stop, pc, codeword, &event_symbols));
+                }
+            }
+        });
+
+        println!("vipchip debugger, type 'help' for commands");
+        let stdin = io::stdin();
+        let mut last = String::new();
+        loop {
+            print!("(vipchip) ");
+            io::stdout().flush().ok();
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => (),
+            }
+            let line = line.trim().to_string();
+            if !line.is_empty() {
+                last = line;
+            }
+            match run_command(&commands, &symbols, &last) {
+                Ok(true) => (),
+                Ok(false) => {
+                    commands.send(Command::Quit).ok();
+                    break;
+                }
+                Err(e) => println!("{}", e),
+            }
+        }
+    })
+}
+
+fn parse_addr(symbols: &Symbols, text: Option<&str>) -> Result<u16, String> {
+    match text {
+        Some(text) => symbols.parse_address(text),
+        None => Err("expected an address".to_string()),
+    }
+}
+
+/// Checks that `addr` and the `len` bytes from it are all inside memory.
+fn check_memory(addr: u16, len: usize) -> Result<(), String> {
+    if addr as usize >= MEMORY_SIZE {
+        Err(format!("{:#X} is outside memory", addr))
+    } else if addr as usize + len > MEMORY_SIZE {
+        Err(format!("{} bytes at {:#05X} run past the end of memory", len, addr))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parses `ADDR [END]`, where the end is inclusive and defaults to ADDR.
+fn parse_range(symbols: &Symbols, args: &[&str]) -> Result<(u16, u16), String> {
+    let start = try!(parse_addr(symbols, args.get(0).cloned()));
+    let end = match args.get(1) {
+        Some(&end) => try!(parse_addr(symbols, Some(end))),
+        None => start,
+    };
+    if end < start {
+        return Err("the end of a range can't be before its start".to_string());
+    }
+    Ok((start, end))
+}
+
+/// Parses the rest of a `break` command: `ADDR [hits N] [if EXPR]`.
+fn parse_breakpoint(symbols: &Symbols, args: &[&str]) -> Result<Breakpoint, String> {
+    let mut breakpoint = Breakpoint::new(try!(parse_addr(symbols, args.get(0).cloned())));
+    let mut rest = &args[1..];
+    if rest.first() == Some(&"hits") {
+        let n = try!(rest.get(1).ok_or("hits needs a count"));
+        breakpoint.hit_count = Some(try!(parse_number(n)) as u64);
+        rest = &rest[2..];
+    }
+    match rest.first() {
+        Some(&"if") => {
+            breakpoint.condition = Some(try!(Expr::parse(&rest[1..].join(" "), symbols)))
+        }
+        Some(word) => return Err(format!("expected 'hits' or 'if', not '{}'", word)),
+        None => (),
+    }
+    Ok(breakpoint)
+}
+
+fn format_breakpoint(breakpoint: &Breakpoint, symbols: &Symbols) -> String {
+    let mut text = symbols.describe(breakpoint.addr);
+    if let Some(n) = breakpoint.hit_count {
+        text.push_str(&format!(" hits {}", n));
+    }
+    if let Some(ref condition) = breakpoint.condition {
+        text.push_str(&format!(" if {}", condition));
+    }
+    text
+}
+
+/// Runs one command line. Returns `Ok(false)` when the user wants to quit.
+fn run_command(commands: &Sender<Command>,
+               symbols: &Symbols,
+               line: &str)
+               -> Result<bool, String> {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return Ok(true),
+    };
+    let args: Vec<&str> = words.collect();
+
+    let reply = match command {
+        "h" | "help" => {
+            println!("{}", HELP);
+            return Ok(true);
+        }
+        "q" | "quit" => return Ok(false),
+        "c" | "continue" => try!(request(commands, DebugRequest::Continue)),
+        "p" | "pause" => try!(request(commands, DebugRequest::Pause)),
+        "s" | "step" => {
+            let n = match args.first() {
+                Some(n) => try!(parse_number(n)) as usize,
+                None => 1,
+            };
+            try!(request(commands, DebugRequest::Step(n)))
+        }
+        "n" | "next" => try!(request(commands, DebugRequest::StepOver)),
+        "finish" => try!(request(commands, DebugRequest::StepOut)),
+        "rs" | "reverse-step" => {
+            let n = match args.first() {
+                Some(n) => try!(parse_number(n)) as usize,
+                None => 1,
+            };
+            try!(request(commands, DebugRequest::ReverseStep(n)))
+        }
+        "rc" | "reverse-continue" => try!(request(commands, DebugRequest::ReverseContinue)),
+        "r" | "regs" => try!(request(commands, DebugRequest::Registers)),
+        "x" => {
+            let addr = try!(parse_addr(symbols, args.get(0).cloned()));
+            let len = match args.get(1) {
+                Some(len) => try!(parse_number(len)) as usize,
+                None => 64,
+            };
+            try!(check_memory(addr, len));
+            try!(request(commands, DebugRequest::ReadMemory(addr, len)))
+        }
+        "set" => {
+            let target = *try!(args.get(0).ok_or("set what?"));
+            if args.len() < 2 {
+                return Err("set needs a value".to_string());
+            }
+            match parse_register(target) {
+                Some(register) => {
+                    let value = try!(parse_number(args[1])) as u16;
+                    try!(request(commands, DebugRequest::SetRegister(register, value)))
+                }
+                None => {
+                    let addr = try!(parse_addr(symbols, Some(target)));
+                    let mut bytes = Vec::new();
+                    for arg in &args[1..] {
+                        bytes.push(try!(parse_number(arg)) as u8);
+                    }
+                    try!(check_memory(addr, bytes.len()));
+                    try!(request(commands, DebugRequest::WriteMemory(addr, bytes)))
+                }
+            }
+        }
+        "d" | "dis" => {
+            let pc = match try!(request(commands, DebugRequest::Registers)) {
+                DebugReply::Registers(registers) => registers.pc,
+                _ => 0,
+            };
+            let count = match args.get(1) {
+                Some(n) => try!(parse_number(n)) as usize,
+                None => 10,
+            };
+            let start = match args.get(0) {
+                Some(&addr) => try!(parse_addr(symbols, Some(addr))),
+                // A few instructions before PC, assuming they're aligned with it.
+                None => pc.saturating_sub(8),
+            };
+            try!(check_memory(start, count.saturating_mul(2)));
+            let breakpoints = match try!(request(commands, DebugRequest::Breakpoints)) {
+                DebugReply::Breakpoints(breakpoints) => {
+                    breakpoints.iter().map(|b| b.addr).collect()
+                }
+                _ => Vec::new(),
+            };
+            match try!(request(commands, DebugRequest::ReadMemory(start, count * 2))) {
+                DebugReply::Memory(_, bytes) => {
+                    print!("{}", disassemble(start, &bytes, pc, &breakpoints, symbols));
+                }
+                _ => (),
+            }
+            return Ok(true);
+        }
+        "b" | "break" => {
+            let breakpoint = try!(parse_breakpoint(symbols, &args));
+            println!("breakpoint at {}", format_breakpoint(&breakpoint, symbols));
+            try!(request(commands, DebugRequest::AddBreakpoint(breakpoint)));
+            return Ok(true);
+        }
+        "delete" => {
+            let addr = try!(parse_addr(symbols, args.get(0).cloned()));
+            try!(request(commands, DebugRequest::RemoveBreakpoint(addr)))
+        }
+        "breakpoints" => try!(request(commands, DebugRequest::Breakpoints)),
+        "watch" | "rwatch" | "awatch" | "iwatch" => {
+            let kind = match command {
+                "watch" => WatchKind::Write,
+                "rwatch" => WatchKind::Read,
+                "awatch" => WatchKind::Access,
+                _ => WatchKind::Pointer,
+            };
+            let (start, end) = try!(parse_range(symbols, &args));
+            let watchpoint = Watchpoint {
+                start: start,
+                end: end,
+                kind: kind,
+            };
+            try!(request(commands, DebugRequest::AddWatchpoint(watchpoint)));
+            println!("{}", format_watchpoint(&watchpoint));
+            return Ok(true);
+        }
+        "unwatch" => {
+            let (start, end) = try!(parse_range(symbols, &args));
+            let watchpoints = match try!(request(commands, DebugRequest::Watchpoints)) {
+                DebugReply::Watchpoints(watchpoints) => watchpoints,
+                _ => Vec::new(),
+            };
+            let mut found = false;
+            for watchpoint in watchpoints {
+                if watchpoint.start == start && watchpoint.end == end {
+                    try!(request(commands, DebugRequest::RemoveWatchpoint(watchpoint)));
+                    found = true;
+                }
+            }
+            if !found {
+                return Err(format!("no watchpoint on {:#05X}-{:#05X}", start, end));
+            }
+            return Ok(true);
+        }
+        "watchpoints" => try!(request(commands, DebugRequest::Watchpoints)),
+        "print" => {
+            let expr = try!(Expr::parse(&args.join(" "), symbols));
+            try!(request(commands, DebugRequest::Evaluate(expr)))
+        }
+        _ => return Err(format!("unknown command '{}', try 'help'", command)),
+    };
+
+    match reply {
+        DebugReply::Stopped { stop, pc, codeword } => {
+            println!("{}", format_stop(stop, pc, codeword, symbols))
+        }
+        DebugReply::Registers(registers) => print!("{}", format_registers(&registers, symbols)),
+        DebugReply::Memory(addr, bytes) => print!("{}", hex_dump(addr, &bytes)),
+        DebugReply::Breakpoints(breakpoints) => {
+            for breakpoint in breakpoints {
+                println!("  {}, hit {} times",
+                         format_breakpoint(&breakpoint, symbols),
+                         breakpoint.hits);
+            }
+        }
+        DebugReply::Watchpoints(watchpoints) => {
+            for watchpoint in watchpoints {
+                println!("  {}", format_watchpoint(&watchpoint));
+            }
+        }
+        DebugReply::Value(value) => println!("{} ({:#X})", value, value),
+        DebugReply::Ok | DebugReply::Error(_) => (),
+    }
+    Ok(true)
+}
+
+
+fn format_stop(stop: Option<Stop>, pc: u16, codeword: u16, symbols: &Symbols) -> String {
+    let reason = match stop {
+        Some(Stop::Breakpoint(_)) => "breakpoint ".to_string(),
+        Some(Stop::Halted(_)) => "halted ".to_string(),
+        Some(Stop::Watchpoint(ref hit)) => format!("{}\n", format_hit(hit)),
+        Some(Stop::Sanitizer(_, issue)) => format!("sanitizer: {}\n", issue),
+        Some(Stop::IllegalOpcode(..)) => "illegal opcode ".to_string(),
+        Some(Stop::Step(_)) | None => String::new(),
+    };
+    let location = match symbols.locate(pc) {
+        Some(_) => format!("in {}\n", symbols.describe(pc)),
+        None => String::new(),
+    };
+    format!("{}{}{}", reason, location, listing_line(pc, codeword, symbols))
+}
+
+fn format_range(start: u16, end: u16) -> String {
+    if start == end {
+        format!("{:#05X}", start)
+    } else {
+        format!("{:#05X}-{:#05X}", start, end)
+    }
+}
+
+fn format_watchpoint(watchpoint: &Watchpoint) -> String {
+    let kind = match watchpoint.kind {
+        WatchKind::Read => "read",
+        WatchKind::Write => "write",
+        WatchKind::Access => "access",
+        WatchKind::Pointer => "I pointer",
+    };
+    format!("{} watchpoint on {}", kind, format_range(watchpoint.start, watchpoint.end))
+}
+
+/// Says what the instruction is about to do to the watched memory.
+fn format_hit(hit: &WatchHit) -> String {
+    let end = hit.access.addr.wrapping_add(hit.access.len - 1);
+    let what = match hit.access.access {
+        Access::Read => format!("reads {}", format_range(hit.access.addr, end)),
+        Access::Write => format!("writes {}", format_range(hit.access.addr, end)),
+        Access::Pointer => format!("sets I to {:#05X}", hit.access.addr),
+    };
+    format!("{} hit: {:#05X} {}", format_watchpoint(&hit.watchpoint), hit.pc, what)
+}
+
+pub fn format_registers(registers: &Registers, symbols: &Symbols) -> String {
+    let mut out = String::new();
+    for (x, v) in registers.v.iter().enumerate() {
+        out.push_str(&format!("V{:X}={:02X}{}", x, v, if x % 8 == 7 { "\n" } else { " " }));
+    }
+    out.push_str(&format!("I={:03X} PC={:03X} SP={} DT={:02X} ST={:02X}\n",
+                          registers.i,
+                          registers.pc,
+                          registers.stack.len(),
+                          registers.delay_timer,
+                          registers.sound_timer));
+    for (depth, addr) in registers.stack.iter().enumerate().rev() {
+        out.push_str(&format!("  #{} return to {}\n", depth, symbols.describe(*addr)));
+    }
+    out
+}
+
+/// Sixteen bytes per line with their ASCII alongside.
+pub fn hex_dump(addr: u16, bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (n, row) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+        let text: String = row.iter()
+            .map(|&b| if b >= 0x20 && b < 0x7F { b as char } else { '.' })
+            .collect();
+        out.push_str(&format!("{:03X}: {:<47}  {}\n",
+                              addr.wrapping_add(n as u16 * 16),
+                              hex.join(" "),
+                              text));
+    }
+    out
+}
+
+/// Lists instructions starting at `start`, marking PC with `>` and breakpoints with `*`.
+/// Addresses with a symbol get a label line before them.
+fn disassemble(start: u16,
+               bytes: &[u8],
+               pc: u16,
+               breakpoints: &[u16],
+               symbols: &Symbols)
+               -> String {
+    let mut out = String::new();
+    for (n, pair) in bytes.chunks(2).enumerate() {
+        if pair.len() < 2 {
+            break;
+        }
+        let addr = start.wrapping_add(n as u16 * 2);
+        let codeword = (pair[0] as u16) << 8 | pair[1] as u16;
+        if let Some(name) = symbols.name_of(addr) {
+            out.push_str(&format!("{}:\n", name));
+        }
+        out.push_str(&format!("{}{} {}\n",
+                              if breakpoints.contains(&addr) { "*" } else { " " },
+                              if addr == pc { ">" } else { " " },
+                              listing_line(addr, codeword, symbols)));
+    }
+    out
+}
+
+
+#[test]
+fn test_hex_dump() {
+    assert_eq!(hex_dump(0x200, &[0x41, 0x00, 0xFF]),
+               format!("200: {:<47}  A..\n", "41 00 FF"));
+}
+
+#[test]
+fn test_disassemble() {
+    let bytes = [0x00, 0xE0, 0x12, 0x00];
+    let listing = disassemble(0x200, &bytes, 0x202, &[0x200], &Symbols::new());
+    assert_eq!(listing, "*  0x200: 00E0  CLS\n > 0x202: 1200  JP 0x200\n");
+
+    let symbols = Symbols::parse("main = 0x200").unwrap();
+    let listing = disassemble(0x200, &bytes, 0x202, &[0x200], &symbols);
+    assert_eq!(listing, "main:\n*  0x200: 00E0  CLS\n > 0x202: 1200  JP main\n");
+    assert_eq!(format_stop(None, 0x202, 0x1200, &symbols),
+               "in 0x202 <main+2>\n0x202: 1200  JP main");
+}
+
+#[test]
+fn test_parse_breakpoint() {
+    let symbols = Symbols::parse("player_move = 0x2A4").unwrap();
+    let breakpoint = parse_breakpoint(&symbols,
+                                      &["player_move", "hits", "3", "if", "v3", "==", "0x1F"])
+        .unwrap();
+    assert_eq!(breakpoint.addr, 0x2A4);
+    assert_eq!(breakpoint.hit_count, Some(3));
+    assert_eq!(format_breakpoint(&breakpoint, &symbols),
+               "0x2A4 <player_move> hits 3 if v3 == 0x1F");
+    assert!(parse_breakpoint(&symbols, &["0x200", "when", "v0"]).is_err());
+    assert!(parse_breakpoint(&symbols, &["0x200", "if", "v0", "=="]).is_err());
+}
+
+#[test]
+fn test_check_memory() {
+    assert_eq!(check_memory(0xFF0, 16), Ok(()));
+    assert!(check_memory(0xFF0, 17).is_err());
+    assert!(check_memory(0x1000, 0).is_err());
+}
@@ -0,0 +1,694 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender, Receiver};
+
+use rustc_serialize::base64::{ToBase64, FromBase64, STANDARD};
+use rustc_serialize::json::{Json, ToJson};
+
+use debugger::{DebugRequest, DebugReply, Register, parse_register, request};
+use debugger::expr::Expr;
+use debugger::lines::{LineMap, Source};
+use disasm::{decode, listing_line, symbolic, Instruction};
+use emulator::Command;
+use machine::{Stop, Registers, Breakpoint};
+use options::parse_number;
+use symbols::Symbols;
+
+/// CHIP-8 has a single thread of execution.
+const THREAD_ID: u64 = 1;
+
+/// Variable containers handed out by `scopes`.
+const REGISTERS_REF: u64 = 1;
+const MEMORY_AT_I_REF: u64 = 2;
+const MEMORY_REF: u64 = 3;
+
+/// Indices into `Session::breakpoints`, one list per kind of DAP breakpoint request.
+const SOURCE_BREAKPOINTS: usize = 0;
+const FUNCTION_BREAKPOINTS: usize = 1;
+const INSTRUCTION_BREAKPOINTS: usize = 2;
+
+
+enum Input {
+    Message(Json),
+    Closed,
+}
+
+struct Session {
+    commands: Sender<Command>,
+    stream: TcpStream,
+    source: Option<Source>,
+    symbols: Arc<Symbols>,
+    seq: u64,
+    stop_on_entry: bool,
+    breakpoints: [Vec<u16>; 3],
+    /// Events to send once the response to the current request has gone out.
+    pending_events: Vec<(&'static str, Json)>,
+    finished: bool,
+}
+
+
+/// Listens for Debug Adapter Protocol clients on a local port, serving one at a time.
+pub fn spawn(port: u16,
+             commands: Sender<Command>,
+             source: Option<Source>,
+             symbols: Arc<Symbols>)
+             -> io::Result<thread::JoinHandle<()>> {
+    let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+    println!("debug adapter listening on 127.0.0.1:{}", port);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            if let Err(e) = serve(stream, commands.clone(), source.clone(), symbols.clone()) {
+                println!("dap: {}", e);
+            }
+        }
+    }))
+}
+
+fn serve(stream: TcpStream,
+         commands: Sender<Command>,
+         source: Option<Source>,
+         symbols: Arc<Symbols>)
+         -> Result<(), String> {
+    let (input_tx, input_rx) = mpsc::channel();
+    let reader = try!(stream.try_clone().map_err(|e| e.to_string()));
+    thread::spawn(move || read_messages(reader, input_tx));
+
+    let (events_tx, events_rx) = mpsc::channel();
+    try!(commands.send(Command::Debug(DebugRequest::Subscribe, events_tx))
+        .map_err(|_| "emulator has exited"));
+
+    let mut session = Session {
+        commands: commands,
+        stream: stream,
+        source: source,
+        symbols: symbols,
+        seq: 1,
+        stop_on_entry: false,
+        breakpoints: [Vec::new(), Vec::new(), Vec::new()],
+        pending_events: Vec::new(),
+        finished: false,
+    };
+    session.run(input_rx, events_rx)
+}
+
+/// Reads `Content-Length` framed JSON messages.
+fn read_messages(stream: TcpStream, input: Sender<Input>) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    input.send(Input::Closed).ok();
+                    return;
+                }
+                Ok(_) => (),
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with("Content-Length:") {
+                length = line["Content-Length:".len()..].trim().parse::<usize>().ok();
+            }
+        }
+        let length = match length {
+            Some(length) => length,
+            None => continue,
+        };
+        let mut body = vec![0; length];
+        if reader.read_exact(&mut body).is_err() {
+            input.send(Input::Closed).ok();
+            return;
+        }
+        if let Ok(message) = Json::from_str(&String::from_utf8_lossy(&body)) {
+            if input.send(Input::Message(message)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+
+fn object(pairs: Vec<(&str, Json)>) -> Json {
+    let mut map = BTreeMap::new();
+    for (key, value) in pairs {
+        map.insert(key.to_string(), value);
+    }
+    Json::Object(map)
+}
+
+fn get_u64(json: Option<&Json>, key: &str) -> Option<u64> {
+    json.and_then(|j| j.find(key)).and_then(|v| v.as_u64())
+}
+
+fn get_str<'a>(json: Option<&'a Json>, key: &str) -> Option<&'a str> {
+    json.and_then(|j| j.find(key)).and_then(|v| v.as_string())
+}
+
+/// Parses a memory reference plus an optional byte offset.
+fn memory_address(arguments: Option<&Json>) -> Result<u16, String> {
+    let reference = try!(get_str(arguments, "memoryReference").ok_or("missing memoryReference"));
+    let base = try!(parse_number(reference));
+    let offset = arguments.and_then(|a| a.find("offset")).and_then(|o| o.as_i64()).unwrap_or(0);
+    Ok((base as i64 + offset) as u16)
+}
+
+fn stopped_event(stop: Option<Stop>, default: &str) -> Json {
+    let (reason, description) = match stop {
+        Some(Stop::Breakpoint(_)) => ("breakpoint", None),
+        Some(Stop::Halted(halt)) => ("exception", Some(format!("halted: {:?}", halt))),
+        Some(Stop::Step(_)) => ("step", None),
+        Some(Stop::Sanitizer(_, issue)) => ("exception", Some(issue.to_string())),
+        Some(Stop::IllegalOpcode(_, codeword)) => {
+            ("exception", Some(format!("illegal opcode {:04X}", codeword)))
+        }
+        Some(Stop::Watchpoint(hit)) => {
+            let access = hit.access;
+            ("data breakpoint",
+             Some(format!("{:?} of {:#05X} by {:#05X}", access.access, access.addr, hit.pc)))
+        }
+        None => (default, None),
+    };
+    let mut pairs = vec![("reason", reason.to_json()),
+                         ("threadId", THREAD_ID.to_json()),
+                         ("allThreadsStopped", true.to_json())];
+    if let Some(description) = description {
+        pairs.push(("description", description.to_json()));
+    }
+    object(pairs)
+}
+
+fn variable(name: String, value: String) -> Json {
+    object(vec![("name", name.to_json()),
+                ("value", value.to_json()),
+                ("variablesReference", 0u64.to_json())])
+}
+
+fn register_variables(registers: &Registers) -> Vec<Json> {
+    let mut variables = Vec::new();
+    for (x, v) in registers.v.iter().enumerate() {
+        variables.push(variable(format!("V{:X}", x), format!("{:#04X}", v)));
+    }
+    variables.push(variable("I".to_string(), format!("{:#05X}", registers.i)));
+    variables.push(variable("PC".to_string(), format!("{:#05X}", registers.pc)));
+    variables.push(variable("SP".to_string(), registers.stack.len().to_string()));
+    variables.push(variable("DT".to_string(), format!("{:#04X}", registers.delay_timer)));
+    variables.push(variable("ST".to_string(), format!("{:#04X}", registers.sound_timer)));
+    variables
+}
+
+/// Sixteen bytes per variable, named by their address.
+fn memory_variables(addr: u16, bytes: &[u8]) -> Vec<Json> {
+    bytes.chunks(16)
+        .enumerate()
+        .map(|(n, row)| {
+            let hex: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+            variable(format!("{:#05X}", addr.wrapping_add(n as u16 * 16)), hex.join(" "))
+        })
+        .collect()
+}
+
+
+impl Session {
+    fn run(&mut self,
+           input: Receiver<Input>,
+           events: Receiver<DebugReply>)
+           -> Result<(), String> {
+        while !self.finished {
+            select! {
+                message = input.recv() => {
+                    match message {
+                        Ok(Input::Message(message)) => try!(self.handle(&message)),
+                        Ok(Input::Closed) | Err(_) => break,
+                    }
+                },
+                event = events.recv() => {
+                    match event {
+                        Ok(DebugReply::Stopped { stop, .. }) => {
+                            try!(self.event("stopped", stopped_event(stop, "pause")));
+                        }
+                        Ok(_) => (),
+                        Err(_) => {
+                            try!(self.event("terminated", object(vec![])));
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        // Don't leave the program stuck after the client goes away.
+        request(&self.commands, DebugRequest::Continue).ok();
+        Ok(())
+    }
+
+    fn send(&mut self, mut pairs: Vec<(&str, Json)>) -> Result<(), String> {
+        pairs.push(("seq", self.seq.to_json()));
+        self.seq += 1;
+        let text = object(pairs).to_string();
+        write!(self.stream, "Content-Length: {}\r\n\r\n{}", text.len(), text)
+            .map_err(|e| e.to_string())
+    }
+
+    fn event(&mut self, name: &str, body: Json) -> Result<(), String> {
+        self.send(vec![("type", "event".to_json()), ("event", name.to_json()), ("body", body)])
+    }
+
+    fn handle(&mut self, message: &Json) -> Result<(), String> {
+        let command = get_str(Some(message), "command").unwrap_or("").to_string();
+        let result = self.handle_request(&command, message.find("arguments"));
+
+        let mut pairs = vec![("type", "response".to_json()),
+                             ("request_seq", get_u64(Some(message), "seq").unwrap_or(0).to_json()),
+                             ("command", command.to_json())];
+        match result {
+            Ok(body) => {
+                pairs.push(("success", true.to_json()));
+                pairs.push(("body", body));
+            }
+            Err(e) => {
+                pairs.push(("success", false.to_json()));
+                pairs.push(("message", e.to_json()));
+            }
+        }
+        try!(self.send(pairs));
+
+        for (name, body) in mem::replace(&mut self.pending_events, Vec::new()) {
+            try!(self.event(name, body));
+        }
+        Ok(())
+    }
+
+    fn registers(&self) -> Result<Registers, String> {
+        match try!(request(&self.commands, DebugRequest::Registers)) {
+            DebugReply::Registers(registers) => Ok(registers),
+            _ => Err("unexpected reply".to_string()),
+        }
+    }
+
+    fn read_memory(&self, addr: u16, len: usize) -> Result<Vec<u8>, String> {
+        match try!(request(&self.commands, DebugRequest::ReadMemory(addr, len))) {
+            DebugReply::Memory(_, bytes) => Ok(bytes),
+            _ => Err("unexpected reply".to_string()),
+        }
+    }
+
+    fn codeword(&self, addr: u16) -> Result<u16, String> {
+        let bytes = try!(self.read_memory(addr, 2));
+        if bytes.len() < 2 {
+            return Err(format!("{:#X} is at the end of memory", addr));
+        }
+        Ok((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    /// Sends a stopped event after the response if the request stopped the machine straight
+    /// away. Otherwise it is running and the event comes when it stops.
+    fn stop(&mut self, step: DebugRequest, reason: &str) -> Result<Json, String> {
+        if let DebugReply::Stopped { stop, .. } = try!(request(&self.commands, step)) {
+            self.pending_events.push(("stopped", stopped_event(stop, reason)));
+        }
+        Ok(object(vec![]))
+    }
+
+    /// Replaces one kind of breakpoint, keeping addresses another kind still wants.
+    fn set_breakpoints(&mut self, kind: usize, breakpoints: Vec<Breakpoint>) {
+        let addrs = breakpoints.iter().map(|b| b.addr).collect();
+        let old = mem::replace(&mut self.breakpoints[kind], addrs);
+        for addr in old {
+            if !self.breakpoints.iter().any(|b| b.contains(&addr)) {
+                request(&self.commands, DebugRequest::RemoveBreakpoint(addr)).ok();
+            }
+        }
+        for breakpoint in breakpoints {
+            request(&self.commands, DebugRequest::AddBreakpoint(breakpoint)).ok();
+        }
+    }
+
+    /// A breakpoint at `addr` with the client's `condition` and `hitCondition`. The hit
+    /// condition is what the user typed; a count like `5` or `>= 5` is all that's understood.
+    fn breakpoint_at(&self, addr: u16, options: &Json) -> Result<Breakpoint, String> {
+        let mut breakpoint = Breakpoint::new(addr);
+        if let Some(condition) = get_str(Some(options), "condition") {
+            if !condition.trim().is_empty() {
+                breakpoint.condition = Some(try!(Expr::parse(condition, &self.symbols)));
+            }
+        }
+        if let Some(hits) = get_str(Some(options), "hitCondition") {
+            let count = hits.trim().trim_left_matches(">=").trim();
+            if !count.is_empty() {
+                breakpoint.hit_count = Some(try!(parse_number(count)) as u64);
+            }
+        }
+        Ok(breakpoint)
+    }
+
+    /// The line map, if `path` is the program's source.
+    fn lines_for(&self, path: Option<&str>) -> Option<&LineMap> {
+        let (source, path) = match (self.source.as_ref(), path) {
+            (Some(source), Some(path)) => (source, path),
+            _ => return None,
+        };
+        let same = match (fs::canonicalize(&source.path), fs::canonicalize(path)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => source.path == path,
+        };
+        if same { Some(&source.lines) } else { None }
+    }
+
+    fn source_json(&self) -> Option<Json> {
+        self.source.as_ref().map(|source| {
+            object(vec![("name", source.path.to_json()), ("path", source.path.to_json())])
+        })
+    }
+
+    fn stack_frame(&self, id: usize, name: String, pc: u16) -> Json {
+        let mut pairs = vec![("id", id.to_json()),
+                             ("name", name.to_json()),
+                             ("instructionPointerReference", format!("{:#05X}", pc).to_json()),
+                             ("column", 0u64.to_json())];
+        let line = self.source.as_ref().and_then(|source| source.lines.line_of(pc));
+        match (line, self.source_json()) {
+            (Some(line), Some(source)) => {
+                pairs.push(("line", line.to_json()));
+                pairs.push(("source", source));
+            }
+            _ => pairs.push(("line", 0u64.to_json())),
+        }
+        object(pairs)
+    }
+
+    /// Frames from the innermost out. Each is named after the subroutine it is in, which is
+    /// the target of the CALL just before its return address.
+    fn stack_trace(&self) -> Result<Json, String> {
+        let registers = try!(self.registers());
+        let mut frames = Vec::new();
+        let mut pc = registers.pc;
+        for (depth, ret) in registers.stack.iter().enumerate().rev() {
+            let call = ret.wrapping_sub(2);
+            let name = match decode(try!(self.codeword(call))) {
+                Instruction::Call(target) => {
+                    match self.symbols.name_of(target) {
+                        Some(name) => name.to_string(),
+                        None => format!("{:#05X}", target),
+                    }
+                }
+                _ => "?".to_string(),
+            };
+            frames.push(self.stack_frame(registers.stack.len() - 1 - depth, name, pc));
+            pc = call;
+        }
+        let id = frames.len();
+        frames.push(self.stack_frame(id, "main".to_string(), pc));
+        let total = frames.len();
+        Ok(object(vec![("stackFrames", Json::Array(frames)), ("totalFrames", total.to_json())]))
+    }
+
+    fn handle_request(&mut self, command: &str, arguments: Option<&Json>) -> Result<Json, String> {
+        match command {
+            "initialize" => {
+                self.pending_events.push(("initialized", object(vec![])));
+                Ok(object(vec![("supportsConfigurationDoneRequest", true.to_json()),
+                               ("supportsFunctionBreakpoints", true.to_json()),
+                               ("supportsConditionalBreakpoints", true.to_json()),
+                               ("supportsStepBack", true.to_json()),
+                               ("supportsHitConditionalBreakpoints", true.to_json()),
+                               ("supportsInstructionBreakpoints", true.to_json()),
+                               ("supportsSetVariable", true.to_json()),
+                               ("supportsReadMemoryRequest", true.to_json()),
+                               ("supportsWriteMemoryRequest", true.to_json()),
+                               ("supportsDisassembleRequest", true.to_json())]))
+            }
+            "launch" | "attach" => {
+                self.stop_on_entry = arguments.and_then(|a| a.find("stopOnEntry"))
+                    .and_then(|s| s.as_boolean())
+                    .unwrap_or(false);
+                try!(request(&self.commands, DebugRequest::Pause));
+                Ok(object(vec![]))
+            }
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.pending_events.push(("stopped", stopped_event(None, "entry")));
+                } else {
+                    try!(request(&self.commands, DebugRequest::Continue));
+                }
+                Ok(object(vec![]))
+            }
+            "setBreakpoints" => {
+                let path = arguments.and_then(|a| a.find_path(&["source", "path"]))
+                    .and_then(|p| p.as_string());
+                let requested = arguments.and_then(|a| a.find("breakpoints"))
+                    .and_then(|b| b.as_array())
+                    .cloned()
+                    .unwrap_or(Vec::new());
+                let mut breakpoints = Vec::new();
+                let mut results = Vec::new();
+                {
+                    let map = self.lines_for(path);
+                    for options in &requested {
+                        let line = get_u64(Some(options), "line").unwrap_or(0) as usize;
+                        let breakpoint = map.and_then(|map| map.address_of(line))
+                            .ok_or("no code at this line".to_string())
+                            .and_then(|addr| self.breakpoint_at(addr, options));
+                        match breakpoint {
+                            Ok(breakpoint) => {
+                                let addr = breakpoint.addr;
+                                breakpoints.push(breakpoint);
+                                let actual = map.and_then(|map| map.line_of(addr)).unwrap_or(line);
+                                results.push(object(vec![("verified", true.to_json()),
+                                                         ("line", actual.to_json()),
+                                                         ("instructionReference",
+                                                          format!("{:#05X}", addr).to_json())]));
+                            }
+                            Err(message) => {
+                                results.push(object(vec![("verified", false.to_json()),
+                                                         ("line", line.to_json()),
+                                                         ("message", message.to_json())]));
+                            }
+                        }
+                    }
+                }
+                self.set_breakpoints(SOURCE_BREAKPOINTS, breakpoints);
+                Ok(object(vec![("breakpoints", Json::Array(results))]))
+            }
+            "setFunctionBreakpoints" | "setInstructionBreakpoints" => {
+                let (kind, key) = if command == "setFunctionBreakpoints" {
+                    (FUNCTION_BREAKPOINTS, "name")
+                } else {
+                    (INSTRUCTION_BREAKPOINTS, "instructionReference")
+                };
+                let mut set = Vec::new();
+                let mut results = Vec::new();
+                let breakpoints = arguments.and_then(|a| a.find("breakpoints"))
+                    .and_then(|b| b.as_array())
+                    .cloned()
+                    .unwrap_or(Vec::new());
+                for breakpoint in &breakpoints {
+                    let offset = breakpoint.find("offset").and_then(|o| o.as_i64()).unwrap_or(0);
+                    let found = get_str(Some(breakpoint), key)
+                        .and_then(|name| self.symbols.parse_address(name).ok())
+                        .map(|addr| (addr as i64 + offset) as u16)
+                        .ok_or("expected an address or symbol".to_string())
+                        .and_then(|addr| self.breakpoint_at(addr, breakpoint));
+                    match found {
+                        Ok(found) => {
+                            results.push(object(vec![("verified", true.to_json()),
+                                                     ("instructionReference",
+                                                      format!("{:#05X}", found.addr).to_json())]));
+                            set.push(found);
+                        }
+                        Err(message) => {
+                            results.push(object(vec![("verified", false.to_json()),
+                                                     ("message", message.to_json())]))
+                        }
+                    }
+                }
+                self.set_breakpoints(kind, set);
+                Ok(object(vec![("breakpoints", Json::Array(results))]))
+            }
+            "threads" => {
+                let thread = object(vec![("id", THREAD_ID.to_json()),
+                                         ("name", "CHIP-8".to_json())]);
+                Ok(object(vec![("threads", Json::Array(vec![thread]))]))
+            }
+            "stackTrace" => self.stack_trace(),
+            "scopes" => {
+                let scope = |name: &str, reference: u64, expensive: bool| {
+                    object(vec![("name", name.to_json()),
+                                ("variablesReference", reference.to_json()),
+                                ("expensive", expensive.to_json())])
+                };
+                Ok(object(vec![("scopes",
+                                Json::Array(vec![scope("Registers", REGISTERS_REF, false),
+                                                 scope("Memory at I", MEMORY_AT_I_REF, false),
+                                                 scope("Memory", MEMORY_REF, true)]))]))
+            }
+            "variables" => {
+                let variables = match get_u64(arguments, "variablesReference") {
+                    Some(REGISTERS_REF) => register_variables(&try!(self.registers())),
+                    Some(MEMORY_AT_I_REF) => {
+                        let i = try!(self.registers()).i;
+                        // Fewer than 16 near the end of memory, and none if I is past it.
+                        let bytes = self.read_memory(i, 16).unwrap_or(Vec::new());
+                        bytes.iter()
+                            .enumerate()
+                            .map(|(n, b)| {
+                                variable(format!("{:#05X}", i.wrapping_add(n as u16)),
+                                         format!("{:#04X}", b))
+                            })
+                            .collect()
+                    }
+                    Some(MEMORY_REF) => memory_variables(0, &try!(self.read_memory(0, 0x1000))),
+                    _ => Vec::new(),
+                };
+                Ok(object(vec![("variables", Json::Array(variables))]))
+            }
+            "setVariable" => {
+                let name = try!(get_str(arguments, "name").ok_or("missing name"));
+                let value = try!(get_str(arguments, "value").ok_or("missing value"));
+                let number = try!(parse_number(value));
+                match get_u64(arguments, "variablesReference") {
+                    Some(REGISTERS_REF) => {
+                        let register = try!(parse_register(name)
+                            .ok_or(format!("can't set {}", name)));
+                        try!(request(&self.commands,
+                                     DebugRequest::SetRegister(register, number as u16)));
+                        let shown = match register {
+                            Register::I | Register::Pc => format!("{:#05X}", number),
+                            _ => format!("{:#04X}", number as u8),
+                        };
+                        Ok(object(vec![("value", shown.to_json())]))
+                    }
+                    Some(MEMORY_AT_I_REF) => {
+                        let addr = try!(parse_number(name)) as u16;
+                        try!(request(&self.commands,
+                                     DebugRequest::WriteMemory(addr, vec![number as u8])));
+                        Ok(object(vec![("value", format!("{:#04X}", number as u8).to_json())]))
+                    }
+                    _ => Err("this variable can't be changed".to_string()),
+                }
+            }
+            "readMemory" => {
+                let addr = try!(memory_address(arguments));
+                let count = get_u64(arguments, "count").unwrap_or(0) as usize;
+                let bytes = try!(self.read_memory(addr, count));
+                Ok(object(vec![("address", format!("{:#05X}", addr).to_json()),
+                               ("data", bytes.to_base64(STANDARD).to_json()),
+                               ("unreadableBytes", (count - bytes.len()).to_json())]))
+            }
+            "writeMemory" => {
+                let addr = try!(memory_address(arguments));
+                let data = try!(get_str(arguments, "data").ok_or("missing data"));
+                let bytes = try!(data.from_base64().map_err(|e| e.to_string()));
+                let written = bytes.len();
+                try!(request(&self.commands, DebugRequest::WriteMemory(addr, bytes)));
+                Ok(object(vec![("bytesWritten", written.to_json())]))
+            }
+            "disassemble" => {
+                let addr = try!(memory_address(arguments));
+                let offset = arguments.and_then(|a| a.find("instructionOffset"))
+                    .and_then(|o| o.as_i64())
+                    .unwrap_or(0);
+                let count = get_u64(arguments, "instructionCount").unwrap_or(0) as usize;
+                let start = (addr as i64 + offset * 2) as u16;
+                let bytes = try!(self.read_memory(start, count.saturating_mul(2)));
+                // A read that stops at the end of memory can leave half an instruction.
+                let instructions = bytes.chunks(2)
+                    .filter(|pair| pair.len() == 2)
+                    .enumerate()
+                    .map(|(n, pair)| {
+                        let addr = start.wrapping_add(n as u16 * 2);
+                        let codeword = (pair[0] as u16) << 8 | pair[1] as u16;
+                        let mut pairs =
+                            vec![("address", format!("{:#05X}", addr).to_json()),
+                                 ("instructionBytes", format!("{:04X}", codeword).to_json()),
+                                 ("instruction",
+                                  symbolic(decode(codeword), &self.symbols).to_json())];
+                        if let Some(name) = self.symbols.name_of(addr) {
+                            pairs.push(("symbol", name.to_json()));
+                        }
+                        let line = self.source.as_ref().and_then(|s| s.lines.line_of(addr));
+                        if let Some(line) = line {
+                            pairs.push(("line", line.to_json()));
+                        }
+                        object(pairs)
+                    })
+                    .collect();
+                Ok(object(vec![("instructions", Json::Array(instructions))]))
+            }
+            "continue" => {
+                try!(request(&self.commands, DebugRequest::Continue));
+                Ok(object(vec![("allThreadsContinued", true.to_json())]))
+            }
+            "next" => self.stop(DebugRequest::StepOver, "step"),
+            "stepIn" => self.stop(DebugRequest::Step(1), "step"),
+            "stepOut" => self.stop(DebugRequest::StepOut, "step"),
+            "stepBack" => self.stop(DebugRequest::ReverseStep(1), "step"),
+            "reverseContinue" => self.stop(DebugRequest::ReverseContinue, "entry"),
+            "pause" => self.stop(DebugRequest::Pause, "pause"),
+            "evaluate" => {
+                // Hovering over a register name or address shows its value. Anything else,
+                // like a watch, is an expression.
+                let expression = try!(get_str(arguments, "expression")
+                    .ok_or("missing expression"));
+                let registers = try!(self.registers());
+                let result = match parse_register(expression) {
+                    Some(Register::V(x)) => format!("{:#04X}", registers.v[x]),
+                    Some(Register::I) => format!("{:#05X}", registers.i),
+                    Some(Register::Pc) => {
+                        listing_line(registers.pc,
+                                     try!(self.codeword(registers.pc)),
+                                     &self.symbols)
+                    }
+                    Some(Register::DelayTimer) => format!("{:#04X}", registers.delay_timer),
+                    Some(Register::SoundTimer) => format!("{:#04X}", registers.sound_timer),
+                    None => {
+                        match self.symbols.parse_address(expression) {
+                            Ok(addr) => format!("{:#04X}", try!(self.read_memory(addr, 1))[0]),
+                            Err(_) => {
+                                let expr = try!(Expr::parse(expression, &self.symbols));
+                                match try!(request(&self.commands, DebugRequest::Evaluate(expr))) {
+                                    DebugReply::Value(value) => format!("{} ({:#X})", value, value),
+                                    _ => return Err("unexpected reply".to_string()),
+                                }
+                            }
+                        }
+                    }
+                };
+                Ok(object(vec![("result", result.to_json()),
+                               ("variablesReference", 0u64.to_json())]))
+            }
+            "disconnect" => {
+                self.finished = true;
+                Ok(object(vec![]))
+            }
+            _ => Err(format!("unsupported request '{}'", command)),
+        }
+    }
+}
+
+
+#[test]
+fn test_memory_variables() {
+    let variables = memory_variables(0x200, &[0x12, 0x00]);
+    assert_eq!(variables.len(), 1);
+    assert_eq!(get_str(Some(&variables[0]), "name"), Some("0x200"));
+    assert_eq!(get_str(Some(&variables[0]), "value"), Some("12 00"));
+}
+
+#[test]
+fn test_stopped_event() {
+    let event = stopped_event(Some(Stop::Breakpoint(0x2A4)), "pause");
+    assert_eq!(get_str(Some(&event), "reason"), Some("breakpoint"));
+    let event = stopped_event(None, "entry");
+    assert_eq!(get_str(Some(&event), "reason"), Some("entry"));
+}
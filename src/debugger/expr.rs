@@ -0,0 +1,336 @@
+//! Expressions for breakpoint conditions and the console's `print`, such as
+//! `v3 == 0x1F && [i+2] > 2`. They're parsed once, when the breakpoint is set, so checking
+//! one at each hit is only a walk over a small tree.
+
+use std::fmt;
+
+use debugger::{Register, parse_register};
+use machine::{Machine, MEMORY_SIZE};
+use options::parse_number;
+use symbols::Symbols;
+
+
+/// Where an expression reads registers and memory from.
+pub trait Context {
+    fn value_of(&mut self, register: Register) -> u32;
+    fn byte_at(&mut self, addr: u16) -> u8;
+}
+
+impl Context for Machine {
+    fn value_of(&mut self, register: Register) -> u32 {
+        match register {
+            Register::V(x) => self.register(x) as u32,
+            Register::I => self.i() as u32,
+            Register::Pc => self.pc() as u32,
+            Register::DelayTimer => self.delay_timer() as u32,
+            Register::SoundTimer => self.sound_timer() as u32,
+        }
+    }
+
+    fn byte_at(&mut self, addr: u16) -> u8 {
+        self.read_byte(addr)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Unary {
+    Negate,
+    Not,
+    Complement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Binary {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(u32),
+    Register(Register),
+    /// The byte at an address, written `[ADDR]`.
+    Memory(Box<Node>),
+    Unary(Unary, Box<Node>),
+    Binary(Binary, Box<Node>, Box<Node>),
+}
+
+/// A parsed expression, which keeps its text to show back to the user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    text: String,
+    root: Node,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u32),
+    Name(String),
+    Operator(&'static str),
+}
+
+/// Longest first, so `<=` isn't read as `<` then `=`.
+const OPERATORS: [&'static str; 24] = ["&&", "||", "==", "!=", "<=", ">=", "<<", ">>", "<", ">",
+                                       "+", "-", "*", "/", "%", "&", "|", "^", "!", "~", "(",
+                                       ")", "[", "]"];
+
+/// Binary operators from the loosest binding to the tightest, as in C.
+const PRECEDENCE: [&'static [(&'static str, Binary)]; 10] =
+    [&[("||", Binary::Or)],
+     &[("&&", Binary::And)],
+     &[("|", Binary::BitOr)],
+     &[("^", Binary::BitXor)],
+     &[("&", Binary::BitAnd)],
+     &[("==", Binary::Equal), ("!=", Binary::NotEqual)],
+     &[("<", Binary::Less),
+       ("<=", Binary::LessEqual),
+       (">", Binary::Greater),
+       (">=", Binary::GreaterEqual)],
+     &[("<<", Binary::ShiftLeft), (">>", Binary::ShiftRight)],
+     &[("+", Binary::Add), ("-", Binary::Subtract)],
+     &[("*", Binary::Multiply), ("/", Binary::Divide), ("%", Binary::Remainder)]];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_left();
+    while !rest.is_empty() {
+        let word_len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if word_len > 0 {
+            let word = &rest[..word_len];
+            if word.starts_with(|c: char| c.is_digit(10)) {
+                tokens.push(Token::Number(try!(parse_number(word))));
+            } else {
+                tokens.push(Token::Name(word.to_string()));
+            }
+            rest = &rest[word_len..];
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                Some(&op) => {
+                    tokens.push(Token::Operator(op));
+                    rest = &rest[op.len()..];
+                }
+                None => return Err(format!("unexpected '{}'", rest.chars().next().unwrap())),
+            }
+        }
+        rest = rest.trim_left();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    next: usize,
+    symbols: &'a Symbols,
+}
+
+impl<'a> Parser<'a> {
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.next) {
+            Some(&Token::Operator(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_operator() == Some(op) {
+            self.next += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", op))
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = try!(self.binary(level + 1));
+        loop {
+            let op = match self.peek_operator()
+                .and_then(|op| PRECEDENCE[level].iter().find(|&&(text, _)| text == op)) {
+                Some(&(_, op)) => op,
+                None => return Ok(left),
+            };
+            self.next += 1;
+            let right = try!(self.binary(level + 1));
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        let op = match self.peek_operator() {
+            Some("-") => Unary::Negate,
+            Some("!") => Unary::Not,
+            Some("~") => Unary::Complement,
+            _ => return self.primary(),
+        };
+        self.next += 1;
+        Ok(Node::Unary(op, Box::new(try!(self.unary()))))
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        let token = try!(self.tokens.get(self.next).cloned().ok_or("expression ends early"));
+        self.next += 1;
+        match token {
+            Token::Number(n) => Ok(Node::Number(n)),
+            Token::Name(name) => {
+                match parse_register(&name) {
+                    Some(register) => Ok(Node::Register(register)),
+                    None => {
+                        self.symbols
+                            .parse_address(&name)
+                            .map(|addr| Node::Number(addr as u32))
+                            .map_err(|_| format!("'{}' is not a register or a known symbol", name))
+                    }
+                }
+            }
+            Token::Operator("(") => {
+                let node = try!(self.binary(0));
+                try!(self.expect(")"));
+                Ok(node)
+            }
+            Token::Operator("[") => {
+                let node = try!(self.binary(0));
+                try!(self.expect("]"));
+                Ok(Node::Memory(Box::new(node)))
+            }
+            Token::Operator(op) => Err(format!("unexpected '{}'", op)),
+        }
+    }
+}
+
+fn eval_node<C: Context>(node: &Node, context: &mut C) -> Result<u32, String> {
+    Ok(match *node {
+        Node::Number(n) => n,
+        Node::Register(register) => context.value_of(register),
+        Node::Memory(ref addr) => {
+            let addr = try!(eval_node(addr, context));
+            if addr as usize >= MEMORY_SIZE {
+                return Err(format!("[{:#X}] is outside memory", addr));
+            }
+            context.byte_at(addr as u16) as u32
+        }
+        Node::Unary(op, ref operand) => {
+            let value = try!(eval_node(operand, context));
+            match op {
+                Unary::Negate => value.wrapping_neg(),
+                Unary::Not => (value == 0) as u32,
+                Unary::Complement => !value,
+            }
+        }
+        // Only evaluate the right side when it can change the answer.
+        Node::Binary(Binary::And, ref left, ref right) => {
+            (try!(eval_node(left, context)) != 0 && try!(eval_node(right, context)) != 0) as u32
+        }
+        Node::Binary(Binary::Or, ref left, ref right) => {
+            (try!(eval_node(left, context)) != 0 || try!(eval_node(right, context)) != 0) as u32
+        }
+        Node::Binary(op, ref left, ref right) => {
+            let a = try!(eval_node(left, context));
+            let b = try!(eval_node(right, context));
+            match op {
+                Binary::BitOr => a | b,
+                Binary::BitXor => a ^ b,
+                Binary::BitAnd => a & b,
+                Binary::Equal => (a == b) as u32,
+                Binary::NotEqual => (a != b) as u32,
+                Binary::Less => (a < b) as u32,
+                Binary::LessEqual => (a <= b) as u32,
+                Binary::Greater => (a > b) as u32,
+                Binary::GreaterEqual => (a >= b) as u32,
+                Binary::ShiftLeft => a.checked_shl(b).unwrap_or(0),
+                Binary::ShiftRight => a.checked_shr(b).unwrap_or(0),
+                Binary::Add => a.wrapping_add(b),
+                Binary::Subtract => a.wrapping_sub(b),
+                Binary::Multiply => a.wrapping_mul(b),
+                Binary::Divide => try!(a.checked_div(b).ok_or("division by zero")),
+                Binary::Remainder => try!(a.checked_rem(b).ok_or("division by zero")),
+                Binary::And | Binary::Or => unreachable!(),
+            }
+        }
+    })
+}
+
+impl Expr {
+    /// Parses an expression. Names are registers (`v0`-`vf`, `i`, `pc`, `dt`, `st`) or
+    /// symbols, and `[ADDR]` is the byte at ADDR. The operators are C's, with the same
+    /// precedence; comparisons and logic give 1 or 0.
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: try!(tokenize(text)),
+            next: 0,
+            symbols: symbols,
+        };
+        if parser.tokens.is_empty() {
+            return Err("expected an expression".to_string());
+        }
+        let root = try!(parser.binary(0));
+        if parser.next < parser.tokens.len() {
+            return Err(format!("unexpected {:?} in expression", parser.tokens[parser.next]));
+        }
+        Ok(Expr {
+            text: text.trim().to_string(),
+            root: root,
+        })
+    }
+
+    pub fn eval<C: Context>(&self, context: &mut C) -> Result<u32, String> {
+        eval_node(&self.root, context)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+
+#[test]
+fn test_eval() {
+    struct Fake;
+    impl Context for Fake {
+        fn value_of(&mut self, register: Register) -> u32 {
+            match register {
+                Register::V(x) => x as u32 * 0x10,
+                Register::I => 0x300,
+                _ => 0,
+            }
+        }
+        fn byte_at(&mut self, addr: u16) -> u8 {
+            addr as u8
+        }
+    }
+    let symbols = Symbols::parse("score = 0x3F0").unwrap();
+    let eval = |text: &str| Expr::parse(text, &symbols).and_then(|e| e.eval(&mut Fake));
+
+    assert_eq!(eval("v3 == 0x30 && [0x300] < 2"), Ok(1));
+    assert_eq!(eval("1 + 2 * 3 == 7"), Ok(1));
+    assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+    assert_eq!(eval("[i+2]"), Ok(2));
+    assert_eq!(eval("[score] >= 0xF0 || 1 / 0"), Ok(1));
+    assert_eq!(eval("!v0 + -1"), Ok(0));
+    assert_eq!(eval("v1 << 4 | 3"), Ok(0x103));
+    assert_eq!(eval("1 / 0"), Err("division by zero".to_string()));
+    assert_eq!(eval("[0x1000]"), Err("[0x1000] is outside memory".to_string()));
+    assert!(eval("v3 ==").is_err());
+    assert!(eval("[1").is_err());
+    assert!(eval("lives > 0").is_err());
+}
@@ -0,0 +1,530 @@
+use std::io::{self, Read, Write, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::sync::mpsc::{self, Sender, Receiver};
+
+use debugger::{DebugRequest, DebugReply, Register, request};
+use emulator::Command;
+use machine::{Registers, Stop, Watchpoint, WatchKind, Breakpoint, MEMORY_SIZE};
+
+/// Registers in the order of the `g` packet and the target description. V0-VF come first
+/// as register numbers 0-15.
+const REGISTER_I: usize = 16;
+const REGISTER_PC: usize = 17;
+const REGISTER_SP: usize = 18;
+const REGISTER_DT: usize = 19;
+const REGISTER_ST: usize = 20;
+const REGISTER_COUNT: usize = 21;
+
+/// Largest memory read a client may ask for in one packet.
+const MAX_READ: usize = 0x1000;
+
+
+/// What the connection reader passes on to the session.
+enum Input {
+    Packet(String),
+    BadPacket,
+    /// Ctrl-C from the client while the target is running.
+    Interrupt,
+    Closed,
+}
+
+struct Session {
+    commands: Sender<Command>,
+    stream: TcpStream,
+    /// Continued and waiting for a stop to report.
+    running: bool,
+    /// The client detached or killed the session.
+    finished: bool,
+}
+
+
+/// Listens for GDB remote serial protocol clients on a local port, serving one at a time.
+/// Attaching pauses the emulator; detaching lets it run again.
+pub fn spawn(port: u16, commands: Sender<Command>) -> io::Result<thread::JoinHandle<()>> {
+    let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+    println!("gdb server listening on 127.0.0.1:{}", port);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            if let Err(e) = serve(stream, commands.clone()) {
+                println!("gdb: {}", e);
+            }
+        }
+    }))
+}
+
+fn serve(stream: TcpStream, commands: Sender<Command>) -> Result<(), String> {
+    let (input_tx, input_rx) = mpsc::channel();
+    let reader = try!(stream.try_clone().map_err(|e| e.to_string()));
+    thread::spawn(move || read_packets(reader, input_tx));
+
+    let (events_tx, events_rx) = mpsc::channel();
+    try!(commands.send(Command::Debug(DebugRequest::Subscribe, events_tx))
+        .map_err(|_| "emulator has exited"));
+    try!(request(&commands, DebugRequest::Pause));
+
+    let mut session = Session {
+        commands: commands,
+        stream: stream,
+        running: false,
+        finished: false,
+    };
+    session.run(input_rx, events_rx)
+}
+
+fn next_byte<R: Read>(bytes: &mut io::Bytes<R>) -> Option<u8> {
+    match bytes.next() {
+        Some(Ok(byte)) => Some(byte),
+        _ => None,
+    }
+}
+
+/// Splits the byte stream into packets (`$data#checksum`) and interrupts.
+fn read_packets(stream: TcpStream, input: Sender<Input>) {
+    let mut bytes = BufReader::new(stream).bytes();
+    loop {
+        let event = match next_byte(&mut bytes) {
+            None => {
+                input.send(Input::Closed).ok();
+                return;
+            }
+            Some(0x03) => Input::Interrupt,
+            Some(b'$') => {
+                let mut data = Vec::new();
+                let mut sum = 0u8;
+                loop {
+                    match next_byte(&mut bytes) {
+                        Some(b'#') => break,
+                        Some(byte) => {
+                            sum = sum.wrapping_add(byte);
+                            data.push(byte);
+                        }
+                        None => {
+                            input.send(Input::Closed).ok();
+                            return;
+                        }
+                    }
+                }
+                let checksum = [next_byte(&mut bytes).unwrap_or(0),
+                                next_byte(&mut bytes).unwrap_or(0)];
+                match hex_byte(checksum[0], checksum[1]) {
+                    Some(expected) if expected == sum => {
+                        Input::Packet(String::from_utf8_lossy(&data).into_owned())
+                    }
+                    _ => Input::BadPacket,
+                }
+            }
+            // Acknowledgements; nothing is ever resent.
+            Some(_) => continue,
+        };
+        if input.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|n| n as u8)
+}
+
+/// A byte from two hex digits. Packets come from the network, so this works on bytes
+/// rather than slicing strings that may hold multi-byte characters.
+fn hex_byte(high: u8, low: u8) -> Option<u8> {
+    match (hex_digit(high), hex_digit(low)) {
+        (Some(high), Some(low)) => Some(high << 4 | low),
+        _ => None,
+    }
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = text.as_bytes();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    digits.chunks(2).map(|pair| hex_byte(pair[0], pair[1])).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+/// Parses `ADDR,LEN` as used by the memory packets. The address isn't checked against
+/// memory, since `qXfer` uses the same form for offsets into documents.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+        (Some(addr), Some(len)) => Some((addr as usize, len as usize)),
+        _ => None,
+    }
+}
+
+/// One register's value in target byte order (little endian), sized per the description.
+fn register_bytes(registers: &Registers, n: usize) -> Vec<u8> {
+    let word = |w: u16| vec![w as u8, (w >> 8) as u8];
+    match n {
+        0...15 => vec![registers.v[n]],
+        REGISTER_I => word(registers.i),
+        REGISTER_PC => word(registers.pc),
+        REGISTER_SP => vec![registers.stack.len() as u8],
+        REGISTER_DT => vec![registers.delay_timer],
+        _ => vec![registers.sound_timer],
+    }
+}
+
+fn register_size(n: usize) -> usize {
+    match n {
+        REGISTER_I | REGISTER_PC => 2,
+        _ => 1,
+    }
+}
+
+/// The register a client may write. SP only reflects the shadow call stack.
+fn writable_register(n: usize) -> Option<Register> {
+    match n {
+        0...15 => Some(Register::V(n)),
+        REGISTER_I => Some(Register::I),
+        REGISTER_PC => Some(Register::Pc),
+        REGISTER_DT => Some(Register::DelayTimer),
+        REGISTER_ST => Some(Register::SoundTimer),
+        _ => None,
+    }
+}
+
+pub fn target_xml() -> String {
+    let mut regs = String::new();
+    for x in 0..16 {
+        regs.push_str(&format!("    <reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>\n", x));
+    }
+    regs.push_str("    <reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>\n");
+    regs.push_str("    <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\n");
+    regs.push_str("    <reg name=\"sp\" bitsize=\"8\" type=\"uint8\"/>\n");
+    regs.push_str("    <reg name=\"dt\" bitsize=\"8\" type=\"uint8\"/>\n");
+    regs.push_str("    <reg name=\"st\" bitsize=\"8\" type=\"uint8\"/>\n");
+    format!("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+             <target version=\"1.0\">\n  <feature name=\"org.vipchip.chip8\">\n{}  \
+             </feature>\n</target>\n",
+            regs)
+}
+
+/// A stop reply packet. Watchpoints report the address they caught.
+fn stop_reply(stop: Option<Stop>) -> String {
+    match stop {
+        Some(Stop::Watchpoint(hit)) => {
+            let name = match hit.watchpoint.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access | WatchKind::Pointer => "awatch",
+            };
+            let addr = if hit.access.addr > hit.watchpoint.start {
+                hit.access.addr
+            } else {
+                hit.watchpoint.start
+            };
+            format!("T05{}:{:x};", name, addr)
+        }
+        _ => "S05".to_string(),
+    }
+}
+
+/// Answers `qXfer:...:read` with the part of `document` at `OFF,LEN`.
+fn xfer(document: &str, range: &str) -> String {
+    let (offset, len) = match parse_range(range) {
+        Some((offset, len)) => (offset, len),
+        None => return "E01".to_string(),
+    };
+    if offset >= document.len() {
+        return "l".to_string();
+    }
+    let end = offset + len;
+    if end >= document.len() {
+        format!("l{}", &document[offset..])
+    } else {
+        format!("m{}", &document[offset..end])
+    }
+}
+
+
+impl Session {
+    fn run(&mut self,
+           input: Receiver<Input>,
+           events: Receiver<DebugReply>)
+           -> Result<(), String> {
+        while !self.finished {
+            select! {
+                packet = input.recv() => {
+                    match packet {
+                        Ok(Input::Packet(packet)) => {
+                            try!(self.send_raw(b"+"));
+                            if let Some(reply) = self.handle(&packet) {
+                                try!(self.send(&reply));
+                            }
+                        }
+                        Ok(Input::BadPacket) => try!(self.send_raw(b"-")),
+                        Ok(Input::Interrupt) => {
+                            if self.running {
+                                try!(request(&self.commands, DebugRequest::Pause));
+                                self.running = false;
+                                try!(self.send("S02"));
+                            }
+                        }
+                        Ok(Input::Closed) | Err(_) => break,
+                    }
+                },
+                event = events.recv() => {
+                    match event {
+                        Ok(DebugReply::Stopped { stop, .. }) if self.running => {
+                            self.running = false;
+                            try!(self.send(&stop_reply(stop)));
+                        }
+                        Ok(_) => (),
+                        Err(_) => break,
+                    }
+                }
+            }
+        }
+        // Don't leave the program stuck after the client goes away.
+        request(&self.commands, DebugRequest::Continue).ok();
+        Ok(())
+    }
+
+    fn send_raw(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.stream.write_all(bytes).map_err(|e| e.to_string())
+    }
+
+    fn send(&mut self, data: &str) -> Result<(), String> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        self.send_raw(packet.as_bytes())
+    }
+
+    fn registers(&self) -> Result<Registers, String> {
+        match try!(request(&self.commands, DebugRequest::Registers)) {
+            DebugReply::Registers(registers) => Ok(registers),
+            _ => Err("unexpected reply".to_string()),
+        }
+    }
+
+    fn set_register(&self, n: usize, bytes: &[u8]) -> Result<(), String> {
+        if let Some(register) = writable_register(n) {
+            let value = bytes.iter().rev().fold(0u16, |value, &b| value << 8 | b as u16);
+            try!(request(&self.commands, DebugRequest::SetRegister(register, value)));
+        }
+        Ok(())
+    }
+
+    /// Sets PC for `c ADDR` and `s ADDR`.
+    fn resume_at(&self, addr: &str) -> Result<(), String> {
+        if let Some(addr) = parse_hex(addr) {
+            try!(request(&self.commands, DebugRequest::SetRegister(Register::Pc, addr as u16)));
+        }
+        Ok(())
+    }
+
+    /// Returns the reply to a packet, or `None` if it comes later (after `c`) or not at all.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        match self.handle_packet(packet) {
+            Ok(reply) => reply,
+            Err(_) => Some("E01".to_string()),
+        }
+    }
+
+    fn handle_packet(&mut self, packet: &str) -> Result<Option<String>, String> {
+        // Packets can hold any bytes, so only split off the command if it's ASCII.
+        let (command, args) = match packet.as_bytes().first() {
+            Some(&byte) if byte < 0x80 => (byte as char, &packet[1..]),
+            Some(_) => return Ok(Some(String::new())),
+            None => ('\0', ""),
+        };
+        let reply = match command {
+            '?' => "S05".to_string(),
+            'g' => {
+                let registers = try!(self.registers());
+                let bytes: Vec<u8> = (0..REGISTER_COUNT)
+                    .flat_map(|n| register_bytes(&registers, n))
+                    .collect();
+                to_hex(&bytes)
+            }
+            'G' => {
+                let bytes = try!(from_hex(args).ok_or("bad hex"));
+                let mut offset = 0;
+                for n in 0..REGISTER_COUNT {
+                    let size = register_size(n);
+                    if offset + size > bytes.len() {
+                        break;
+                    }
+                    try!(self.set_register(n, &bytes[offset..offset + size]));
+                    offset += size;
+                }
+                "OK".to_string()
+            }
+            'p' => {
+                let n = try!(parse_hex(args).ok_or("bad register")) as usize;
+                if n >= REGISTER_COUNT {
+                    return Ok(Some("E02".to_string()));
+                }
+                let registers = try!(self.registers());
+                to_hex(&register_bytes(&registers, n))
+            }
+            'P' => {
+                let mut parts = args.splitn(2, '=');
+                let n = try!(parts.next().and_then(parse_hex).ok_or("bad register")) as usize;
+                let bytes = try!(parts.next().and_then(from_hex).ok_or("bad hex"));
+                try!(self.set_register(n, &bytes));
+                "OK".to_string()
+            }
+            'm' => {
+                let (addr, len) = try!(parse_range(args).ok_or("bad range"));
+                if addr >= MEMORY_SIZE {
+                    return Ok(Some("E01".to_string()));
+                }
+                // Reads that run past the end of memory come back short, which GDB allows.
+                let len = if len > MAX_READ { MAX_READ } else { len };
+                match try!(request(&self.commands, DebugRequest::ReadMemory(addr as u16, len))) {
+                    DebugReply::Memory(_, bytes) => to_hex(&bytes),
+                    _ => "E01".to_string(),
+                }
+            }
+            'M' => {
+                let mut parts = args.splitn(2, ':');
+                let (addr, _) = try!(parts.next().and_then(parse_range).ok_or("bad range"));
+                let bytes = try!(parts.next().and_then(from_hex).ok_or("bad hex"));
+                if addr + bytes.len() > MEMORY_SIZE {
+                    return Ok(Some("E01".to_string()));
+                }
+                try!(request(&self.commands, DebugRequest::WriteMemory(addr as u16, bytes)));
+                "OK".to_string()
+            }
+            'Z' | 'z' => {
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let addr = parts.next().and_then(parse_hex);
+                match (kind, addr) {
+                    (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                        let addr = addr as u16;
+                        let breakpoint = if command == 'Z' {
+                            DebugRequest::AddBreakpoint(Breakpoint::new(addr))
+                        } else {
+                            DebugRequest::RemoveBreakpoint(addr)
+                        };
+                        // Removing a breakpoint that isn't there is fine.
+                        request(&self.commands, breakpoint).ok();
+                        "OK".to_string()
+                    }
+                    (Some(kind @ "2"), Some(addr)) |
+                    (Some(kind @ "3"), Some(addr)) |
+                    (Some(kind @ "4"), Some(addr)) => {
+                        let len = parts.next().and_then(parse_hex).unwrap_or(1);
+                        let watchpoint = Watchpoint {
+                            start: addr as u16,
+                            end: (addr + len.saturating_sub(1)) as u16,
+                            kind: match kind {
+                                "2" => WatchKind::Write,
+                                "3" => WatchKind::Read,
+                                _ => WatchKind::Access,
+                            },
+                        };
+                        let watch = if command == 'Z' {
+                            DebugRequest::AddWatchpoint(watchpoint)
+                        } else {
+                            DebugRequest::RemoveWatchpoint(watchpoint)
+                        };
+                        request(&self.commands, watch).ok();
+                        "OK".to_string()
+                    }
+                    _ => String::new(),
+                }
+            }
+            'c' => {
+                try!(self.resume_at(args));
+                try!(request(&self.commands, DebugRequest::Continue));
+                self.running = true;
+                return Ok(None);
+            }
+            's' => {
+                try!(self.resume_at(args));
+                match try!(request(&self.commands, DebugRequest::Step(1))) {
+                    DebugReply::Stopped { stop, .. } => stop_reply(stop),
+                    _ => "S05".to_string(),
+                }
+            }
+            // Reverse step and continue. Going back to the start without a hit is the start
+            // of the replay log as far as the client is concerned.
+            'b' if args == "s" || args == "c" => {
+                let reverse = if args == "s" {
+                    DebugRequest::ReverseStep(1)
+                } else {
+                    DebugRequest::ReverseContinue
+                };
+                match try!(request(&self.commands, reverse)) {
+                    DebugReply::Stopped { stop: None, .. } if args == "c" => {
+                        "T05replaylog:begin;".to_string()
+                    }
+                    DebugReply::Stopped { stop, .. } => stop_reply(stop),
+                    _ => "S05".to_string(),
+                }
+            }
+            'D' => {
+                self.finished = true;
+                "OK".to_string()
+            }
+            'k' => {
+                self.finished = true;
+                return Ok(None);
+            }
+            'H' => "OK".to_string(),
+            'q' if args.starts_with("Supported") => {
+                "PacketSize=1000;qXfer:features:read+;ReverseStep+;ReverseContinue+".to_string()
+            }
+            'q' if args.starts_with("Xfer:features:read:target.xml:") => {
+                xfer(&target_xml(), &args["Xfer:features:read:target.xml:".len()..])
+            }
+            'q' if args == "Attached" => "1".to_string(),
+            // An empty reply tells the client a packet isn't supported.
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+}
+
+
+#[test]
+fn test_hex() {
+    assert_eq!(to_hex(&[0x00, 0xAB, 0x12]), "00ab12");
+    assert_eq!(from_hex("00ab12"), Some(vec![0x00, 0xAB, 0x12]));
+    assert_eq!(from_hex("0g"), None);
+    assert_eq!(from_hex("\u{e9}"), None);
+    assert_eq!(hex_byte(b'f', b'F'), Some(0xFF));
+    assert_eq!(parse_range("200,10"), Some((0x200, 16)));
+    assert_eq!(parse_range("10200,10"), Some((0x10200, 16)));
+}
+
+#[test]
+fn test_register_bytes() {
+    let registers = Registers {
+        v: [0x11; 16],
+        i: 0x0234,
+        pc: 0x0200,
+        delay_timer: 3,
+        sound_timer: 4,
+        stack: vec![0x204],
+    };
+    let bytes: Vec<u8> = (0..REGISTER_COUNT).flat_map(|n| register_bytes(&registers, n)).collect();
+    assert_eq!(bytes.len(), 23);
+    assert_eq!(&bytes[16..], &[0x34, 0x02, 0x00, 0x02, 1, 3, 4]);
+}
+
+#[test]
+fn test_xfer() {
+    assert_eq!(xfer("abcdef", "0,4"), "mabcd");
+    assert_eq!(xfer("abcdef", "4,10"), "lef");
+    assert_eq!(xfer("abcdef", "6,10"), "l");
+}
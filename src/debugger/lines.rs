@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::io::Read;
+
+use fileio::is_hex_source;
+
+/// Where programs are loaded.
+const PROGRAM_START: u16 = 0x200;
+
+
+/// Maps program addresses to lines of the hex listing they were loaded from, so debugger
+/// front ends can show and break on source lines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineMap {
+    /// First address on each line that has any bytes, in address order. Lines count from 1.
+    lines: Vec<(u16, usize)>,
+    end: u16,
+}
+
+/// A program's source file, for mapping breakpoints, stack frames and coverage to lines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub path: String,
+    pub lines: LineMap,
+}
+
+impl Source {
+    /// Loads the source for a program file, if it is a hex listing.
+    pub fn load(path: &str) -> Option<Source> {
+        LineMap::load(path).map(|lines| {
+            Source {
+                path: path.to_string(),
+                lines: lines,
+            }
+        })
+    }
+}
+
+impl LineMap {
+    /// Builds the map from hex source text, counting `0xNN` literals the way the hex loader
+    /// reads them.
+    pub fn from_hex_source(text: &str) -> LineMap {
+        let mut lines = Vec::new();
+        let mut addr = PROGRAM_START;
+        for (n, line) in text.lines().enumerate() {
+            let bytes = line.matches("0x").count() + line.matches("0X").count();
+            if bytes > 0 {
+                lines.push((addr, n + 1));
+                addr = addr.wrapping_add(bytes as u16);
+            }
+        }
+        LineMap {
+            lines: lines,
+            end: addr,
+        }
+    }
+
+    /// Loads the map for a program file, if it is a hex listing.
+    pub fn load(path: &str) -> Option<LineMap> {
+        let mut data = Vec::new();
+        if File::open(path).and_then(|mut f| f.read_to_end(&mut data)).is_err() ||
+           !is_hex_source(&data) {
+            return None;
+        }
+        Some(LineMap::from_hex_source(&String::from_utf8_lossy(&data)))
+    }
+
+    /// The first address on `line`, or on the next line with code if it has none.
+    pub fn address_of(&self, line: usize) -> Option<u16> {
+        self.lines.iter().find(|&&(_, l)| l >= line).map(|&(addr, _)| addr)
+    }
+
+    /// Each line with code, with the addresses of its bytes as a half-open range.
+    pub fn ranges(&self) -> Vec<(usize, u16, u16)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .map(|(n, &(start, line))| {
+                let end = self.lines.get(n + 1).map(|&(addr, _)| addr).unwrap_or(self.end);
+                (line, start, end)
+            })
+            .collect()
+    }
+
+    /// The line that holds the byte at `addr`.
+    pub fn line_of(&self, addr: u16) -> Option<usize> {
+        if addr < PROGRAM_START || addr >= self.end {
+            return None;
+        }
+        self.lines.iter().rev().find(|&&(a, _)| a <= addr).map(|&(_, line)| line)
+    }
+}
+
+
+#[test]
+fn test_line_map() {
+    let map = LineMap::from_hex_source("[0x00, 0xE0,\n\n 0x12, 0x00, 0xA2, 0x10]\n");
+    assert_eq!(map.address_of(1), Some(0x200));
+    assert_eq!(map.address_of(2), Some(0x202));
+    assert_eq!(map.line_of(0x201), Some(1));
+    assert_eq!(map.line_of(0x205), Some(3));
+    assert_eq!(map.line_of(0x206), None);
+    assert_eq!(map.ranges(), vec![(1, 0x200, 0x202), (3, 0x202, 0x206)]);
+}
@@ -0,0 +1,112 @@
+//! Requests that debugger front ends send to the emulation thread, and its replies. Each
+//! request carries the channel to answer on; see `emulator::Command::Debug`.
+
+pub mod console;
+pub mod gdb;
+pub mod dap;
+pub mod lines;
+pub mod expr;
+
+use std::sync::mpsc::{self, Sender};
+
+use emulator::Command;
+use machine::{Stop, Registers, Watchpoint, Breakpoint};
+
+use self::expr::Expr;
+
+
+/// A register a debugger can write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    V(usize),
+    I,
+    Pc,
+    DelayTimer,
+    SoundTimer,
+}
+
+pub enum DebugRequest {
+    /// Also sends stops that happen while running to the reply channel.
+    Subscribe,
+    Pause,
+    Continue,
+    /// Runs up to N instructions, even while paused.
+    Step(usize),
+    /// Steps, running a CALL at PC through to its return.
+    StepOver,
+    /// Runs until the current subroutine returns.
+    StepOut,
+    /// Goes back N instructions by replaying from reset.
+    ReverseStep(usize),
+    /// Goes back to the last breakpoint or watchpoint hit, or to the start if there wasn't
+    /// one.
+    ReverseContinue,
+    Registers,
+    /// Reads up to N bytes, stopping at the end of memory. Fails if the address is past it.
+    ReadMemory(u16, usize),
+    /// Fails without writing anything if the bytes would run past the end of memory.
+    WriteMemory(u16, Vec<u8>),
+    SetRegister(Register, u16),
+    /// Replaces any breakpoint already at the same address.
+    AddBreakpoint(Breakpoint),
+    RemoveBreakpoint(u16),
+    Breakpoints,
+    AddWatchpoint(Watchpoint),
+    RemoveWatchpoint(Watchpoint),
+    Watchpoints,
+    Evaluate(Expr),
+}
+
+pub enum DebugReply {
+    Ok,
+    Error(String),
+    Registers(Registers),
+    Memory(u16, Vec<u8>),
+    Breakpoints(Vec<Breakpoint>),
+    Watchpoints(Vec<Watchpoint>),
+    Value(u32),
+    /// Execution is paused at `pc`. `stop` is the reason, or `None` if it was paused on
+    /// request or a step ran to completion.
+    Stopped {
+        stop: Option<Stop>,
+        pc: u16,
+        codeword: u16,
+    },
+}
+
+
+/// Parses a register name: `v0`-`vf`, `i`, `pc`, `dt` or `st`, in either case.
+pub fn parse_register(name: &str) -> Option<Register> {
+    let name = name.to_lowercase();
+    match &name[..] {
+        "i" => Some(Register::I),
+        "pc" => Some(Register::Pc),
+        "dt" => Some(Register::DelayTimer),
+        "st" => Some(Register::SoundTimer),
+        _ if name.len() == 2 && name.starts_with('v') => {
+            usize::from_str_radix(&name[1..], 16).ok().map(Register::V)
+        }
+        _ => None,
+    }
+}
+
+/// Sends a request to the emulation thread and waits for the answer.
+pub fn request(commands: &Sender<Command>, request: DebugRequest) -> Result<DebugReply, String> {
+    let (tx, rx) = mpsc::channel();
+    try!(commands.send(Command::Debug(request, tx)).map_err(|_| "emulator has exited"));
+    match rx.recv() {
+        Ok(DebugReply::Error(e)) => Err(e),
+        Ok(reply) => Ok(reply),
+        Err(_) => Err("emulator has exited".to_string()),
+    }
+}
+
+
+#[test]
+fn test_parse_register() {
+    assert_eq!(parse_register("vA"), Some(Register::V(10)));
+    assert_eq!(parse_register("PC"), Some(Register::Pc));
+    assert_eq!(parse_register("dt"), Some(Register::DelayTimer));
+    assert_eq!(parse_register("vg"), None);
+    assert_eq!(parse_register("v10"), None);
+}
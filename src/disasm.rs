@@ -0,0 +1,242 @@
+use std::fmt;
+
+use symbols::Symbols;
+
+/// A decoded CHIP-8 or SCHIP instruction. Register operands are indices 0-F.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    /// 0NNN: call a machine code routine on the original hardware.
+    Sys(u16),
+    Cls,
+    Ret,
+    ScrollDown(u8),
+    ScrollRight,
+    ScrollLeft,
+    /// 00FD: SCHIP exit interpreter.
+    Exit,
+    LowRes,
+    HighRes,
+    Jump(u16),
+    Call(u16),
+    SkipEqImm(usize, u8),
+    SkipNeImm(usize, u8),
+    SkipEqReg(usize, usize),
+    LoadImm(usize, u8),
+    AddImm(usize, u8),
+    Move(usize, usize),
+    Or(usize, usize),
+    And(usize, usize),
+    Xor(usize, usize),
+    Add(usize, usize),
+    Sub(usize, usize),
+    ShiftRight(usize, usize),
+    SubN(usize, usize),
+    ShiftLeft(usize, usize),
+    SkipNeReg(usize, usize),
+    LoadI(u16),
+    JumpV0(u16),
+    Random(usize, u8),
+    Draw(usize, usize, u8),
+    SkipKey(usize),
+    SkipNoKey(usize),
+    GetDelay(usize),
+    WaitKey(usize),
+    SetDelay(usize),
+    SetSound(usize),
+    AddI(usize),
+    Font(usize),
+    BigFont(usize),
+    Bcd(usize),
+    Store(usize),
+    Load(usize),
+    SaveFlags(usize),
+    LoadFlags(usize),
+    Unknown(u16),
+}
+
+pub fn decode(codeword: u16) -> Instruction {
+    use self::Instruction::*;
+
+    let x = ((codeword >> 8) & 0xF) as usize;
+    let y = ((codeword >> 4) & 0xF) as usize;
+    let n = (codeword & 0xF) as u8;
+    let nn = (codeword & 0xFF) as u8;
+    let nnn = codeword & 0xFFF;
+
+    match codeword >> 12 {
+        0x0 => {
+            match nnn {
+                0x0E0 => Cls,
+                0x0EE => Ret,
+                0x0FB => ScrollRight,
+                0x0FC => ScrollLeft,
+                0x0FD => Exit,
+                0x0FE => LowRes,
+                0x0FF => HighRes,
+                _ if nnn & 0xFF0 == 0x0C0 => ScrollDown(n),
+                _ => Sys(nnn),
+            }
+        }
+        0x1 => Jump(nnn),
+        0x2 => Call(nnn),
+        0x3 => SkipEqImm(x, nn),
+        0x4 => SkipNeImm(x, nn),
+        0x5 if n == 0 => SkipEqReg(x, y),
+        0x6 => LoadImm(x, nn),
+        0x7 => AddImm(x, nn),
+        0x8 => {
+            match n {
+                0x0 => Move(x, y),
+                0x1 => Or(x, y),
+                0x2 => And(x, y),
+                0x3 => Xor(x, y),
+                0x4 => Add(x, y),
+                0x5 => Sub(x, y),
+                0x6 => ShiftRight(x, y),
+                0x7 => SubN(x, y),
+                0xE => ShiftLeft(x, y),
+                _ => Unknown(codeword),
+            }
+        }
+        0x9 if n == 0 => SkipNeReg(x, y),
+        0xA => LoadI(nnn),
+        0xB => JumpV0(nnn),
+        0xC => Random(x, nn),
+        0xD => Draw(x, y, n),
+        0xE if nn == 0x9E => SkipKey(x),
+        0xE if nn == 0xA1 => SkipNoKey(x),
+        0xF => {
+            match nn {
+                0x07 => GetDelay(x),
+                0x0A => WaitKey(x),
+                0x15 => SetDelay(x),
+                0x18 => SetSound(x),
+                0x1E => AddI(x),
+                0x29 => Font(x),
+                0x30 => BigFont(x),
+                0x33 => Bcd(x),
+                0x55 => Store(x),
+                0x65 => Load(x),
+                0x75 => SaveFlags(x),
+                0x85 => LoadFlags(x),
+                _ => Unknown(codeword),
+            }
+        }
+        _ => Unknown(codeword),
+    }
+}
+
+impl Instruction {
+    /// The address a jump, call or I load refers to.
+    pub fn target(&self) -> Option<u16> {
+        use self::Instruction::*;
+
+        match *self {
+            Sys(nnn) | Jump(nnn) | Call(nnn) | LoadI(nnn) | JumpV0(nnn) => Some(nnn),
+            _ => None,
+        }
+    }
+
+    /// `0NNN` machine code calls and opcodes no platform defines, which interpreters can't
+    /// run.
+    pub fn is_illegal(&self) -> bool {
+        match *self {
+            Instruction::Sys(_) | Instruction::Unknown(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Formats in the mnemonic style of Cowgod's CHIP-8 technical reference.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Instruction::*;
+
+        match *self {
+            Sys(nnn) => write!(f, "SYS {:#05X}", nnn),
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            ScrollDown(n) => write!(f, "SCD {}", n),
+            ScrollRight => write!(f, "SCR"),
+            ScrollLeft => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            LowRes => write!(f, "LOW"),
+            HighRes => write!(f, "HIGH"),
+            Jump(nnn) => write!(f, "JP {:#05X}", nnn),
+            Call(nnn) => write!(f, "CALL {:#05X}", nnn),
+            SkipEqImm(x, nn) => write!(f, "SE V{:X}, {:#04X}", x, nn),
+            SkipNeImm(x, nn) => write!(f, "SNE V{:X}, {:#04X}", x, nn),
+            SkipEqReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            LoadImm(x, nn) => write!(f, "LD V{:X}, {:#04X}", x, nn),
+            AddImm(x, nn) => write!(f, "ADD V{:X}, {:#04X}", x, nn),
+            Move(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Add(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            SubN(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SkipNeReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LoadI(nnn) => write!(f, "LD I, {:#05X}", nnn),
+            JumpV0(nnn) => write!(f, "JP V0, {:#05X}", nnn),
+            Random(x, nn) => write!(f, "RND V{:X}, {:#04X}", x, nn),
+            Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            SkipKey(x) => write!(f, "SKP V{:X}", x),
+            SkipNoKey(x) => write!(f, "SKNP V{:X}", x),
+            GetDelay(x) => write!(f, "LD V{:X}, DT", x),
+            WaitKey(x) => write!(f, "LD V{:X}, K", x),
+            SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+            SetSound(x) => write!(f, "LD ST, V{:X}", x),
+            AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Font(x) => write!(f, "LD F, V{:X}", x),
+            BigFont(x) => write!(f, "LD HF, V{:X}", x),
+            Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Store(x) => write!(f, "LD [I], V{:X}", x),
+            Load(x) => write!(f, "LD V{:X}, [I]", x),
+            SaveFlags(x) => write!(f, "LD R, V{:X}", x),
+            LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+            Unknown(codeword) => write!(f, "DW {:#06X}", codeword),
+        }
+    }
+}
+
+/// The mnemonic, with the target address named if there is a symbol for it.
+pub fn symbolic(instruction: Instruction, symbols: &Symbols) -> String {
+    let text = instruction.to_string();
+    match instruction.target().and_then(|addr| symbols.name_of(addr).map(|name| (addr, name))) {
+        Some((addr, name)) => text.replace(&format!("{:#05X}", addr), name),
+        None => text,
+    }
+}
+
+/// One line of a listing: address, raw codeword and mnemonic.
+pub fn listing_line(addr: u16, codeword: u16, symbols: &Symbols) -> String {
+    format!("{:#05X}: {:04X}  {}", addr, codeword, symbolic(decode(codeword), symbols))
+}
+
+
+#[test]
+fn test_decode() {
+    use self::Instruction::*;
+    assert_eq!(decode(0x00E0), Cls);
+    assert_eq!(decode(0x00FD), Exit);
+    assert_eq!(decode(0x00C4), ScrollDown(4));
+    assert_eq!(decode(0x0123), Sys(0x123));
+    assert_eq!(decode(0x1208), Jump(0x208));
+    assert_eq!(decode(0x8AB4), Add(0xA, 0xB));
+    assert_eq!(decode(0x8AB8), Unknown(0x8AB8));
+    assert_eq!(decode(0xD125), Draw(1, 2, 5));
+    assert_eq!(decode(0xF30A), WaitKey(3));
+    assert_eq!(decode(0x5121), Unknown(0x5121));
+}
+
+#[test]
+fn test_display() {
+    assert_eq!(decode(0xD125).to_string(), "DRW V1, V2, 5");
+    assert_eq!(decode(0x6A0F).to_string(), "LD VA, 0x0F");
+    let symbols = Symbols::parse("loop = 0x208").unwrap();
+    assert_eq!(listing_line(0x200, 0x1208, &Symbols::new()), "0x200: 1208  JP 0x208");
+    assert_eq!(listing_line(0x200, 0x1208, &symbols), "0x200: 1208  JP loop");
+}
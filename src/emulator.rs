@@ -1,90 +1,609 @@
 use std::thread;
 use std::time::{Duration, SystemTime};
+use std::sync::mpsc::{Sender, Receiver, TryRecvError};
 
-use chip8::Config;
-use chip8::{ SimulatorTask, Simulate };
+use chip8::Keyboard;
 
+use machine::{Machine, Stop, Halt, Hook, MEMORY_SIZE};
+use debugger::{DebugRequest, DebugReply};
+use disasm::{decode, Instruction};
+use movie::MovieMode;
+use speed::SpeedControl;
+use framebuffer::{FrameWriter, DebugView};
+use replay::{History, ReplayStart};
+use script::Script;
 
+
+/// Messages from the UI to the emulation thread.
+pub enum Command {
+    SetKeys(Keyboard),
+    TogglePause,
+    AdvanceFrame,
+    FastForward(bool),
+    SlowDown,
+    SpeedUp,
+    /// Whether published frames should include a `DebugView` for the debug panels.
+    ShowDebugView(bool),
+    /// A request from a debugger front end, answered on the given channel.
+    Debug(DebugRequest, Sender<DebugReply>),
+    Quit,
+}
+
+/// Messages from the emulation thread to the UI. Frames themselves go through the shared
+/// frame buffer instead.
+pub enum EmulatorEvent {
+    Status(Option<String>),
+    /// The machine stopped mid-frame and emulation has been paused.
+    Stopped(Stop),
+    /// The emulation thread has finished, with the process exit status it wants.
+    Exited(i32),
+}
+
+
+/// Exit status when the program halts on an illegal opcode, whatever the policy's code is,
+/// since that's a crash rather than the program finishing.
+pub const ILLEGAL_OPCODE_EXIT_CODE: i32 = 4;
+
+/// What to do when the program halts (see `machine::Halt`).
+#[derive(Debug, Clone, Copy)]
+pub struct HaltPolicy {
+    /// Quit with an exit status instead of pausing.
+    pub exit: bool,
+    pub code: i32,
+    /// Take the exit status from this register instead of `code`, so test ROMs can report
+    /// their own result.
+    pub result_register: Option<usize>,
+}
+
+impl HaltPolicy {
+    pub fn exit_code(&self, halt: Halt, machine: &mut Machine) -> i32 {
+        match (halt, self.result_register) {
+            (Halt::Script(_, code), _) => code,
+            (Halt::IllegalOpcode(_), _) => ILLEGAL_OPCODE_EXIT_CODE,
+            (_, Some(x)) => machine.register(x) as i32,
+            (_, None) => self.code,
+        }
+    }
+}
+
+
+/// Drives a `Machine` one frame at a time and owns everything that has to stay in lockstep
+/// with emulated frames, like movie recording.
 pub struct Emulator {
-    config: Config,
-    pub core: SimulatorTask,
-    start_time: SystemTime,
-    num_processed: u64,
+    pub machine: Machine,
+    movie: MovieMode,
+    instructions_per_frame: usize,
+    frame: u64,
+    /// Instructions still to run in the current frame, if it was interrupted by a stop.
+    frame_remaining: usize,
+    halt_policy: HaltPolicy,
+    /// Debuggers to tell about stops that happen while running.
+    debuggers: Vec<Sender<DebugReply>>,
+    debug_view: bool,
+    /// Inputs logged for reverse execution, if it's enabled.
+    history: Option<History>,
+    script: Option<Script>,
 }
 
 impl Emulator {
-    pub fn new(config: Config) -> Emulator {
-        let core = SimulatorTask::spawn(config);
-
+    pub fn new(machine: Machine, movie: MovieMode, instructions_per_frame: usize) -> Emulator {
         Emulator {
-            config: config,
-            core: core,
-            start_time: SystemTime::now(),
-            num_processed: 0,
+            machine: machine,
+            movie: movie,
+            instructions_per_frame: instructions_per_frame,
+            frame: 0,
+            frame_remaining: 0,
+            halt_policy: HaltPolicy {
+                exit: false,
+                code: 0,
+                result_register: None,
+            },
+            debuggers: Vec::new(),
+            debug_view: false,
+            history: None,
+            script: None,
         }
     }
 
-    pub fn run(&mut self) {
-        let tick = Duration::new(0, 1000000000 / 60);
-        let cycle_time = None;//Some(Duration::new(0, 100));
+    /// Starts logging inputs so debuggers can step backwards. `start` has to describe how
+    /// the machine was built, since going back replays from there.
+    pub fn enable_reverse(&mut self, start: ReplayStart) {
+        self.history = Some(History::new(start, self.instructions_per_frame));
+    }
+
+    pub fn set_script(&mut self, script: Option<Script>) {
+        self.script = script;
+    }
+
+    /// Jump-to-self loops are only treated as halts when the policy is to exit on them.
+    pub fn set_halt_policy(&mut self, policy: HaltPolicy) {
+        self.machine.set_detect_halts(policy.exit);
+        self.halt_policy = policy;
+    }
+
+    pub fn halt_policy(&self) -> HaltPolicy {
+        self.halt_policy
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Runs one emulated frame: a fixed number of instructions followed by a timer tick, so
+    /// that a given input sequence always produces the same result. If the machine stops
+    /// part way through, the next call picks up where this one left off.
+    pub fn run_frame(&mut self) -> Option<Stop> {
+        if self.frame_remaining == 0 {
+            self.begin_frame();
+        }
+
+        let before = self.machine.instructions();
+        let stop = self.machine
+            .step_n_with(self.frame_remaining, self.script.as_mut().map(|s| s as &mut Hook));
+        self.frame_remaining -= (self.machine.instructions() - before) as usize;
+        if stop.is_some() {
+            return stop;
+        }
+        self.end_frame();
+        None
+    }
 
-        let mut last_timer_tick = SystemTime::now();
-        let mut last_instruction_time;
+    /// Runs a single instruction, keeping the frame accounting of `run_frame`.
+    pub fn step_instruction(&mut self) -> Option<Stop> {
+        if self.frame_remaining == 0 {
+            self.begin_frame();
+        }
 
+        let before = self.machine.instructions();
+        let stop = self.machine.step_with(self.script.as_mut().map(|s| s as &mut Hook));
+        self.frame_remaining -= (self.machine.instructions() - before) as usize;
+        if self.frame_remaining == 0 {
+            self.end_frame();
+        }
+        stop
+    }
+
+    fn begin_frame(&mut self) {
+        if let MovieMode::Playing(ref player) = self.movie {
+            if let Some(keys) = player.next_keys() {
+                let before = self.machine.keyboard();
+                self.machine.set_keyboard(&keys);
+                if let Some(ref mut script) = self.script {
+                    script.keys_changed(&mut self.machine, &before, &keys);
+                }
+                if let Some(ref mut history) = self.history {
+                    history.record_keys(self.machine.instructions(), &keys);
+                }
+            }
+        }
+        self.frame_remaining = self.instructions_per_frame;
+    }
+
+    fn end_frame(&mut self) {
+        self.machine.timer_tick();
+        self.machine.end_profile_frame();
+        self.frame += 1;
+
+        match self.movie {
+            MovieMode::Recording(ref mut recorder) => {
+                let keys = self.machine.keyboard();
+                let checksum = if recorder.wants_checksum() {
+                    Some(self.machine.state_checksum())
+                } else {
+                    None
+                };
+                recorder.record_frame(&keys, checksum);
+            }
+            MovieMode::Playing(ref mut player) => {
+                let checksum = if player.wants_checksum() {
+                    Some(self.machine.state_checksum())
+                } else {
+                    None
+                };
+                if let Err(e) = player.end_frame(checksum) {
+                    println!("{}", e);
+                }
+            }
+            MovieMode::Off => (),
+        }
+
+        let finished = match self.movie {
+            MovieMode::Playing(ref player) => player.finished(),
+            _ => false,
+        };
+        if finished {
+            println!("movie finished, switching to live input");
+            self.movie = MovieMode::Off;
+        }
+
+        if let Some(ref mut script) = self.script {
+            script.end_frame(&mut self.machine, self.frame);
+        }
+    }
+
+    /// Keys from the user are ignored while a movie is playing.
+    pub fn set_keyboard(&mut self, keys: &Keyboard) {
+        if let MovieMode::Playing(_) = self.movie {
+            return;
+        }
+        let before = self.machine.keyboard();
+        self.machine.set_keyboard(keys);
+        if let Some(ref mut history) = self.history {
+            history.record_keys(self.machine.instructions(), keys);
+        }
+        if let Some(ref mut script) = self.script {
+            script.keys_changed(&mut self.machine, &before, keys);
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if let MovieMode::Recording(ref mut recorder) = self.movie {
+            recorder.finish();
+        }
+        self.machine.flush_trace();
+        self.machine.write_profile();
+        self.machine.write_coverage();
+    }
+
+    fn publish(&mut self, frames: &mut FrameWriter) {
+        let vram = self.machine.vram();
+        let keyboard = self.machine.keyboard();
+        let debug = if self.debug_view {
+            Some(DebugView {
+                registers: self.machine.registers(),
+                memory: self.machine.read_memory(0, MEMORY_SIZE),
+                breakpoints: self.machine.breakpoints(),
+                last_sprite: self.machine.last_sprite(),
+            })
+        } else {
+            None
+        };
+        {
+            let frame = frames.back_mut();
+            frame.number = self.frame;
+            frame.vram.clear();
+            frame.vram.extend(vram.iter().cloned());
+            frame.keyboard = keyboard;
+            frame.debug = debug;
+        }
+        frames.publish();
+    }
+
+    /// Runs a frame, pausing if the machine stops. Returns an exit status if the program
+    /// halted and the halt policy says to quit.
+    fn run_frame_and_report(&mut self,
+                            speed: &mut SpeedControl,
+                            events: &Sender<EmulatorEvent>)
+                            -> Option<i32> {
+        match self.run_frame() {
+            Some(Stop::Halted(Halt::Script(_, code))) => {
+                println!("script stopped the run");
+                Some(code)
+            }
+            Some(Stop::Halted(halt)) if self.halt_policy.exit => {
+                println!("halted: {:?}", halt);
+                Some(self.halt_policy.exit_code(halt, &mut self.machine))
+            }
+            Some(stop) => {
+                speed.pause();
+                events.send(EmulatorEvent::Stopped(stop)).ok();
+                self.notify_debuggers(Some(stop));
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn stopped(&mut self, stop: Option<Stop>) -> DebugReply {
+        let pc = self.machine.pc();
+        DebugReply::Stopped {
+            stop: stop,
+            pc: pc,
+            codeword: self.machine.codeword(pc),
+        }
+    }
+
+    fn notify_debuggers(&mut self, stop: Option<Stop>) {
+        let pc = self.machine.pc();
+        let codeword = self.machine.codeword(pc);
+        // Drop debuggers that have gone away.
+        self.debuggers.retain(|debugger| {
+            debugger.send(DebugReply::Stopped {
+                    stop: stop,
+                    pc: pc,
+                    codeword: codeword,
+                })
+                .is_ok()
+        });
+    }
+
+    /// Runs `n` instructions for a debugger, pausing emulation first. Stepping never
+    /// stops at the instruction it starts on.
+    fn debug_step(&mut self, n: usize, speed: &mut SpeedControl) -> DebugReply {
+        speed.pause();
+        self.machine.set_stop_at_depth(None);
+        self.machine.resume();
+        for _ in 0..n {
+            if let Some(stop) = self.step_instruction() {
+                return self.stopped(Some(stop));
+            }
+        }
+        self.stopped(None)
+    }
+
+    /// Whether going back is possible. Movies would lose sync, and scripts change the
+    /// machine in ways the history doesn't log and keep their own state in Lua.
+    fn can_rewind(&self) -> Result<(), String> {
+        match self.movie {
+            MovieMode::Off => (),
+            _ => return Err("can't go back while a movie is recording or playing".to_string()),
+        }
+        if self.script.is_some() {
+            return Err("can't go back while a script is loaded".to_string());
+        }
+        Ok(())
+    }
+
+    /// Replays from reset to `target` instructions and carries on from there.
+    fn rewind(&mut self, target: u64) -> Result<(), String> {
+        try!(self.can_rewind());
+        // Replaying with the breakpoints counts their hits up to the target again.
+        let replayed = match self.history {
+            Some(ref mut history) => {
+                history.truncate(target);
+                history.replay(target, Some(&self.machine)).0
+            }
+            None => return Err("reverse execution isn't enabled".to_string()),
+        };
+        self.machine.restore(replayed);
+        let ipf = self.instructions_per_frame as u64;
+        self.frame = target / ipf;
+        self.frame_remaining = match target % ipf {
+            0 => 0,
+            done => (ipf - done) as usize,
+        };
+        Ok(())
+    }
+
+    /// Goes back `n` instructions, or to the start.
+    fn reverse_step(&mut self, n: usize, speed: &mut SpeedControl) -> DebugReply {
+        speed.pause();
+        self.machine.set_stop_at_depth(None);
+        let target = self.machine.instructions().saturating_sub(n as u64);
+        match self.rewind(target) {
+            Ok(()) => self.stopped(None),
+            Err(e) => DebugReply::Error(e),
+        }
+    }
+
+    /// Goes back to the last place a breakpoint or watchpoint stopped, or would have
+    /// stopped, before now. Without one, goes back to the start.
+    fn reverse_continue(&mut self, speed: &mut SpeedControl) -> DebugReply {
+        speed.pause();
+        self.machine.set_stop_at_depth(None);
+        if let Err(e) = self.can_rewind() {
+            return DebugReply::Error(e);
+        }
+        let now = self.machine.instructions();
+        let last = match self.history {
+            Some(ref history) => {
+                history.replay(now, Some(&self.machine))
+                    .1
+                    .into_iter()
+                    .filter(|&(at, _)| at < now)
+                    .last()
+            }
+            None => None,
+        };
+        let (target, stop) = match last {
+            Some((at, stop)) => (at, Some(stop)),
+            None => (0, None),
+        };
+        match self.rewind(target) {
+            Ok(()) => self.stopped(stop),
+            Err(e) => DebugReply::Error(e),
+        }
+    }
+
+    fn debug(&mut self,
+             request: DebugRequest,
+             reply: Sender<DebugReply>,
+             speed: &mut SpeedControl) {
+        let response = match request {
+            DebugRequest::Subscribe => {
+                self.debuggers.push(reply.clone());
+                DebugReply::Ok
+            }
+            DebugRequest::Pause => {
+                speed.pause();
+                self.machine.set_stop_at_depth(None);
+                self.stopped(None)
+            }
+            DebugRequest::Continue => {
+                self.machine.set_stop_at_depth(None);
+                self.machine.resume();
+                speed.resume();
+                DebugReply::Ok
+            }
+            DebugRequest::Step(n) => self.debug_step(n, speed),
+            DebugRequest::ReverseStep(n) => self.reverse_step(n, speed),
+            DebugRequest::ReverseContinue => self.reverse_continue(speed),
+            DebugRequest::StepOver => {
+                let pc = self.machine.pc();
+                match decode(self.machine.codeword(pc)) {
+                    Instruction::Call(_) => {
+                        let depth = self.machine.call_stack().len();
+                        self.machine.set_stop_at_depth(Some(depth));
+                        self.machine.resume();
+                        speed.resume();
+                        DebugReply::Ok
+                    }
+                    _ => self.debug_step(1, speed),
+                }
+            }
+            DebugRequest::StepOut => {
+                let depth = self.machine.call_stack().len();
+                if depth == 0 {
+                    DebugReply::Error("not in a subroutine".to_string())
+                } else {
+                    self.machine.set_stop_at_depth(Some(depth - 1));
+                    self.machine.resume();
+                    speed.resume();
+                    DebugReply::Ok
+                }
+            }
+            DebugRequest::Registers => DebugReply::Registers(self.machine.registers()),
+            // Debuggers take addresses from users and clients, so they're checked here rather
+            // than in each front end; the core panics on anything past memory.
+            DebugRequest::ReadMemory(addr, _) if addr as usize >= MEMORY_SIZE => {
+                DebugReply::Error(format!("{:#X} is outside memory", addr))
+            }
+            DebugRequest::ReadMemory(addr, len) => {
+                let len = len.min(MEMORY_SIZE - addr as usize);
+                DebugReply::Memory(addr, self.machine.read_memory(addr, len))
+            }
+            DebugRequest::WriteMemory(addr, bytes) => {
+                if addr as usize + bytes.len() > MEMORY_SIZE {
+                    DebugReply::Error(format!("{} bytes at {:#X} run past the end of memory",
+                                              bytes.len(),
+                                              addr))
+                } else {
+                    for (n, byte) in bytes.iter().enumerate() {
+                        self.machine.write_byte(addr + n as u16, *byte);
+                    }
+                    if let Some(ref mut history) = self.history {
+                        history.record_memory(self.machine.instructions(), addr, bytes);
+                    }
+                    DebugReply::Ok
+                }
+            }
+            DebugRequest::SetRegister(register, value) => {
+                self.machine.set_register_value(register, value);
+                if let Some(ref mut history) = self.history {
+                    history.record_register(self.machine.instructions(), register, value);
+                }
+                DebugReply::Ok
+            }
+            DebugRequest::AddBreakpoint(breakpoint) => {
+                self.machine.set_breakpoint(breakpoint);
+                DebugReply::Ok
+            }
+            DebugRequest::RemoveBreakpoint(addr) => {
+                if self.machine.remove_breakpoint(addr) {
+                    DebugReply::Ok
+                } else {
+                    DebugReply::Error(format!("no breakpoint at {:#05X}", addr))
+                }
+            }
+            DebugRequest::Breakpoints => DebugReply::Breakpoints(self.machine.breakpoint_details()),
+            DebugRequest::AddWatchpoint(watchpoint) => {
+                self.machine.add_watchpoint(watchpoint);
+                DebugReply::Ok
+            }
+            DebugRequest::RemoveWatchpoint(watchpoint) => {
+                if self.machine.remove_watchpoint(watchpoint) {
+                    DebugReply::Ok
+                } else {
+                    DebugReply::Error("no such watchpoint".to_string())
+                }
+            }
+            DebugRequest::Watchpoints => {
+                DebugReply::Watchpoints(self.machine.watchpoints().to_vec())
+            }
+            DebugRequest::Evaluate(expr) => {
+                match expr.eval(&mut self.machine) {
+                    Ok(value) => DebugReply::Value(value),
+                    Err(e) => DebugReply::Error(e),
+                }
+            }
+        };
+        reply.send(response).ok();
+    }
+
+    /// Emulation thread main loop. Paces frames according to the speed controls and keeps
+    /// going until the UI sends `Command::Quit`, hangs up, or the program halts. Returns the
+    /// process exit status.
+    pub fn run(&mut self,
+               commands: Receiver<Command>,
+               events: Sender<EmulatorEvent>,
+               mut frames: FrameWriter,
+               mut speed: SpeedControl)
+               -> i32 {
+        let park_duration = Duration::new(0, 5);
+        let mut status = None;
+        let mut last_frame = SystemTime::now();
+        let mut exit_code = 0;
+
+        self.publish(&mut frames);
         'running: loop {
-            match last_timer_tick.elapsed() {
-                Ok(elapsed) => {
-                    if elapsed > tick {
-                        last_timer_tick += tick;
-                        self.core.decrement_timers();
+            loop {
+                match commands.try_recv() {
+                    Ok(Command::SetKeys(keys)) => self.set_keyboard(&keys),
+                    Ok(Command::TogglePause) => speed.toggle_pause(),
+                    Ok(Command::AdvanceFrame) => speed.advance_frame(),
+                    Ok(Command::FastForward(on)) => speed.set_fast_forward(on),
+                    Ok(Command::SlowDown) => speed.slower(),
+                    Ok(Command::SpeedUp) => speed.faster(),
+                    Ok(Command::ShowDebugView(on)) => {
+                        self.debug_view = on;
+                        self.publish(&mut frames);
+                    }
+                    Ok(Command::Debug(request, reply)) => {
+                        self.debug(request, reply, &mut speed);
+                        self.publish(&mut frames);
                     }
+                    Ok(Command::Quit) |
+                    Err(TryRecvError::Disconnected) => break 'running,
+                    Err(TryRecvError::Empty) => break,
                 }
-                Err(_) => { panic!("no time elapsed")},
-            }
-
-
-            last_instruction_time = SystemTime::now();
-            let pc = self.core.pc();
-            let codeword = self.core.current_codeword();
-            self.core.advance_pc();
-
-            let instr = self.core.decode_instruction(codeword);
-/*            if self.config.print_instructions {
-                print!("0x{:04X}: {}   ", pc, instr.to_string());
-                self.core.dump_reg();
-            }*/
-            self.core.execute(&instr);
-
-            self.num_processed += 1;
-            if self.num_processed % 10000000 == 0 {
-                let millions = self.num_processed as f64 / 1000000.0;
-                let elapsed = self.start_time.elapsed().unwrap();
-                let secs = elapsed.as_secs() as f64;
-                let nanos = elapsed.subsec_nanos() as f64;
-                let total = secs + nanos / 1000000000.0;
-                if total > 0.0f64 {
-                    let per_sec = (self.num_processed as f64) / total;
-
-                    println!("{:.1}M in {:.1}s: {:.1} /sec = {:.1} /frame",
-                             millions,
-                             total,
-                             per_sec,
-                             per_sec / 60.0);
+            }
+
+            match speed.frame_period() {
+                Some(frame_period) => {
+                    match last_frame.elapsed() {
+                        Ok(elapsed) => {
+                            if elapsed >= frame_period {
+                                // Drop frames rather than racing to catch up after a stall
+                                // or when leaving fast-forward.
+                                if elapsed > frame_period * 4 {
+                                    last_frame = SystemTime::now();
+                                } else {
+                                    last_frame += frame_period;
+                                }
+                                let halted = self.run_frame_and_report(&mut speed, &events);
+                                self.publish(&mut frames);
+                                if let Some(code) = halted {
+                                    exit_code = code;
+                                    break 'running;
+                                }
+                            }
+                        }
+                        _ => (),
+                    }
                 }
-                self.num_processed = 0;
-                self.start_time = SystemTime::now();
-            }
-            match cycle_time {
-                Some(cycle_time) => {
-                    let elapsed = last_instruction_time.elapsed().unwrap();
-                    if elapsed < cycle_time {
-                        thread::sleep(cycle_time - elapsed);
+                None => {
+                    last_frame = SystemTime::now();
+                    if speed.take_advance() {
+                        let halted = self.run_frame_and_report(&mut speed, &events);
+                        self.publish(&mut frames);
+                        if let Some(code) = halted {
+                            exit_code = code;
+                            break 'running;
+                        }
                     }
                 }
-                None => {}
             }
 
-            //thread::park_timeout(Duration::new(0,50));
+            let label = speed.label();
+            if label != status {
+                status = label.clone();
+                events.send(EmulatorEvent::Status(label)).ok();
+            }
+            thread::park_timeout(park_duration);
         }
+
+        self.finish();
+        events.send(EmulatorEvent::Exited(exit_code)).ok();
+        exit_code
     }
 }
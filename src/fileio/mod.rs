@@ -51,7 +51,11 @@ impl Loader for HexLoader {
 }
 
 pub fn load_file(path: &str, loader_type: LoaderType) -> Vec<u8> {
-    let file = File::open(path).unwrap();
+    load_from(File::open(path).unwrap(), loader_type)
+}
+
+/// Loads a program from a file that's already open, for callers that handle open errors.
+pub fn load_from(file: File, loader_type: LoaderType) -> Vec<u8> {
     match loader_type {
         LoaderType::Auto => load_autodetect(file).get_bytes(),
         LoaderType::Hex => HexLoader::new(file).get_bytes(),
@@ -59,16 +63,16 @@ pub fn load_file(path: &str, loader_type: LoaderType) -> Vec<u8> {
     }
 }
 
-fn load_autodetect(mut file: File) -> Box<Loader> {
+/// Whether `data` looks like a hex listing rather than a binary ROM.
+pub fn is_hex_source(data: &[u8]) -> bool {
     let hex_chars: Vec<u8> = "0123456789abcdefABCDEFxX[];, \r\n\t".bytes().collect();
+    data.iter().all(|b| hex_chars.contains(b))
+}
+
+fn load_autodetect(mut file: File) -> Box<Loader> {
     let mut data = Vec::<u8>::new();
     file.read_to_end(&mut data).unwrap();
-    let mut binary_data = false;
-    for b in data {
-        if !hex_chars.contains(&b) {
-            binary_data = true;
-        }
-    }
+    let binary_data = !is_hex_source(&data);
     file.seek(SeekFrom::Start(0)).unwrap();
     if binary_data {
         BinaryLoader::new(file)
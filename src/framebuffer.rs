@@ -0,0 +1,89 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use chip8::Keyboard;
+
+use machine::Registers;
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+
+
+/// What the renderer needs to draw one emulated frame.
+#[derive(Clone)]
+pub struct Frame {
+    pub number: u64,
+    /// One byte per pixel, row by row, nonzero when lit.
+    pub vram: Vec<u8>,
+    pub keyboard: Keyboard,
+    /// Machine state for the debug panels, only filled in while they are shown.
+    pub debug: Option<DebugView>,
+}
+
+/// What the debug panels show alongside a frame.
+#[derive(Clone)]
+pub struct DebugView {
+    pub registers: Registers,
+    /// All of memory, from address 0.
+    pub memory: Vec<u8>,
+    pub breakpoints: Vec<u16>,
+    /// Address and height of the last sprite drawn; height 0 is a 16x16 sprite.
+    pub last_sprite: Option<(u16, u8)>,
+}
+
+impl DebugView {
+    pub fn codeword(&self, addr: u16) -> u16 {
+        let byte = |a: u16| self.memory.get(a as usize).cloned().unwrap_or(0) as u16;
+        byte(addr) << 8 | byte(addr.wrapping_add(1))
+    }
+}
+
+impl Frame {
+    pub fn new() -> Frame {
+        Frame {
+            number: 0,
+            vram: vec![0; WIDTH * HEIGHT],
+            keyboard: [false; 16],
+            debug: None,
+        }
+    }
+}
+
+
+/// Double buffered frame shared between the emulation and render threads. The emulator fills
+/// its private back buffer and swaps it in under a short lock; the renderer copies out the
+/// front buffer. Neither side holds the lock while doing real work.
+pub fn frame_buffer() -> (FrameWriter, FrameReader) {
+    let front = Arc::new(Mutex::new(Frame::new()));
+    (FrameWriter {
+        front: front.clone(),
+        back: Frame::new(),
+    },
+     FrameReader { front: front })
+}
+
+pub struct FrameWriter {
+    front: Arc<Mutex<Frame>>,
+    back: Frame,
+}
+
+impl FrameWriter {
+    pub fn back_mut(&mut self) -> &mut Frame {
+        &mut self.back
+    }
+
+    pub fn publish(&mut self) {
+        let mut front = self.front.lock().unwrap();
+        mem::swap(&mut *front, &mut self.back);
+    }
+}
+
+pub struct FrameReader {
+    front: Arc<Mutex<Frame>>,
+}
+
+impl FrameReader {
+    pub fn latest(&self) -> Frame {
+        self.front.lock().unwrap().clone()
+    }
+}
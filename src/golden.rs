@@ -0,0 +1,285 @@
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use chip8::config::COSMAC_VIP;
+
+use emulator::Emulator;
+use fileio::{load_from, LoaderType};
+use hash::fnv1a;
+use headless::{self, HeadlessOptions, KeyEvent, parse_key_script};
+use machine::Machine;
+use movie::MovieMode;
+use options::{parse_number, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use programs;
+use rng::{RngKind, new_rng};
+use screenshot;
+
+
+/// The expected screen at the end of a case.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expectation {
+    /// FNV-1a of the final vram, one byte per pixel.
+    Hash(u64),
+    /// A text image in the format written by `screenshot::to_text`.
+    Image(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub name: String,
+    pub rom: String,
+    pub frames: u64,
+    pub keys: Vec<KeyEvent>,
+    pub seed: u64,
+    pub rng: RngKind,
+    pub instructions_per_frame: usize,
+    pub expect: Expectation,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// Blessing was asked for, so the actual screen was saved as the expected one.
+    Recorded,
+}
+
+
+/// Reads a manifest. Each non-empty line is a case: a name followed by `key=value` fields.
+///
+///     name rom=<file|builtin:NAME> frames=N [keys=SCRIPT] [seed=N] [rng=KIND] [ipf=N]
+///          expect=<file.txt|hash:HEX>
+///
+/// Files are relative to the manifest. `keys` uses the headless key script syntax with
+/// commas between entries. `#` starts a comment.
+pub fn load_manifest(path: &Path) -> Result<Vec<Case>, String> {
+    let mut text = String::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("{}: {}", path.display(), e)));
+    let base = path.parent().unwrap_or(Path::new("."));
+
+    let mut cases = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let case = try!(parse_case(line, base).map_err(|e| format!("{}:{}: {}",
+                                                                 path.display(),
+                                                                 n + 1,
+                                                                 e)));
+        cases.push(case);
+    }
+    Ok(cases)
+}
+
+fn parse_case(line: &str, base: &Path) -> Result<Case, String> {
+    let mut fields = line.split_whitespace();
+    let mut case = Case {
+        name: fields.next().unwrap().to_string(),
+        rom: String::new(),
+        frames: 0,
+        keys: Vec::new(),
+        seed: 0,
+        rng: RngKind::XorShift,
+        instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+        expect: Expectation::Hash(0),
+    };
+    let mut have_expect = false;
+    for field in fields {
+        let mut parts = field.splitn(2, '=');
+        let key = parts.next().unwrap();
+        let value = match parts.next() {
+            Some(value) => value,
+            None => return Err(format!("expected key=value, got '{}'", field)),
+        };
+        match key {
+            "rom" => {
+                case.rom = if value.starts_with("builtin:") {
+                    value.to_string()
+                } else {
+                    base.join(value).to_string_lossy().into_owned()
+                }
+            }
+            "frames" => case.frames = try!(parse_number(value)) as u64,
+            "keys" => case.keys = try!(parse_key_script(value)),
+            "seed" => case.seed = try!(parse_number(value)) as u64,
+            "ipf" => case.instructions_per_frame = try!(parse_number(value)) as usize,
+            "rng" => {
+                case.rng = try!(RngKind::from_name(value)
+                    .ok_or(format!("unknown rng '{}'", value)))
+            }
+            "expect" => {
+                have_expect = true;
+                case.expect = if value.starts_with("hash:") {
+                    Expectation::Hash(try!(u64::from_str_radix(&value[5..], 16)
+                        .map_err(|_| format!("bad hash '{}'", value))))
+                } else {
+                    Expectation::Image(base.join(value))
+                }
+            }
+            _ => return Err(format!("unknown field '{}'", key)),
+        }
+    }
+    if case.rom.is_empty() || case.frames == 0 || !have_expect {
+        return Err(format!("case '{}' needs rom, frames and expect", case.name));
+    }
+    Ok(case)
+}
+
+
+fn load_rom(rom: &str) -> Result<Vec<u8>, String> {
+    if rom.starts_with("builtin:") {
+        let name = &rom[8..];
+        programs::examples()
+            .remove(name)
+            .ok_or(format!("no built-in program '{}'", name))
+    } else {
+        File::open(rom)
+            .map(|file| load_from(file, LoaderType::Auto))
+            .map_err(|e| format!("{}: {}", rom, e))
+    }
+}
+
+/// Runs a case headlessly and returns its final screen.
+pub fn run_case(case: &Case) -> Result<Vec<u8>, String> {
+    let program = try!(load_rom(&case.rom));
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(case.rng, case.seed));
+    machine.load_program(&program);
+    let mut emulator = Emulator::new(machine, MovieMode::Off, case.instructions_per_frame);
+    let options = HeadlessOptions {
+        frames: Some(case.frames),
+        until: None,
+        keys: case.keys.clone(),
+        screenshot: None,
+    };
+    try!(headless::run(&mut emulator, &options));
+    Ok(emulator.machine.vram().iter().cloned().collect())
+}
+
+/// Draws expected and actual screens side by side with a map of the differences next to
+/// them: `+` is lit only in the actual screen, `-` only in the expected one.
+pub fn visual_diff(expected: &str, actual: &str) -> String {
+    let mut out = String::new();
+    for (e, a) in expected.lines().zip(actual.lines()) {
+        let diff: String = e.chars()
+            .zip(a.chars())
+            .map(|(e, a)| {
+                match (e == '#', a == '#') {
+                    (true, false) => '-',
+                    (false, true) => '+',
+                    _ => ' ',
+                }
+            })
+            .collect();
+        out.push_str(&format!("{} | {} | {}\n", e, a, diff));
+    }
+    out
+}
+
+/// Runs a case and compares it against its expectation. With `bless`, the actual screen is
+/// written out as the expected image instead. A missing image is a failure otherwise, so a
+/// checkout without one can't pass by recording whatever it happens to draw.
+pub fn check_case(case: &Case, bless: bool) -> Outcome {
+    let vram = match run_case(case) {
+        Ok(vram) => vram,
+        Err(e) => return Outcome::Fail(e),
+    };
+    let actual = screenshot::to_text(&vram);
+
+    match case.expect {
+        Expectation::Hash(expected) => {
+            let hash = fnv1a(&vram);
+            if hash == expected {
+                Outcome::Pass
+            } else {
+                Outcome::Fail(format!("expected hash {:016x}, got {:016x}\n{}",
+                                      expected,
+                                      hash,
+                                      actual))
+            }
+        }
+        Expectation::Image(ref path) => {
+            if bless {
+                let written = File::create(path).and_then(|mut f| f.write_all(actual.as_bytes()));
+                return match written {
+                    Ok(_) => Outcome::Recorded,
+                    Err(e) => Outcome::Fail(format!("{}: {}", path.display(), e)),
+                };
+            }
+            let mut expected = String::new();
+            let read = File::open(path).and_then(|mut f| f.read_to_string(&mut expected));
+            if let Err(e) = read {
+                return Outcome::Fail(format!("{}: {}; run with VIPCHIP_BLESS=1 to record it\n{}",
+                                             path.display(),
+                                             e,
+                                             actual));
+            }
+            if expected == actual {
+                Outcome::Pass
+            } else {
+                Outcome::Fail(visual_diff(&expected, &actual))
+            }
+        }
+    }
+}
+
+/// Runs every case in a manifest, printing a line per case and the diff of each failure.
+/// Returns the number of failures. Setting `VIPCHIP_BLESS` rewrites all expected images.
+pub fn run_manifest(path: &Path) -> Result<usize, String> {
+    let bless = env::var_os("VIPCHIP_BLESS").is_some();
+    let cases = try!(load_manifest(path));
+    let mut failures = 0;
+    for case in &cases {
+        match check_case(case, bless) {
+            Outcome::Pass => println!("{} ... ok", case.name),
+            Outcome::Recorded => println!("{} ... recorded new expected screen", case.name),
+            Outcome::Fail(diff) => {
+                println!("{} ... FAILED\n{}", case.name, diff);
+                failures += 1;
+            }
+        }
+    }
+    println!("{} cases, {} failed", cases.len(), failures);
+    Ok(failures)
+}
+
+
+#[test]
+fn test_golden_suite() {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/manifest.txt");
+    assert_eq!(run_manifest(&manifest), Ok(0));
+}
+
+#[test]
+fn test_parse_case() {
+    let case = parse_case("a rom=x.hex frames=0x10 keys=1:+5,2:-5 expect=hash:ff",
+                          Path::new("dir"))
+        .unwrap();
+    assert_eq!(case.frames, 16);
+    assert_eq!(case.keys.len(), 2);
+    assert_eq!(case.expect, Expectation::Hash(0xff));
+    assert!(case.rom.ends_with("x.hex"));
+    assert!(parse_case("b rom=x.hex frames=1", Path::new(".")).is_err());
+}
+
+#[test]
+fn test_missing_files_fail() {
+    let case = parse_case("a rom=missing.hex frames=1 expect=hash:0", Path::new("no-such-dir"))
+        .unwrap();
+    match check_case(&case, false) {
+        Outcome::Fail(e) => assert!(e.contains("missing.hex")),
+        outcome => panic!("expected a failure, got {:?}", outcome),
+    }
+
+    let case = parse_case("b rom=builtin:monitor frames=1 expect=missing.txt",
+                          Path::new("no-such-dir"))
+        .unwrap();
+    match check_case(&case, false) {
+        Outcome::Fail(e) => assert!(e.contains("VIPCHIP_BLESS")),
+        outcome => panic!("expected a failure, got {:?}", outcome),
+    }
+}
@@ -0,0 +1,172 @@
+use std::fs::File;
+use std::io::Read;
+
+use emulator::Emulator;
+use machine::{Stop, Halt};
+use options::parse_number;
+use screenshot;
+
+
+/// A keypad change scheduled for the start of a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: usize,
+    pub pressed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Until {
+    Pc(u16),
+}
+
+pub struct HeadlessOptions {
+    pub frames: Option<u64>,
+    pub until: Option<Until>,
+    pub keys: Vec<KeyEvent>,
+    pub screenshot: Option<String>,
+}
+
+/// Exit status when a sanitizer finding, watchpoint or illegal opcode stops the run, so CI
+/// runs that hit one fail whatever the halt policy says.
+pub const STOPPED_EXIT_CODE: i32 = 3;
+
+pub struct RunResult {
+    pub frames: u64,
+    pub halt: Option<Halt>,
+    /// Process exit status: from the emulator's halt policy if the program halted,
+    /// `STOPPED_EXIT_CODE` if something else stopped it, else 0.
+    pub exit_code: i32,
+}
+
+
+/// Parses `pc=ADDR`.
+pub fn parse_until(text: &str) -> Result<Until, String> {
+    let mut parts = text.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some("pc"), Some(addr)) => parse_number(addr).map(|a| Until::Pc(a as u16)),
+        _ => Err(format!("unknown condition '{}'", text)),
+    }
+}
+
+/// Parses a key script: entries of the form `FRAME:+K` (press) or `FRAME:-K` (release),
+/// with K a hex key digit, separated by commas or whitespace. `#` starts a comment.
+pub fn parse_key_script(script: &str) -> Result<Vec<KeyEvent>, String> {
+    let mut events = Vec::new();
+    for line in script.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        for entry in line.split(|c: char| c == ',' || c.is_whitespace()) {
+            if entry.is_empty() {
+                continue;
+            }
+            let mut parts = entry.splitn(2, ':');
+            let frame = parts.next().and_then(|f| f.parse::<u64>().ok());
+            let action = parts.next().unwrap_or("");
+            let pressed = action.starts_with('+');
+            let key = if pressed || action.starts_with('-') {
+                usize::from_str_radix(&action[1..], 16).ok()
+            } else {
+                None
+            };
+            match (frame, key) {
+                (Some(frame), Some(key)) if key < 16 => {
+                    events.push(KeyEvent {
+                        frame: frame,
+                        key: key,
+                        pressed: pressed,
+                    })
+                }
+                _ => return Err(format!("bad key event '{}'", entry)),
+            }
+        }
+    }
+    events.sort_by(|a, b| a.frame.cmp(&b.frame));
+    Ok(events)
+}
+
+pub fn load_key_script(path: &str) -> Result<Vec<KeyEvent>, String> {
+    let mut script = String::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut script))
+        .map_err(|e| format!("{}: {}", path, e)));
+    parse_key_script(&script)
+}
+
+
+/// Runs the emulator without a window until the frame limit or the `until` condition is
+/// reached, or the program halts, then saves the screen if asked to. Once the key script has
+/// run out, waiting for a key counts as a halt.
+pub fn run(emulator: &mut Emulator, options: &HeadlessOptions) -> Result<RunResult, String> {
+    if options.frames.is_none() && options.until.is_none() {
+        return Err("headless mode needs --frames or --until".to_string());
+    }
+    if let Some(Until::Pc(addr)) = options.until {
+        emulator.machine.add_breakpoint(addr);
+    }
+    emulator.machine.set_detect_halts(true);
+
+    let mut keys = [false; 16];
+    let mut next_key = 0;
+    let mut halt = None;
+    let mut stopped = false;
+    loop {
+        if let Some(frames) = options.frames {
+            if emulator.frame() >= frames {
+                break;
+            }
+        }
+
+        while next_key < options.keys.len() && options.keys[next_key].frame <= emulator.frame() {
+            let event = options.keys[next_key];
+            keys[event.key] = event.pressed;
+            next_key += 1;
+        }
+        emulator.set_keyboard(&keys);
+        emulator.machine.set_halt_on_key_wait(next_key >= options.keys.len());
+
+        match emulator.run_frame() {
+            Some(Stop::Breakpoint(addr)) => {
+                println!("reached pc={:#05X} in frame {}", addr, emulator.frame());
+                break;
+            }
+            Some(Stop::Halted(h)) => {
+                println!("halted: {:?} in frame {}", h, emulator.frame());
+                halt = Some(h);
+                break;
+            }
+            Some(stop) => {
+                println!("stopped: {:?} in frame {}", stop, emulator.frame());
+                stopped = true;
+                break;
+            }
+            None => (),
+        }
+    }
+
+    emulator.finish();
+    if let Some(ref path) = options.screenshot {
+        let vram: Vec<u8> = emulator.machine.vram().iter().cloned().collect();
+        try!(screenshot::save(path, &vram).map_err(|e| format!("{}: {}", path, e)));
+    }
+    let exit_code = match halt {
+        Some(h) => emulator.halt_policy().exit_code(h, &mut emulator.machine),
+        None if stopped => STOPPED_EXIT_CODE,
+        None => 0,
+    };
+    Ok(RunResult {
+        frames: emulator.frame(),
+        halt: halt,
+        exit_code: exit_code,
+    })
+}
+
+
+#[test]
+fn test_parse_key_script() {
+    let events = parse_key_script("20:-5, 10:+5 # fire\n30:+a").unwrap();
+    assert_eq!(events,
+               vec![KeyEvent { frame: 10, key: 5, pressed: true },
+                    KeyEvent { frame: 20, key: 5, pressed: false },
+                    KeyEvent { frame: 30, key: 0xA, pressed: true }]);
+    assert!(parse_key_script("10:5").is_err());
+}
@@ -0,0 +1,814 @@
+use std::collections::HashMap;
+
+use chip8::{Config, SimulatorTask, Simulate, Vram, Keyboard};
+use chip8::instruction::{Src, Dest};
+
+use rng::Rng;
+use hash::Fnv1a;
+use disasm::{decode, Instruction};
+use trace::Tracer;
+use profile::Profiler;
+use coverage::Coverage;
+use sanitizer::{Sanitizer, Issue};
+use crash::{CrashReporter, OpcodePolicy};
+use debugger::Register;
+use debugger::expr::Expr;
+
+/// Bytes of memory a program can address.
+pub const MEMORY_SIZE: usize = 0x1000;
+
+/// Wraps the chip8 core and fetches each instruction before the core does, so vipchip can
+/// handle the ones it needs control over. CXNN is executed here with our own `Rng`, which
+/// makes runs repeatable for a given seed.
+pub struct Machine {
+    core: SimulatorTask,
+    rng: Box<Rng>,
+    instructions: u64,
+    last_opcode: u16,
+    breakpoints: HashMap<u16, Breakpoint>,
+    /// Set after a stop so that resuming executes the instruction that caused it.
+    resume_from: Option<u16>,
+    detect_halts: bool,
+    halt_on_key_wait: bool,
+    /// Return addresses of the subroutines in progress, tracked from CALL and RET since the
+    /// core doesn't expose its stack.
+    call_stack: Vec<u16>,
+    /// Stop once the call stack is no deeper than this, for stepping over and out of calls.
+    stop_at_depth: Option<usize>,
+    watchpoints: Vec<Watchpoint>,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    sanitizer: Option<Sanitizer>,
+    opcode_policy: OpcodePolicy,
+    crash_reporter: Option<CrashReporter>,
+    /// Address and height of the last sprite drawn, for the sprite viewer.
+    last_sprite: Option<(u16, u8)>,
+}
+
+/// Why `step` or `step_n` returned before running everything it was asked to. The
+/// instruction at the stop address has not run yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    Breakpoint(u16),
+    Halted(Halt),
+    /// A step over or out of a subroutine finished at this address.
+    Step(u16),
+    /// The instruction at `pc` is about to touch a watched address.
+    Watchpoint(WatchHit),
+    /// The sanitizer found a problem with the instruction at this address.
+    Sanitizer(u16, Issue),
+    /// The instruction at `pc` is an illegal opcode and the policy is to break.
+    IllegalOpcode(u16, u16),
+}
+
+/// How an instruction uses memory. `Pointer` is I being set to an address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Pointer,
+}
+
+/// The memory an instruction is about to use: `len` bytes from `addr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryAccess {
+    pub addr: u16,
+    pub len: u16,
+    pub access: Access,
+}
+
+/// Which accesses a watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Reads or writes.
+    Access,
+    /// I being set to point into the range.
+    Pointer,
+}
+
+/// Stops before the instruction at `addr` runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    /// Only hits when this isn't zero.
+    pub condition: Option<Expr>,
+    /// Only stops from this hit on.
+    pub hit_count: Option<u64>,
+    /// Times execution reached `addr` with the condition true.
+    pub hits: u64,
+}
+
+impl Breakpoint {
+    pub fn new(addr: u16) -> Breakpoint {
+        Breakpoint {
+            addr: addr,
+            condition: None,
+            hit_count: None,
+            hits: 0,
+        }
+    }
+
+    /// Counts a hit if the condition holds and says whether to stop. A condition that
+    /// can't be evaluated stops, so the mistake gets noticed.
+    fn hit(&mut self, machine: &mut Machine) -> bool {
+        let holds = match self.condition {
+            Some(ref condition) => condition.eval(machine).map(|value| value != 0).unwrap_or(true),
+            None => true,
+        };
+        if holds {
+            self.hits += 1;
+        }
+        holds && self.hits >= self.hit_count.unwrap_or(0)
+    }
+}
+
+/// Stops before an instruction accesses `start..=end`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub pc: u16,
+    pub codeword: u16,
+    pub access: MemoryAccess,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &MemoryAccess) -> bool {
+        let kind_matches = match (self.kind, access.access) {
+            (WatchKind::Read, Access::Read) |
+            (WatchKind::Write, Access::Write) |
+            (WatchKind::Access, Access::Read) |
+            (WatchKind::Access, Access::Write) |
+            (WatchKind::Pointer, Access::Pointer) => true,
+            _ => false,
+        };
+        let last = access.addr as u32 + access.len as u32 - 1;
+        kind_matches && access.addr <= self.end && last >= self.start as u32
+    }
+}
+
+/// Ways a program can be finished.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Halt {
+    /// A `1NNN` jump to its own address.
+    JumpToSelf(u16),
+    /// SCHIP `00FD`.
+    Exit(u16),
+    /// `FX0A` with no key held, when no more input is coming.
+    KeyWait(u16),
+    /// An illegal opcode, when the policy is to halt.
+    IllegalOpcode(u16),
+    /// A script stopped the run, with this exit status.
+    Script(u16, i32),
+}
+
+/// Code run before each instruction that the machine doesn't own, such as a script, which
+/// can't be sent to the emulation thread along with it.
+pub trait Hook {
+    fn before(&mut self, machine: &mut Machine, pc: u16, codeword: u16);
+    /// The exit status, once the hook wants the run to end.
+    fn stopped(&self) -> Option<i32>;
+}
+
+/// A snapshot of the CPU state, for debuggers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    /// Return addresses, innermost last.
+    pub stack: Vec<u16>,
+}
+
+impl Machine {
+    pub fn new(config: Config, rng: Box<Rng>) -> Machine {
+        Machine {
+            core: SimulatorTask::spawn(config),
+            rng: rng,
+            instructions: 0,
+            last_opcode: 0,
+            breakpoints: HashMap::new(),
+            resume_from: None,
+            detect_halts: false,
+            halt_on_key_wait: false,
+            call_stack: Vec::new(),
+            stop_at_depth: None,
+            watchpoints: Vec::new(),
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            sanitizer: None,
+            opcode_policy: OpcodePolicy::Platform,
+            crash_reporter: None,
+            last_sprite: None,
+        }
+    }
+
+    /// Enables stopping at jump-to-self loops. `00FD` always stops the machine.
+    pub fn set_detect_halts(&mut self, detect: bool) {
+        self.detect_halts = detect;
+    }
+
+    /// Treats waiting for a key as a halt, for runs where nobody is going to press one.
+    pub fn set_halt_on_key_wait(&mut self, halt: bool) {
+        self.halt_on_key_wait = halt;
+    }
+
+    /// Total number of instructions executed so far.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// The codeword of the most recently executed instruction.
+    pub fn last_opcode(&self) -> u16 {
+        self.last_opcode
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.set_breakpoint(Breakpoint::new(addr));
+    }
+
+    /// Adds a breakpoint, replacing any other at its address.
+    pub fn set_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.insert(breakpoint.addr, breakpoint);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    pub fn breakpoints(&self) -> Vec<u16> {
+        let mut addrs: Vec<u16> = self.breakpoints.keys().cloned().collect();
+        addrs.sort();
+        addrs
+    }
+
+    /// The breakpoints with their conditions and hit counts, in address order.
+    pub fn breakpoint_details(&self) -> Vec<Breakpoint> {
+        let mut breakpoints: Vec<Breakpoint> = self.breakpoints.values().cloned().collect();
+        breakpoints.sort_by_key(|b| b.addr);
+        breakpoints
+    }
+
+    /// Lets the next step run the instruction at PC even if it would stop there, the way
+    /// resuming after a stop does.
+    pub fn resume(&mut self) {
+        let pc = self.pc();
+        self.resume_from = Some(pc);
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watchpoint);
+        self.watchpoints.len() != before
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn flush_trace(&mut self) {
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.flush();
+        }
+    }
+
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    /// Tells the profiler a frame has ended.
+    pub fn end_profile_frame(&mut self) {
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.end_frame();
+        }
+    }
+
+    pub fn write_profile(&mut self) {
+        if let Some(ref profiler) = self.profiler {
+            profiler.write();
+        }
+    }
+
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    pub fn write_coverage(&mut self) {
+        if self.coverage.is_some() {
+            let memory = self.read_memory(0, MEMORY_SIZE);
+            if let Some(ref coverage) = self.coverage {
+                coverage.write(&memory);
+            }
+        }
+    }
+
+    pub fn set_sanitizer(&mut self, sanitizer: Option<Sanitizer>) {
+        self.sanitizer = sanitizer;
+    }
+
+    pub fn set_opcode_policy(&mut self, policy: OpcodePolicy) {
+        self.opcode_policy = policy;
+    }
+
+    /// Sets where to report illegal opcodes when the policy is to halt or break.
+    pub fn set_crash_reporter(&mut self, reporter: Option<CrashReporter>) {
+        self.crash_reporter = reporter;
+    }
+
+    /// Address and height of the sprite the last DXYN drew. Height 0 is a 16x16 sprite.
+    pub fn last_sprite(&self) -> Option<(u16, u8)> {
+        self.last_sprite
+    }
+
+    pub fn call_stack(&self) -> &[u16] {
+        &self.call_stack
+    }
+
+    /// Stops with `Stop::Step` as soon as no more than `depth` calls are in progress.
+    pub fn set_stop_at_depth(&mut self, depth: Option<usize>) {
+        self.stop_at_depth = depth;
+    }
+
+    /// Takes over the emulated state of `other`, a machine replayed from reset, keeping
+    /// this one's breakpoints, watchpoints, settings and hooks. Breakpoint hit counts are
+    /// taken from `other` too, which should have been replayed with the same breakpoints.
+    /// Resuming runs the instruction at the new PC even if it would stop there, so getting
+    /// there counts as a hit, the same as it did on the way forward.
+    pub fn restore(&mut self, other: Machine) {
+        let Machine { core, rng, instructions, last_opcode, call_stack, last_sprite, breakpoints,
+                      .. } = other;
+        self.core = core;
+        self.rng = rng;
+        self.instructions = instructions;
+        self.last_opcode = last_opcode;
+        self.call_stack = call_stack;
+        self.last_sprite = last_sprite;
+        self.stop_at_depth = None;
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.memory_changed();
+        }
+        for (addr, breakpoint) in self.breakpoints.iter_mut() {
+            breakpoint.hits = breakpoints.get(addr).map(|b| b.hits).unwrap_or(0);
+        }
+        let pc = self.pc();
+        self.check_breakpoint(pc);
+        self.resume();
+    }
+
+    pub fn load_program(&mut self, program: &[u8]) {
+        self.core.load_program(program);
+    }
+
+    pub fn core(&self) -> &SimulatorTask {
+        &self.core
+    }
+
+    pub fn core_mut(&mut self) -> &mut SimulatorTask {
+        &mut self.core
+    }
+
+    pub fn pc(&mut self) -> u16 {
+        self.core.load(Src::PC).unwrap() as u16
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.core.store(Dest::PC, pc as usize).unwrap();
+    }
+
+    pub fn register(&mut self, x: usize) -> u8 {
+        self.core.load(Src::Register(x)).unwrap() as u8
+    }
+
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.core.store(Dest::Register(x), value as usize).unwrap();
+    }
+
+    pub fn i(&mut self) -> u16 {
+        self.core.load(Src::I).unwrap() as u16
+    }
+
+    pub fn set_i(&mut self, i: u16) {
+        self.core.store(Dest::I, i as usize).unwrap();
+    }
+
+    pub fn delay_timer(&mut self) -> u8 {
+        self.core.load(Src::DelayTimer).unwrap() as u8
+    }
+
+    pub fn sound_timer(&mut self) -> u8 {
+        self.core.load(Src::SoundTimer).unwrap() as u8
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.core.store(Dest::DelayTimer, value as usize).unwrap();
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.core.store(Dest::SoundTimer, value as usize).unwrap();
+    }
+
+    /// Sets a register the way a debugger names it.
+    pub fn set_register_value(&mut self, register: Register, value: u16) {
+        match register {
+            Register::V(x) => self.set_register(x, value as u8),
+            Register::I => self.set_i(value),
+            Register::Pc => self.set_pc(value),
+            Register::DelayTimer => self.set_delay_timer(value as u8),
+            Register::SoundTimer => self.set_sound_timer(value as u8),
+        }
+    }
+
+    pub fn registers(&mut self) -> Registers {
+        let mut v = [0; 16];
+        for x in 0..16 {
+            v[x] = self.register(x);
+        }
+        Registers {
+            v: v,
+            i: self.i(),
+            pc: self.pc(),
+            delay_timer: self.delay_timer(),
+            sound_timer: self.sound_timer(),
+            stack: self.call_stack.clone(),
+        }
+    }
+
+    pub fn vram(&self) -> Vram {
+        self.core.vram().unwrap()
+    }
+
+    pub fn keyboard(&self) -> Keyboard {
+        self.core.keyboard().unwrap()
+    }
+
+    pub fn set_keyboard(&mut self, keys: &Keyboard) {
+        self.core.set_keyboard(keys);
+    }
+
+    pub fn read_byte(&mut self, addr: u16) -> u8 {
+        self.core.load(Src::Address12(addr as usize)).unwrap() as u8
+    }
+
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        self.core.store(Dest::Address12(addr as usize), value as usize).unwrap();
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.memory_changed();
+        }
+    }
+
+    pub fn read_memory(&mut self, addr: u16, len: usize) -> Vec<u8> {
+        (0..len).map(|n| self.read_byte(addr.wrapping_add(n as u16))).collect()
+    }
+
+    pub fn codeword(&mut self, addr: u16) -> u16 {
+        (self.read_byte(addr) as u16) << 8 | self.read_byte(addr.wrapping_add(1)) as u16
+    }
+
+    /// The memory `instruction` will read or write when it runs now, or the address it will
+    /// point I at. Sprites with height 0 are SCHIP 16x16 ones.
+    pub fn memory_access(&mut self, instruction: Instruction) -> Option<MemoryAccess> {
+        let (addr, len, access) = match instruction {
+            Instruction::Draw(_, _, 0) => (self.i(), 32, Access::Read),
+            Instruction::Draw(_, _, n) => (self.i(), n as u16, Access::Read),
+            Instruction::Bcd(_) => (self.i(), 3, Access::Write),
+            Instruction::Store(x) => (self.i(), x as u16 + 1, Access::Write),
+            Instruction::Load(x) => (self.i(), x as u16 + 1, Access::Read),
+            Instruction::LoadI(nnn) => (nnn, 1, Access::Pointer),
+            Instruction::AddI(x) => {
+                let i = self.i();
+                let v = self.register(x);
+                (i.wrapping_add(v as u16), 1, Access::Pointer)
+            }
+            _ => return None,
+        };
+        Some(MemoryAccess {
+            addr: addr,
+            len: len,
+            access: access,
+        })
+    }
+
+    fn check_watchpoints(&mut self, pc: u16, codeword: u16) -> Option<WatchHit> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+        let access = match self.memory_access(decode(codeword)) {
+            Some(access) => access,
+            None => return None,
+        };
+        self.watchpoints.iter().find(|w| w.matches(&access)).map(|w| {
+            WatchHit {
+                watchpoint: *w,
+                pc: pc,
+                codeword: codeword,
+                access: access,
+            }
+        })
+    }
+
+    /// Only instructions at a breakpoint's address get here, so conditions cost nothing
+    /// anywhere else.
+    fn check_breakpoint(&mut self, pc: u16) -> bool {
+        match self.breakpoints.remove(&pc) {
+            Some(mut breakpoint) => {
+                let stop = breakpoint.hit(self);
+                self.breakpoints.insert(pc, breakpoint);
+                stop
+            }
+            None => false,
+        }
+    }
+
+    fn check_sanitizer(&mut self, pc: u16, codeword: u16) -> Option<Issue> {
+        let sanitizer = match self.sanitizer.take() {
+            Some(sanitizer) => sanitizer,
+            None => return None,
+        };
+        let issue = sanitizer.break_on(self, pc, codeword);
+        self.sanitizer = Some(sanitizer);
+        issue
+    }
+
+    fn write_crash_report(&mut self, pc: u16, codeword: u16) {
+        if let Some(reporter) = self.crash_reporter.take() {
+            reporter.write(self, pc, codeword);
+            self.crash_reporter = Some(reporter);
+        }
+    }
+
+    fn check_halt(&mut self, pc: u16, codeword: u16) -> Option<Halt> {
+        match decode(codeword) {
+            Instruction::Jump(addr) if self.detect_halts && addr == pc => {
+                Some(Halt::JumpToSelf(pc))
+            }
+            Instruction::WaitKey(_) if self.halt_on_key_wait => {
+                if self.keyboard().iter().any(|k| *k) {
+                    None
+                } else {
+                    Some(Halt::KeyWait(pc))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn step(&mut self) -> Option<Stop> {
+        self.step_with(None)
+    }
+
+    /// Runs an instruction, calling `hook` before it runs alongside the machine's own.
+    pub fn step_with(&mut self, hook: Option<&mut Hook>) -> Option<Stop> {
+        let stop = self.run_instruction(hook);
+        // A step over or out that something else interrupted is abandoned, so a later
+        // continue doesn't stop when the call returns.
+        if stop.is_some() {
+            self.stop_at_depth = None;
+        }
+        stop
+    }
+
+    fn run_instruction(&mut self, hook: Option<&mut Hook>) -> Option<Stop> {
+        let pc = self.pc();
+        let codeword = self.codeword(pc);
+        let instruction = decode(codeword);
+        // The core doesn't know 00FD, so it never gets to run it.
+        if let Instruction::Exit = instruction {
+            return Some(Stop::Halted(Halt::Exit(pc)));
+        }
+        if let Some(code) = hook.as_ref().and_then(|hook| hook.stopped()) {
+            return Some(Stop::Halted(Halt::Script(pc, code)));
+        }
+        let illegal = instruction.is_illegal();
+        if illegal && self.opcode_policy == OpcodePolicy::Halt {
+            // The report is written when the machine gets here; it stays halted after that.
+            if self.resume_from.take() != Some(pc) {
+                self.write_crash_report(pc, codeword);
+            }
+            self.resume_from = Some(pc);
+            return Some(Stop::Halted(Halt::IllegalOpcode(pc)));
+        }
+        if self.resume_from.take() != Some(pc) {
+            let stop = if self.breakpoints.contains_key(&pc) && self.check_breakpoint(pc) {
+                Some(Stop::Breakpoint(pc))
+            } else if let Some(hit) = self.check_watchpoints(pc, codeword) {
+                Some(Stop::Watchpoint(hit))
+            } else if let Some(issue) = self.check_sanitizer(pc, codeword) {
+                Some(Stop::Sanitizer(pc, issue))
+            } else if illegal && self.opcode_policy == OpcodePolicy::Break {
+                self.write_crash_report(pc, codeword);
+                Some(Stop::IllegalOpcode(pc, codeword))
+            } else {
+                self.check_halt(pc, codeword).map(Stop::Halted)
+            };
+            if stop.is_some() {
+                self.resume_from = Some(pc);
+                return stop;
+            }
+        }
+
+        // These read registers through the machine, so they can't stay borrowed.
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.record(self, pc, codeword);
+            self.tracer = Some(tracer);
+        }
+        if let Some(mut profiler) = self.profiler.take() {
+            profiler.record(self, pc, codeword);
+            self.profiler = Some(profiler);
+        }
+        if let Some(mut coverage) = self.coverage.take() {
+            coverage.record(self, pc, codeword);
+            self.coverage = Some(coverage);
+        }
+        if let Some(mut sanitizer) = self.sanitizer.take() {
+            sanitizer.record(self, pc, codeword);
+            self.sanitizer = Some(sanitizer);
+        }
+        if let Some(mut reporter) = self.crash_reporter.take() {
+            reporter.record(self, pc, codeword);
+            self.crash_reporter = Some(reporter);
+        }
+        if let Some(hook) = hook {
+            hook.before(self, pc, codeword);
+        }
+
+        self.rng.tick();
+        if codeword & 0xF000 == 0xC000 {
+            let x = ((codeword >> 8) & 0xF) as usize;
+            let value = self.rng.next_byte() & codeword as u8;
+            self.set_register(x, value);
+            self.set_pc(pc.wrapping_add(2));
+        } else if illegal && self.opcode_policy == OpcodePolicy::Ignore {
+            self.set_pc(pc.wrapping_add(2));
+        } else {
+            self.core.step().unwrap();
+        }
+        self.instructions += 1;
+        self.last_opcode = codeword;
+
+        match instruction {
+            Instruction::Call(_) => self.call_stack.push(pc.wrapping_add(2)),
+            Instruction::Ret => {
+                self.call_stack.pop();
+            }
+            Instruction::Draw(_, _, n) => self.last_sprite = Some((self.i(), n)),
+            _ => (),
+        }
+        if let Some(depth) = self.stop_at_depth {
+            if self.call_stack.len() <= depth {
+                self.stop_at_depth = None;
+                let pc = self.pc();
+                self.resume_from = Some(pc);
+                return Some(Stop::Step(pc));
+            }
+        }
+        None
+    }
+
+    pub fn step_n(&mut self, n: usize) -> Option<Stop> {
+        self.step_n_with(n, None)
+    }
+
+    pub fn step_n_with(&mut self, n: usize, mut hook: Option<&mut Hook>) -> Option<Stop> {
+        for _ in 0..n {
+            let hook = match hook {
+                Some(ref mut hook) => Some(&mut **hook),
+                None => None,
+            };
+            if let Some(stop) = self.step_with(hook) {
+                return Some(stop);
+            }
+        }
+        None
+    }
+
+    pub fn timer_tick(&mut self) {
+        self.core.timer_tick().unwrap();
+    }
+
+    /// Fingerprint of the screen and CPU registers, used to spot desyncs between runs.
+    pub fn state_checksum(&mut self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        let vram = self.vram();
+        for dot in vram.iter() {
+            hasher.write(&[*dot]);
+        }
+        for x in 0..16 {
+            let v = self.register(x);
+            hasher.write(&[v]);
+        }
+        let pc = self.pc();
+        let i = self.i();
+        hasher.write(&[(pc >> 8) as u8, pc as u8, (i >> 8) as u8, i as u8]);
+        let timers = [self.delay_timer(), self.sound_timer()];
+        hasher.write(&timers);
+        hasher.finish()
+    }
+}
+
+
+#[test]
+fn test_watchpoint_matches() {
+    let watchpoint = Watchpoint {
+        start: 0x300,
+        end: 0x30F,
+        kind: WatchKind::Write,
+    };
+    let access = |addr, len, access| {
+        MemoryAccess {
+            addr: addr,
+            len: len,
+            access: access,
+        }
+    };
+    assert!(watchpoint.matches(&access(0x2FE, 3, Access::Write)));
+    assert!(watchpoint.matches(&access(0x30F, 1, Access::Write)));
+    assert!(!watchpoint.matches(&access(0x310, 1, Access::Write)));
+    assert!(!watchpoint.matches(&access(0x300, 1, Access::Read)));
+}
+
+#[test]
+fn test_conditional_breakpoint() {
+    use chip8::config::COSMAC_VIP;
+    use rng::{new_rng, RngKind};
+    use symbols::Symbols;
+
+    // V0 = 0, then count it up forever.
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(RngKind::XorShift, 0));
+    machine.load_program(&[0x60, 0x00, 0x70, 0x01, 0x12, 0x02]);
+    let mut breakpoint = Breakpoint::new(0x202);
+    breakpoint.condition = Some(Expr::parse("v0 >= 3", &Symbols::new()).unwrap());
+    breakpoint.hit_count = Some(2);
+    machine.set_breakpoint(breakpoint);
+
+    assert_eq!(machine.step_n(100), Some(Stop::Breakpoint(0x202)));
+    assert_eq!(machine.register(0), 4);
+    assert_eq!(machine.breakpoint_details()[0].hits, 2);
+}
+
+#[test]
+fn test_illegal_opcode_policy() {
+    use chip8::config::COSMAC_VIP;
+    use rng::{new_rng, RngKind};
+
+    // A machine code call, then V0 = 1.
+    let program = [0x01, 0x23, 0x60, 0x01];
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(RngKind::XorShift, 0));
+    machine.load_program(&program);
+    machine.set_opcode_policy(OpcodePolicy::Ignore);
+    assert_eq!(machine.step_n(2), None);
+    assert_eq!(machine.register(0), 1);
+
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(RngKind::XorShift, 0));
+    machine.load_program(&program);
+    machine.set_opcode_policy(OpcodePolicy::Halt);
+    assert_eq!(machine.step(), Some(Stop::Halted(Halt::IllegalOpcode(0x200))));
+    machine.resume();
+    assert_eq!(machine.step(), Some(Stop::Halted(Halt::IllegalOpcode(0x200))));
+
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(RngKind::XorShift, 0));
+    machine.load_program(&program);
+    machine.set_opcode_policy(OpcodePolicy::Break);
+    assert_eq!(machine.step(), Some(Stop::IllegalOpcode(0x200, 0x0123)));
+}
+
+#[test]
+fn test_interrupted_step_over() {
+    use chip8::config::COSMAC_VIP;
+    use rng::{new_rng, RngKind};
+
+    // CALL 0x206, then V0 = 1 and spin; the subroutine sets V1 = 2 and returns.
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(RngKind::XorShift, 0));
+    machine.load_program(&[0x22, 0x06, 0x60, 0x01, 0x12, 0x04, 0x61, 0x02, 0x00, 0xEE]);
+    machine.add_breakpoint(0x206);
+
+    // Step over the call, which the breakpoint in its body interrupts.
+    machine.set_stop_at_depth(Some(0));
+    assert_eq!(machine.step_n(100), Some(Stop::Breakpoint(0x206)));
+
+    // Continuing runs past the return instead of finishing the step over.
+    machine.resume();
+    assert_eq!(machine.step_n(100), None);
+    assert_eq!(machine.register(0), 1);
+}
@@ -6,63 +6,269 @@ extern crate strfmt;
 extern crate clap;
 #[macro_use]
 extern crate nom;
+extern crate rustc_serialize;
 extern crate chip8;
+#[cfg(feature = "scripting")]
+extern crate hlua;
 
 use std::thread;
-use std::sync::mpsc;
+use std::process;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
 
 mod ui;
-//mod emulator;
+mod emulator;
 mod fileio;
 mod programs;
 mod options;
+mod rng;
+mod machine;
+mod hash;
+mod movie;
+mod speed;
+mod framebuffer;
+mod screenshot;
+mod headless;
+mod golden;
+mod bench;
+mod disasm;
+mod debugger;
+mod trace;
+mod tracediff;
+mod profile;
+mod coverage;
+mod symbols;
+mod sanitizer;
+mod crash;
+mod script;
+mod replay;
 
 use ui::Ui;
-//use emulator::Emulator;
-use options::parse_commandline;
+use emulator::Emulator;
+use options::{parse_commandline, Mode};
 use fileio::{load_file, LoaderType};
+use machine::Machine;
+use rng::new_rng;
+use hash::fnv1a;
+use movie::{MovieHeader, MovieMode, MovieRecorder, MoviePlayer};
+use framebuffer::frame_buffer;
+use speed::SpeedControl;
+use debugger::lines::Source;
+use trace::Tracer;
+use profile::Profiler;
+use coverage::Coverage;
+use symbols::Symbols;
+use sanitizer::Sanitizer;
+use crash::CrashReporter;
+use script::Script;
+use replay::ReplayStart;
 
-use chip8::{Config, SimulatorTask, Simulate};
 use chip8::config::COSMAC_VIP;
 
 
+/// Unwraps something set up from the command line, or prints why it failed and exits with
+/// status 2, like a bad option value.
+fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            println!("error: {}", e);
+            process::exit(2);
+        }
+    }
+}
+
 fn main() {
 
     let options = parse_commandline();
 
+    if let Mode::Test(ref manifest) = options.mode {
+        match golden::run_manifest(Path::new(manifest)) {
+            Ok(0) => process::exit(0),
+            Ok(_) => process::exit(1),
+            Err(e) => {
+                println!("error: {}", e);
+                process::exit(2);
+            }
+        }
+    }
+    if let Mode::Bench(ref path, ref bench_options) = options.mode {
+        let program = match *path {
+            Some(ref path) => load_file(path, LoaderType::Auto),
+            None => programs::examples().remove("bench").unwrap(),
+        };
+        let report = bench::run(&program, bench_options);
+        if bench_options.json {
+            println!("{}", report.to_json());
+        } else {
+            print!("{}", report.to_text());
+        }
+        return;
+    }
+
+    let symbols = match Symbols::load_all(&options.symbols) {
+        Ok(symbols) => Arc::new(symbols),
+        Err(e) => {
+            println!("error: {}", e);
+            process::exit(2);
+        }
+    };
+
+    if let Mode::TraceDiff(ref a, ref b, context) = options.mode {
+        let compared = tracediff::open(a).and_then(|mut a| {
+            tracediff::open(b)
+                .and_then(|mut b| tracediff::compare(&mut a, &mut b, context, &symbols))
+        });
+        match compared {
+            Ok(tracediff::Comparison { divergence: Some(report), .. }) => {
+                print!("{}", report);
+                process::exit(1);
+            }
+            Ok(comparison) => {
+                println!("traces match ({} records)", comparison.matched);
+                process::exit(0);
+            }
+            Err(e) => {
+                println!("error: {}", e);
+                process::exit(2);
+            }
+        }
+    }
+
     let test_program = load_file(&options.filename, LoaderType::Auto);
 
     let (tx_ui, rx_ui) = mpsc::channel();
-//    let (tx_emulator, rx_emulator) = mpsc::channel();
+    let (tx_emulator, rx_emulator) = mpsc::channel();
 
+    let mut header = MovieHeader {
+        rom_hash: fnv1a(&test_program),
+        seed: options.seed,
+        rng: options.rng,
+        instructions_per_frame: options.instructions_per_frame,
+    };
+    let mut movie = MovieMode::Off;
+    if let Some(ref path) = options.play {
+        let player = or_exit(MoviePlayer::open(path));
+        if player.header.rom_hash != header.rom_hash {
+            println!("warning: movie was recorded with a different ROM");
+        }
+        header = player.header;
+        movie = MovieMode::Playing(player);
+    }
+    if let Some(ref path) = options.record {
+        movie = MovieMode::Recording(or_exit(MovieRecorder::create(path, &header)));
+    }
 
-/*    let state = UiState::new();
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(header.rng, header.seed));
+    machine.load_program(&test_program);
+    if let Some(trace_options) = options.trace {
+        machine.set_tracer(Some(or_exit(Tracer::create(trace_options, symbols.clone()))));
+    }
+    if let Some(profile_options) = options.profile {
+        machine.set_profiler(Some(Profiler::new(profile_options, symbols.clone())));
+    }
+    if let Some(coverage_options) = options.coverage {
+        let source = Source::load(&options.filename);
+        machine.set_coverage(Some(Coverage::new(coverage_options,
+                                                test_program.len(),
+                                                source,
+                                                symbols.clone())));
+    }
+    machine.set_opcode_policy(options.opcode_policy);
+    if let Some(ref path) = options.crash_report {
+        machine.set_crash_reporter(Some(CrashReporter::new(path, symbols.clone())));
+    }
+    if let Some(policy) = options.sanitize {
+        machine.set_sanitizer(Some(Sanitizer::new(policy, test_program.len(), symbols.clone())));
+    }
 
-    let ui_state = state.clone();
-    let emulator_state = state.clone();*/
+    if let Some(ref headless_options) = options.headless {
+        let mut emulator = Emulator::new(machine, movie, header.instructions_per_frame);
+        emulator.set_halt_policy(options.halt_policy);
+        if let Some(ref path) = options.script {
+            emulator.set_script(Some(or_exit(Script::load(path, symbols.clone()))));
+        }
+        match headless::run(&mut emulator, headless_options) {
+            Ok(result) => {
+                println!("ran {} frames", result.frames);
+                process::exit(result.exit_code);
+            }
+            Err(e) => {
+                println!("error: {}", e);
+                process::exit(1);
+            }
+        }
+    }
 
-    let mut simulator_task = SimulatorTask::spawn(COSMAC_VIP);
-    simulator_task.load_program(&test_program);
+    let (command_tx, command_rx) = mpsc::channel();
+    let (event_tx, event_rx) = mpsc::channel();
+    let (frame_writer, frame_reader) = frame_buffer();
+    let halt_policy = options.halt_policy;
+    let mut speed = SpeedControl::new(options.ff_multiplier);
+    let mut replay_start = None;
+    if options.debug || options.gdb_port.is_some() || options.dap_port.is_some() {
+        // Start paused so breakpoints can be set before anything runs.
+        speed.pause();
+        replay_start = Some(ReplayStart {
+            rng: header.rng,
+            seed: header.seed,
+            program: test_program.clone(),
+            opcode_policy: options.opcode_policy,
+        });
+    }
+    if options.debug {
+        debugger::console::spawn(command_tx.clone(), symbols.clone());
+    }
+    if let Some(port) = options.gdb_port {
+        if let Err(e) = debugger::gdb::spawn(port, command_tx.clone()) {
+            println!("error: can't listen on port {}: {}", port, e);
+            process::exit(1);
+        }
+    }
+    if let Some(port) = options.dap_port {
+        let source = Source::load(&options.filename);
+        if let Err(e) = debugger::dap::spawn(port, command_tx.clone(), source, symbols.clone()) {
+            println!("error: can't listen on port {}: {}", port, e);
+            process::exit(1);
+        }
+    }
+
+    // Scripts can't move between threads, so this one is loaded on the emulation thread. A
+    // script that fails to load ends the whole process from there.
+    let script = options.script.clone();
+    let script_symbols = symbols.clone();
+    let emulator_thread = thread::spawn(move || {
+        let mut emulator = Emulator::new(machine, movie, header.instructions_per_frame);
+        emulator.set_halt_policy(halt_policy);
+        if let Some(ref path) = script {
+            emulator.set_script(Some(or_exit(Script::load(path, script_symbols))));
+        }
+        if let Some(start) = replay_start {
+            emulator.enable_reverse(start);
+        }
+        let code = emulator.run(command_rx, event_tx, frame_writer, speed);
+        tx_emulator.send(code).unwrap();
+    });
 
     let ui_thread = thread::spawn(move || {
-        let mut ui = Ui::new(simulator_task);
-        ui.run();
-        tx_ui.send(0).unwrap();
+        let mut ui = Ui::new(command_tx, event_rx, frame_reader, symbols);
+        let code = ui.run();
+        tx_ui.send(code).unwrap();
     });
 
-    /*let emulator_thread = thread::spawn(move || {
-        let mut emulator = Emulator::new(config, emulator_state);
-        let load_offset = 0x200;
-        emulator.core.load_bytes(&test_program, load_offset);
-        emulator.core.jump_pc(load_offset);
-        emulator.run();
-        tx_emulator.send(()).unwrap();
-    });*/
-
-    select! {
-//        _ = rx_emulator.recv() => println!("emulator"),
-        v = rx_ui.recv() => println!("ui: {}", v.unwrap())
+    let code = select! {
+        v = rx_emulator.recv() => {
+            let code = v.unwrap();
+            println!("emulator: {}", code);
+            code
+        },
+        v = rx_ui.recv() => {
+            let code = v.unwrap();
+            println!("ui: {}", code);
+            code
         }
+    };
+    process::exit(code);
 
     // thread::sleep(std::time::Duration::new(5, 0));
 
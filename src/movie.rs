@@ -0,0 +1,210 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use chip8::Keyboard;
+
+use rng::RngKind;
+
+const MOVIE_MAGIC: &'static str = "vipchip-movie 1";
+
+/// How often (in frames) a state checksum is written to a recording.
+pub const CHECKSUM_INTERVAL: u64 = 60;
+
+
+/// Everything needed to reproduce a run besides the input itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovieHeader {
+    pub rom_hash: u64,
+    pub seed: u64,
+    pub rng: RngKind,
+    pub instructions_per_frame: usize,
+}
+
+pub enum MovieMode {
+    Off,
+    Recording(MovieRecorder),
+    Playing(MoviePlayer),
+}
+
+
+pub fn keys_to_bits(keys: &Keyboard) -> u16 {
+    let mut bits = 0;
+    for (k, pressed) in keys.iter().enumerate() {
+        if *pressed {
+            bits |= 1 << k;
+        }
+    }
+    bits
+}
+
+pub fn bits_to_keys(bits: u16) -> Keyboard {
+    let mut keys = [false; 16];
+    for k in 0..16 {
+        keys[k] = bits & (1 << k) != 0;
+    }
+    keys
+}
+
+
+/// Writes one line per frame: the keypad state as a hex bitmask, followed by a state
+/// checksum every `CHECKSUM_INTERVAL` frames.
+pub struct MovieRecorder {
+    out: BufWriter<File>,
+    frame: u64,
+}
+
+impl MovieRecorder {
+    pub fn create(path: &str, header: &MovieHeader) -> Result<MovieRecorder, String> {
+        let file = try!(File::create(path).map_err(|e| format!("{}: {}", path, e)));
+        let mut out = BufWriter::new(file);
+        try!(write!(out,
+                    "{}\nrom {:016x}\nseed {}\nrng {}\nipf {}\nframes\n",
+                    MOVIE_MAGIC,
+                    header.rom_hash,
+                    header.seed,
+                    header.rng.name(),
+                    header.instructions_per_frame)
+            .map_err(|e| e.to_string()));
+        Ok(MovieRecorder {
+            out: out,
+            frame: 0,
+        })
+    }
+
+    /// Whether the frame about to be recorded should carry a checksum.
+    pub fn wants_checksum(&self) -> bool {
+        (self.frame + 1) % CHECKSUM_INTERVAL == 0
+    }
+
+    pub fn record_frame(&mut self, keys: &Keyboard, checksum: Option<u64>) {
+        let result = match checksum {
+            Some(sum) => writeln!(self.out, "{:04x} {:016x}", keys_to_bits(keys), sum),
+            None => writeln!(self.out, "{:04x}", keys_to_bits(keys)),
+        };
+        result.unwrap();
+        self.frame += 1;
+    }
+
+    pub fn finish(&mut self) {
+        self.out.flush().unwrap();
+    }
+}
+
+
+pub struct MoviePlayer {
+    pub header: MovieHeader,
+    frames: Vec<(u16, Option<u64>)>,
+    frame: usize,
+    desynced: bool,
+}
+
+impl MoviePlayer {
+    pub fn open(path: &str) -> Result<MoviePlayer, String> {
+        let file = try!(File::open(path).map_err(|e| format!("{}: {}", path, e)));
+        let mut lines = BufReader::new(file).lines();
+
+        match lines.next() {
+            Some(Ok(ref magic)) if magic == MOVIE_MAGIC => (),
+            _ => return Err(format!("{}: not a vipchip movie", path)),
+        }
+
+        let mut rom_hash = None;
+        let mut seed = None;
+        let mut rng = None;
+        let mut ipf = None;
+        loop {
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => return Err(format!("{}: truncated header", path)),
+            };
+            if line == "frames" {
+                break;
+            }
+            let mut parts = line.split_whitespace();
+            let key = parts.next();
+            let value = parts.next().unwrap_or("");
+            match key {
+                Some("rom") => rom_hash = u64::from_str_radix(value, 16).ok(),
+                Some("seed") => seed = value.parse::<u64>().ok(),
+                Some("rng") => rng = RngKind::from_name(value),
+                Some("ipf") => ipf = value.parse::<usize>().ok(),
+                _ => return Err(format!("{}: bad header line '{}'", path, line)),
+            }
+        }
+
+        let header = match (rom_hash, seed, rng, ipf) {
+            (Some(rom_hash), Some(seed), Some(rng), Some(ipf)) => {
+                MovieHeader {
+                    rom_hash: rom_hash,
+                    seed: seed,
+                    rng: rng,
+                    instructions_per_frame: ipf,
+                }
+            }
+            _ => return Err(format!("{}: incomplete header", path)),
+        };
+
+        let mut frames = Vec::new();
+        for line in lines {
+            let line = try!(line.map_err(|e| e.to_string()));
+            let mut parts = line.split_whitespace();
+            let keys = match parts.next().and_then(|k| u16::from_str_radix(k, 16).ok()) {
+                Some(keys) => keys,
+                None => return Err(format!("{}: bad frame {}", path, frames.len())),
+            };
+            let checksum = parts.next().and_then(|c| u64::from_str_radix(c, 16).ok());
+            frames.push((keys, checksum));
+        }
+
+        Ok(MoviePlayer {
+            header: header,
+            frames: frames,
+            frame: 0,
+            desynced: false,
+        })
+    }
+
+    /// Keypad state for the next frame, or `None` once the movie has ended.
+    pub fn next_keys(&self) -> Option<Keyboard> {
+        self.frames.get(self.frame).map(|&(bits, _)| bits_to_keys(bits))
+    }
+
+    pub fn wants_checksum(&self) -> bool {
+        match self.frames.get(self.frame) {
+            Some(&(_, Some(_))) => !self.desynced,
+            _ => false,
+        }
+    }
+
+    /// Finishes the current frame, comparing against the recorded checksum if there is one.
+    /// Only the first desync is reported; everything after it is expected to differ.
+    pub fn end_frame(&mut self, checksum: Option<u64>) -> Result<(), String> {
+        let expected = self.frames.get(self.frame).and_then(|&(_, sum)| sum);
+        let frame = self.frame;
+        self.frame += 1;
+        match (expected, checksum) {
+            (Some(expected), Some(actual)) if !self.desynced && expected != actual => {
+                self.desynced = true;
+                Err(format!("desync at frame {}: expected state {:016x}, got {:016x}",
+                            frame,
+                            expected,
+                            actual))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.frame >= self.frames.len()
+    }
+}
+
+
+#[test]
+fn test_key_bits() {
+    let mut keys = [false; 16];
+    keys[0x0] = true;
+    keys[0xA] = true;
+    assert_eq!(keys_to_bits(&keys), 0x0401);
+    assert_eq!(bits_to_keys(0x0401), keys);
+}
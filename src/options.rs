@@ -1,10 +1,87 @@
-use clap::{Arg, App};
+use std::io::{self, Write};
+use std::process;
+use std::str::FromStr;
+
+use clap::{Arg, App, AppSettings, SubCommand};
+
+use rng::{RngKind, seed_from_time};
+use headless::{HeadlessOptions, parse_until, parse_key_script, load_key_script};
+use bench::{BenchOptions, Limit};
+use emulator::HaltPolicy;
+use profile::ProfileOptions;
+use coverage::CoverageOptions;
+use sanitizer::SanitizePolicy;
+use crash::OpcodePolicy;
+use trace::{TraceOptions, TraceFormat, parse_trigger, parse_opcode_patterns,
+            parse_address_range};
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: usize = 1000;
+
+pub enum Mode {
+    Run,
+    /// Run a golden screenshot manifest.
+    Test(String),
+    /// Measure emulation throughput. Uses the built-in `bench` program without a ROM file.
+    Bench(Option<String>, BenchOptions),
+    /// Compare two trace files and report where they first differ.
+    TraceDiff(String, String, usize),
+}
 
 pub struct Options {
+    pub mode: Mode,
     pub filename: String,
-    pub debug: bool
+    pub debug: bool,
+    /// Port for the GDB remote protocol server.
+    pub gdb_port: Option<u16>,
+    /// Port for the Debug Adapter Protocol server.
+    pub dap_port: Option<u16>,
+    pub seed: u64,
+    pub rng: RngKind,
+    pub instructions_per_frame: usize,
+    pub record: Option<String>,
+    pub play: Option<String>,
+    pub ff_multiplier: u32,
+    pub headless: Option<HeadlessOptions>,
+    pub halt_policy: HaltPolicy,
+    pub trace: Option<TraceOptions>,
+    pub profile: Option<ProfileOptions>,
+    pub coverage: Option<CoverageOptions>,
+    /// Symbol files, in the order given.
+    pub symbols: Vec<String>,
+    /// Whether to check for program bugs, and what to do about them.
+    pub sanitize: Option<SanitizePolicy>,
+    pub opcode_policy: OpcodePolicy,
+    /// Where to write a report when an illegal opcode halts or breaks.
+    pub crash_report: Option<String>,
+    /// A Lua script to run alongside the program.
+    pub script: Option<String>,
+}
+
+/// Parses a decimal number or a hex number with a `0x` prefix.
+pub fn parse_number(text: &str) -> Result<u32, String> {
+    let result = if text.starts_with("0x") || text.starts_with("0X") {
+        u32::from_str_radix(&text[2..], 16)
+    } else {
+        text.parse::<u32>()
+    };
+    result.map_err(|_| format!("'{}' is not a number", text))
+}
+
+/// The parsed value of option `--name`, or exits with status 2 after saying what's wrong.
+fn or_exit<T>(name: &str, result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "error: --{}: {}", name, e);
+            process::exit(2);
+        }
+    }
+}
+
+/// Parses option `--name` as a decimal number of type `T`, exiting if it isn't one.
+fn number<T: FromStr>(name: &str, text: &str) -> T {
+    or_exit(name, text.parse::<T>().map_err(|_| format!("'{}' is not a valid number", text)))
 }
 
 pub fn parse_commandline() -> Options {
@@ -12,20 +89,403 @@ pub fn parse_commandline() -> Options {
         .version(VERSION)
         .author("jdeeny")
         .about("Emulates a chip8 system")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(SubCommand::with_name("test")
+            .about("Runs the golden screenshot tests in a manifest")
+            .arg(Arg::with_name("MANIFEST")
+                .help("The manifest file listing the test cases")
+                .required(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("bench")
+            .about("Runs a program uncapped and reports emulation speed")
+            .arg(Arg::with_name("seconds")
+                .long("seconds")
+                .value_name("N")
+                .takes_value(true)
+                .help("How long to run (default: 10)"))
+            .arg(Arg::with_name("instructions")
+                .long("instructions")
+                .value_name("N")
+                .takes_value(true)
+                .conflicts_with("seconds")
+                .help("Runs this many instructions instead of a fixed time"))
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Prints the report as JSON"))
+            .arg(Arg::with_name("INPUT")
+                .help("The program to run (default: the built-in bench program)")
+                .index(1)))
+        .subcommand(SubCommand::with_name("tracediff")
+            .about("Finds the first instruction where two traces differ")
+            .arg(Arg::with_name("context")
+                .long("context")
+                .value_name("N")
+                .takes_value(true)
+                .help("Records to show around the difference (default: 5)"))
+            .arg(Arg::with_name("A")
+                .help("The first trace")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("B")
+                .help("The second trace")
+                .required(true)
+                .index(2)))
         .arg(Arg::with_name("debug")
             .short("d")
             .long("debug")
-            .help("Enables printing of debug information"))
+            .help("Starts an interactive debugger on the console"))
+        .arg(Arg::with_name("gdb")
+            .long("gdb")
+            .value_name("PORT")
+            .takes_value(true)
+            .help("Serves the GDB remote protocol on a local port"))
+        .arg(Arg::with_name("dap")
+            .long("dap")
+            .value_name("PORT")
+            .takes_value(true)
+            .help("Serves the Debug Adapter Protocol on a local port for editors"))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .value_name("SEED")
+            .takes_value(true)
+            .help("Seeds the CXNN random number generator so runs are repeatable"))
+        .arg(Arg::with_name("rng")
+            .long("rng")
+            .value_name("RNG")
+            .takes_value(true)
+            .possible_values(&["xorshift", "vip"])
+            .help("Selects the CXNN random number algorithm (default: xorshift)"))
+        .arg(Arg::with_name("ipf")
+            .long("ipf")
+            .value_name("N")
+            .takes_value(true)
+            .help("Number of instructions executed per 60Hz frame"))
+        .arg(Arg::with_name("ff")
+            .long("ff")
+            .value_name("N")
+            .takes_value(true)
+            .help("Fast-forward speed while Tab is held; 0 runs uncapped (default)"))
+        .arg(Arg::with_name("record")
+            .long("record")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with("play")
+            .help("Records keypad input to a movie file"))
+        .arg(Arg::with_name("play")
+            .long("play")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Replays keypad input from a movie file"))
+        .arg(Arg::with_name("headless")
+            .long("headless")
+            .help("Runs without a window; needs --frames or --until"))
+        .arg(Arg::with_name("frames")
+            .long("frames")
+            .value_name("N")
+            .takes_value(true)
+            .requires("headless")
+            .help("Number of frames to run in headless mode"))
+        .arg(Arg::with_name("until")
+            .long("until")
+            .value_name("CONDITION")
+            .takes_value(true)
+            .requires("headless")
+            .help("Stops headless mode when the condition is met, e.g. pc=0x2A4"))
+        .arg(Arg::with_name("keys")
+            .long("keys")
+            .value_name("SCRIPT")
+            .takes_value(true)
+            .requires("headless")
+            .help("Key presses for headless mode, e.g. \"10:+5,20:-5\""))
+        .arg(Arg::with_name("key-script")
+            .long("key-script")
+            .value_name("FILE")
+            .takes_value(true)
+            .requires("headless")
+            .conflicts_with("keys")
+            .help("Reads headless key presses from a file"))
+        .arg(Arg::with_name("screenshot")
+            .long("screenshot")
+            .value_name("FILE")
+            .takes_value(true)
+            .requires("headless")
+            .help("Saves the final screen as .png or text"))
+        .arg(Arg::with_name("exit-on-halt")
+            .long("exit-on-halt")
+            .help("Quits when the program halts (jump to self or 00FD) instead of pausing; \
+                   always on in headless mode"))
+        .arg(Arg::with_name("halt-code")
+            .long("halt-code")
+            .value_name("N")
+            .takes_value(true)
+            .help("Exit status to use when the program halts (default: 0)"))
+        .arg(Arg::with_name("result-reg")
+            .long("result-reg")
+            .value_name("X")
+            .takes_value(true)
+            .conflicts_with("halt-code")
+            .help("Uses register VX as the exit status when the program halts"))
+        .arg(Arg::with_name("trace")
+            .long("trace")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Writes every executed instruction to a trace file"))
+        .arg(Arg::with_name("trace-format")
+            .long("trace-format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(&["text", "binary"])
+            .requires("trace")
+            .help("Trace file format (default: text)"))
+        .arg(Arg::with_name("trace-registers")
+            .long("trace-registers")
+            .requires("trace")
+            .help("Adds V0-VF, I and the call depth to the trace"))
+        .arg(Arg::with_name("trace-timers")
+            .long("trace-timers")
+            .requires("trace")
+            .help("Adds the delay and sound timers to the trace"))
+        .arg(Arg::with_name("trace-memory")
+            .long("trace-memory")
+            .requires("trace")
+            .help("Adds a hash of memory to the trace"))
+        .arg(Arg::with_name("trace-range")
+            .long("trace-range")
+            .value_name("START-END")
+            .takes_value(true)
+            .requires("trace")
+            .help("Only traces instructions in this address range"))
+        .arg(Arg::with_name("trace-opcodes")
+            .long("trace-opcodes")
+            .value_name("PATTERNS")
+            .takes_value(true)
+            .requires("trace")
+            .help("Only traces matching opcodes, e.g. DXYN,FX55"))
+        .arg(Arg::with_name("trace-start")
+            .long("trace-start")
+            .value_name("TRIGGER")
+            .takes_value(true)
+            .requires("trace")
+            .help("Starts tracing at pc=ADDR or instr=N"))
+        .arg(Arg::with_name("trace-stop")
+            .long("trace-stop")
+            .value_name("TRIGGER")
+            .takes_value(true)
+            .requires("trace")
+            .help("Stops tracing at pc=ADDR or instr=N"))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Writes a report of where instructions were spent when the emulator exits"))
+        .arg(Arg::with_name("profile-folded")
+            .long("profile-folded")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Writes call stacks in the folded format used by flamegraph tools"))
+        .arg(Arg::with_name("coverage")
+            .long("coverage")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Writes a disassembly showing which instructions ran when the emulator exits"))
+        .arg(Arg::with_name("coverage-lcov")
+            .long("coverage-lcov")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Writes line coverage of a hex source program in lcov format"))
+        .arg(Arg::with_name("symbols")
+            .long("symbols")
+            .value_name("FILE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Loads names for addresses from a symbol file; can be given more than once"))
+        .arg(Arg::with_name("sanitize")
+            .long("sanitize")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(&["warn", "break"])
+            .help("Checks for stack, memory and jump bugs, warning about them or stopping"))
+        .arg(Arg::with_name("illegal-opcodes")
+            .long("illegal-opcodes")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(&["platform", "ignore", "halt", "break"])
+            .help("Handles 0NNN calls and unknown opcodes (default: platform)"))
+        .arg(Arg::with_name("crash-report")
+            .long("crash-report")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Where illegal opcodes that halt or break are reported (default: crash.txt)"))
+        .arg(Arg::with_name("script")
+            .long("script")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Runs a Lua script with hooks into the emulator (needs the scripting feature)"))
         .arg(Arg::with_name("INPUT")
             .help("The input file to use")
             .required(true)
             .index(1))
         .get_matches();
 
-        println!("{:?}", matches);
+        let seed = match matches.value_of("seed") {
+            Some(s) => number("seed", s),
+            None => {
+                // Printed so a run with a surprising outcome can be repeated with --seed.
+                // It goes to stderr to keep stdout for reports.
+                let seed = seed_from_time();
+                let _ = writeln!(io::stderr(), "seed: {}", seed);
+                seed
+            }
+        };
+
+        let headless = if matches.is_present("headless") {
+            let keys = match (matches.value_of("keys"), matches.value_of("key-script")) {
+                (Some(script), _) => or_exit("keys", parse_key_script(script)),
+                (_, Some(path)) => or_exit("key-script", load_key_script(path)),
+                _ => Vec::new(),
+            };
+            Some(HeadlessOptions {
+                frames: matches.value_of("frames").map(|n| number("frames", n)),
+                until: matches.value_of("until").map(|u| or_exit("until", parse_until(u))),
+                keys: keys,
+                screenshot: matches.value_of("screenshot").map(|s| s.to_string()),
+            })
+        } else {
+            None
+        };
+
+        let trace = matches.value_of("trace").map(|path| {
+            TraceOptions {
+                path: path.to_string(),
+                format: match matches.value_of("trace-format") {
+                    Some("binary") => TraceFormat::Binary,
+                    _ => TraceFormat::Text,
+                },
+                registers: matches.is_present("trace-registers"),
+                timers: matches.is_present("trace-timers"),
+                memory: matches.is_present("trace-memory"),
+                range: matches.value_of("trace-range")
+                    .map(|r| or_exit("trace-range", parse_address_range(r))),
+                opcodes: matches.value_of("trace-opcodes")
+                    .map(|p| or_exit("trace-opcodes", parse_opcode_patterns(p)))
+                    .unwrap_or(Vec::new()),
+                start: matches.value_of("trace-start")
+                    .map(|t| or_exit("trace-start", parse_trigger(t))),
+                stop: matches.value_of("trace-stop")
+                    .map(|t| or_exit("trace-stop", parse_trigger(t))),
+            }
+        });
+
+        let profile = if matches.is_present("profile") || matches.is_present("profile-folded") {
+            Some(ProfileOptions {
+                report: matches.value_of("profile").map(|s| s.to_string()),
+                folded: matches.value_of("profile-folded").map(|s| s.to_string()),
+            })
+        } else {
+            None
+        };
+
+        let coverage = if matches.is_present("coverage") || matches.is_present("coverage-lcov") {
+            Some(CoverageOptions {
+                listing: matches.value_of("coverage").map(|s| s.to_string()),
+                lcov: matches.value_of("coverage-lcov").map(|s| s.to_string()),
+            })
+        } else {
+            None
+        };
+
+        let opcode_policy = OpcodePolicy::from_name(matches.value_of("illegal-opcodes")
+                .unwrap_or("platform"))
+            .unwrap();
+        let crash_report = match opcode_policy {
+            OpcodePolicy::Halt | OpcodePolicy::Break => {
+                Some(matches.value_of("crash-report").unwrap_or("crash.txt").to_string())
+            }
+            OpcodePolicy::Platform | OpcodePolicy::Ignore => None,
+        };
+
+        let instructions_per_frame = match matches.value_of("ipf") {
+            Some(n) => {
+                // Frames are counted by dividing by this, and a frame has to run something.
+                let ipf: usize = number("ipf", n);
+                or_exit("ipf",
+                        if ipf > 0 {
+                            Ok(ipf)
+                        } else {
+                            Err("has to be at least 1".to_string())
+                        })
+            }
+            None => DEFAULT_INSTRUCTIONS_PER_FRAME,
+        };
+
+        let mode = match matches.subcommand() {
+            ("test", Some(sub)) => Mode::Test(sub.value_of("MANIFEST").unwrap().to_string()),
+            ("bench", Some(sub)) => {
+                let limit = match sub.value_of("instructions") {
+                    Some(n) => Limit::Instructions(or_exit("instructions", parse_number(n)) as u64),
+                    None => {
+                        Limit::Seconds(sub.value_of("seconds")
+                            .map(|n| number("seconds", n))
+                            .unwrap_or(10))
+                    }
+                };
+                Mode::Bench(sub.value_of("INPUT").map(|s| s.to_string()),
+                            BenchOptions {
+                                limit: limit,
+                                instructions_per_frame: instructions_per_frame,
+                                json: sub.is_present("json"),
+                            })
+            }
+            ("tracediff", Some(sub)) => {
+                Mode::TraceDiff(sub.value_of("A").unwrap().to_string(),
+                                sub.value_of("B").unwrap().to_string(),
+                                sub.value_of("context")
+                                    .map(|n| number("context", n))
+                                    .unwrap_or(5))
+            }
+            _ => Mode::Run,
+        };
 
         Options {
-            filename: matches.value_of("INPUT").unwrap().to_string(),
-            debug: matches.is_present("debug")
+            mode: mode,
+            filename: matches.value_of("INPUT").unwrap_or("").to_string(),
+            debug: matches.is_present("debug"),
+            gdb_port: matches.value_of("gdb").map(|port| number("gdb", port)),
+            dap_port: matches.value_of("dap").map(|port| number("dap", port)),
+            seed: seed,
+            rng: RngKind::from_name(matches.value_of("rng").unwrap_or("xorshift")).unwrap(),
+            instructions_per_frame: instructions_per_frame,
+            record: matches.value_of("record").map(|s| s.to_string()),
+            play: matches.value_of("play").map(|s| s.to_string()),
+            ff_multiplier: match matches.value_of("ff") {
+                Some(n) => number("ff", n),
+                None => 0,
+            },
+            headless: headless,
+            halt_policy: HaltPolicy {
+                exit: matches.is_present("exit-on-halt") || matches.is_present("headless"),
+                code: matches.value_of("halt-code")
+                    .map(|n| number("halt-code", n))
+                    .unwrap_or(0),
+                result_register: matches.value_of("result-reg").map(|x| {
+                    let register = x.trim_left_matches(|c| c == 'v' || c == 'V');
+                    let register = usize::from_str_radix(register, 16)
+                        .ok()
+                        .and_then(|x| if x < 16 { Some(x) } else { None })
+                        .ok_or(format!("'{}' is not a register 0-F", x));
+                    or_exit("result-reg", register)
+                }),
+            },
+            trace: trace,
+            profile: profile,
+            coverage: coverage,
+            symbols: matches.values_of("symbols")
+                .map(|files| files.map(|s| s.to_string()).collect())
+                .unwrap_or(Vec::new()),
+            sanitize: matches.value_of("sanitize").map(|s| SanitizePolicy::from_name(s).unwrap()),
+            opcode_policy: opcode_policy,
+            crash_report: crash_report,
+            script: matches.value_of("script").map(|s| s.to_string()),
         }
 }
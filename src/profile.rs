@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+
+use disasm::{decode, listing_line, Instruction};
+use machine::Machine;
+use symbols::Symbols;
+
+/// Number of addresses listed in the hotspot section of the report.
+const HOT_ADDRESSES: usize = 20;
+const ADDRESSES: usize = 0x10000;
+
+
+pub struct ProfileOptions {
+    /// Where to write the text report.
+    pub report: Option<String>,
+    /// Where to write call stacks in the folded format read by flamegraph.pl and inferno.
+    pub folded: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FrameStats {
+    instructions: u64,
+    /// Instructions run before the program started waiting on the delay timer or a key.
+    busy: u64,
+}
+
+/// Counts executed instructions per address, per call stack and per frame, and writes a
+/// report when the emulator exits.
+pub struct Profiler {
+    options: ProfileOptions,
+    total: u64,
+    counts: Vec<u64>,
+    codewords: Vec<u16>,
+    /// Entry points of the subroutines in progress, outermost first.
+    stack: Vec<u16>,
+    /// Instructions run with each call stack, keyed like `stack`.
+    stacks: HashMap<Vec<u16>, u64>,
+    calls: HashMap<u16, u64>,
+    frames: Vec<FrameStats>,
+    frame: FrameStats,
+    /// The program has waited during the current frame, so the rest of it isn't busy.
+    waiting: bool,
+    symbols: Arc<Symbols>,
+}
+
+
+fn write_file(path: &str, text: &str) {
+    let written = File::create(path).and_then(|mut file| file.write_all(text.as_bytes()));
+    if let Err(e) = written {
+        println!("profile: {}: {}", path, e);
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+impl Profiler {
+    pub fn new(options: ProfileOptions, symbols: Arc<Symbols>) -> Profiler {
+        Profiler {
+            options: options,
+            total: 0,
+            counts: vec![0; ADDRESSES],
+            codewords: vec![0; ADDRESSES],
+            stack: Vec::new(),
+            stacks: HashMap::new(),
+            calls: HashMap::new(),
+            frames: Vec::new(),
+            frame: FrameStats {
+                instructions: 0,
+                busy: 0,
+            },
+            waiting: false,
+            symbols: symbols,
+        }
+    }
+
+    /// Name of a subroutine in reports: its symbol, or `sub_XXX` without one. The code
+    /// outside any subroutine is `main`.
+    fn name(&self, entry: Option<&u16>) -> String {
+        match entry {
+            Some(&addr) => {
+                match self.symbols.name_of(addr) {
+                    Some(name) => name.to_string(),
+                    None => format!("sub_{:03X}", addr),
+                }
+            }
+            None => "main".to_string(),
+        }
+    }
+
+    /// Called by the machine before it runs each instruction.
+    pub fn record(&mut self, machine: &mut Machine, pc: u16, codeword: u16) {
+        let delay_running = match decode(codeword) {
+            Instruction::GetDelay(_) => machine.delay_timer() != 0,
+            _ => false,
+        };
+        self.count(pc, codeword, delay_running);
+    }
+
+    fn count(&mut self, pc: u16, codeword: u16, delay_running: bool) {
+        self.total += 1;
+        self.counts[pc as usize] += 1;
+        self.codewords[pc as usize] = codeword;
+
+        let known = match self.stacks.get_mut(&self.stack[..]) {
+            Some(count) => {
+                *count += 1;
+                true
+            }
+            None => false,
+        };
+        if !known {
+            self.stacks.insert(self.stack.clone(), 1);
+        }
+
+        let instruction = decode(codeword);
+        self.frame.instructions += 1;
+        if !self.waiting {
+            match instruction {
+                // Reading a running delay timer is how most games wait for the next frame.
+                Instruction::GetDelay(_) if delay_running => self.waiting = true,
+                Instruction::WaitKey(_) => self.waiting = true,
+                _ => self.frame.busy += 1,
+            }
+        }
+
+        match instruction {
+            Instruction::Call(addr) => {
+                self.stack.push(addr);
+                *self.calls.entry(addr).or_insert(0) += 1;
+            }
+            Instruction::Ret => {
+                self.stack.pop();
+            }
+            _ => (),
+        }
+    }
+
+    /// Called after each emulated frame's timer tick.
+    pub fn end_frame(&mut self) {
+        self.frames.push(self.frame);
+        self.frame = FrameStats {
+            instructions: 0,
+            busy: 0,
+        };
+        self.waiting = false;
+    }
+
+    /// One line per call stack, like `main;sub_2A4;sub_300 1234`, sorted.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self.stacks
+            .iter()
+            .map(|(stack, count)| {
+                let mut names = vec![self.name(None)];
+                names.extend(stack.iter().map(|entry| self.name(Some(entry))));
+                format!("{} {}", names.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    pub fn report(&self) -> String {
+        let mut text = format!("{} instructions, {} frames\n", self.total, self.frames.len());
+
+        if !self.frames.is_empty() {
+            let busy: Vec<u64> = self.frames.iter().map(|f| f.busy).collect();
+            let never_waited = self.frames.iter().filter(|f| f.busy == f.instructions).count();
+            text.push_str(&format!("\nInstructions per frame before waiting on the delay timer \
+                                    or a key:\n  min {}, mean {:.1}, max {}\n  {} frames \
+                                    never waited\n",
+                                   busy.iter().min().unwrap(),
+                                   busy.iter().fold(0, |sum, n| sum + n) as f64 / busy.len() as f64,
+                                   busy.iter().max().unwrap(),
+                                   never_waited));
+        }
+
+        // Self counts go to the innermost subroutine, totals to every one on the stack.
+        let mut self_counts: HashMap<Option<u16>, u64> = HashMap::new();
+        let mut total_counts: HashMap<Option<u16>, u64> = HashMap::new();
+        for (stack, &count) in &self.stacks {
+            *self_counts.entry(stack.last().cloned()).or_insert(0) += count;
+            *total_counts.entry(None).or_insert(0) += count;
+            let mut seen: Vec<u16> = Vec::new();
+            for &entry in stack {
+                if !seen.contains(&entry) {
+                    seen.push(entry);
+                    *total_counts.entry(Some(entry)).or_insert(0) += count;
+                }
+            }
+        }
+        let mut subroutines: Vec<(Option<u16>, u64)> = total_counts.into_iter().collect();
+        subroutines.sort_by(|a, b| (b.1, a.0).cmp(&(a.1, b.0)));
+        text.push_str("\nSubroutines:\n     self    total     calls  name\n");
+        for (entry, total) in subroutines {
+            let calls = match entry {
+                Some(addr) => self.calls.get(&addr).cloned().unwrap_or(0).to_string(),
+                None => "-".to_string(),
+            };
+            text.push_str(&format!("  {:6.2}%  {:6.2}%  {:>8}  {}\n",
+                                   percent(self_counts.get(&entry).cloned().unwrap_or(0),
+                                           self.total),
+                                   percent(total, self.total),
+                                   calls,
+                                   self.name(entry.as_ref())));
+        }
+
+        let mut hot: Vec<usize> = (0..ADDRESSES).filter(|&addr| self.counts[addr] > 0).collect();
+        hot.sort_by(|&a, &b| (self.counts[b], a).cmp(&(self.counts[a], b)));
+        text.push_str("\nHot addresses:\n");
+        for &addr in hot.iter().take(HOT_ADDRESSES) {
+            text.push_str(&format!("  {:6.2}%  {:>10}  {}\n",
+                                   percent(self.counts[addr], self.total),
+                                   self.counts[addr],
+                                   listing_line(addr as u16,
+                                                self.codewords[addr],
+                                                &self.symbols)));
+        }
+        text
+    }
+
+    /// Writes the report and folded stacks to the files in the options.
+    pub fn write(&self) {
+        if let Some(ref path) = self.options.report {
+            write_file(path, &self.report());
+        }
+        if let Some(ref path) = self.options.folded {
+            write_file(path, &self.folded());
+        }
+    }
+}
+
+#[test]
+fn test_profiler() {
+    let symbols = Symbols::parse("draw = 0x400").unwrap();
+    let mut profiler = Profiler::new(ProfileOptions {
+                                         report: None,
+                                         folded: None,
+                                     },
+                                     Arc::new(symbols));
+    // main calls 0x300 twice; it runs one instruction then returns.
+    for _ in 0..2 {
+        profiler.count(0x200, 0x2300, false);
+        profiler.count(0x300, 0x00EE, false);
+    }
+    profiler.count(0x202, 0xF007, true);
+    profiler.count(0x204, 0x1202, false);
+    profiler.end_frame();
+    profiler.count(0x206, 0x2400, false);
+    profiler.count(0x400, 0x00EE, false);
+
+    assert_eq!(profiler.folded(), "main 5\nmain;draw 1\nmain;sub_300 2\n");
+    assert_eq!(profiler.frames,
+               vec![FrameStats {
+                        instructions: 6,
+                        busy: 4,
+                    }]);
+    let report = profiler.report();
+    assert!(report.contains(" 25.00%   25.00%         2  sub_300"));
+    assert!(report.contains("0x206: 2400  CALL draw"));
+    assert!(report.contains("0x200: 2300  CALL 0x300"));
+}
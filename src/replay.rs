@@ -0,0 +1,179 @@
+//! Reverse execution. The chip8 core can't save and restore its state (its call stack is
+//! private), so going back means replaying from reset. A run is deterministic given the
+//! program, the RNG and the inputs, so the emulator logs each input with the instruction
+//! count it arrived at, and a replay applies them at the same points.
+
+use chip8::Keyboard;
+use chip8::config::COSMAC_VIP;
+
+use debugger::Register;
+use crash::OpcodePolicy;
+use machine::{Machine, Stop};
+use rng::{new_rng, RngKind};
+
+
+/// What a replay starts from: the machine as `main` built it.
+pub struct ReplayStart {
+    pub rng: RngKind,
+    pub seed: u64,
+    pub program: Vec<u8>,
+    /// Skipping illegal opcodes changes how the program runs, so replays have to as well.
+    pub opcode_policy: OpcodePolicy,
+}
+
+/// Something that changed the machine from outside the program.
+enum Input {
+    Keys(Keyboard),
+    Memory(u16, Vec<u8>),
+    Register(Register, u16),
+}
+
+pub struct History {
+    start: ReplayStart,
+    instructions_per_frame: usize,
+    /// Inputs in order, with the instruction count when each arrived.
+    inputs: Vec<(u64, Input)>,
+    /// Keys as last logged, so only changes are kept.
+    keys: Keyboard,
+}
+
+fn apply(input: &Input, machine: &mut Machine) {
+    match *input {
+        Input::Keys(ref keys) => machine.set_keyboard(keys),
+        Input::Memory(addr, ref bytes) => {
+            for (n, byte) in bytes.iter().enumerate() {
+                machine.write_byte(addr.wrapping_add(n as u16), *byte);
+            }
+        }
+        Input::Register(register, value) => machine.set_register_value(register, value),
+    }
+}
+
+impl History {
+    pub fn new(start: ReplayStart, instructions_per_frame: usize) -> History {
+        History {
+            start: start,
+            instructions_per_frame: instructions_per_frame,
+            inputs: Vec::new(),
+            keys: [false; 16],
+        }
+    }
+
+    pub fn record_keys(&mut self, at: u64, keys: &Keyboard) {
+        if *keys != self.keys {
+            self.keys = *keys;
+            self.inputs.push((at, Input::Keys(*keys)));
+        }
+    }
+
+    pub fn record_memory(&mut self, at: u64, addr: u16, bytes: Vec<u8>) {
+        self.inputs.push((at, Input::Memory(addr, bytes)));
+    }
+
+    pub fn record_register(&mut self, at: u64, register: Register, value: u16) {
+        self.inputs.push((at, Input::Register(register, value)));
+    }
+
+    /// Drops the inputs that came after instruction `at`, once the machine has gone back
+    /// there; from then on the run can turn out differently.
+    pub fn truncate(&mut self, at: u64) {
+        self.inputs.retain(|&(when, _)| when <= at);
+        self.keys = self.inputs
+            .iter()
+            .filter_map(|&(_, ref input)| {
+                match *input {
+                    Input::Keys(keys) => Some(keys),
+                    _ => None,
+                }
+            })
+            .last()
+            .unwrap_or([false; 16]);
+    }
+
+    /// Replays from reset until `until` instructions have run, ticking the timers at the
+    /// end of every frame the way `Emulator` does. When `stops_like` is given, the replay
+    /// gets its breakpoints, with no hits yet, and watchpoints and returns the instruction
+    /// counts where they would have stopped it, in order.
+    pub fn replay(&self, until: u64, stops_like: Option<&Machine>) -> (Machine, Vec<(u64, Stop)>) {
+        let mut machine = Machine::new(COSMAC_VIP, new_rng(self.start.rng, self.start.seed));
+        machine.load_program(&self.start.program);
+        machine.set_opcode_policy(self.start.opcode_policy);
+        if let Some(original) = stops_like {
+            for mut breakpoint in original.breakpoint_details() {
+                breakpoint.hits = 0;
+                machine.set_breakpoint(breakpoint);
+            }
+            for watchpoint in original.watchpoints() {
+                machine.add_watchpoint(*watchpoint);
+            }
+        }
+
+        let mut stops = Vec::new();
+        let mut inputs = self.inputs.iter().peekable();
+        loop {
+            let now = machine.instructions();
+            while inputs.peek().map(|&&(at, _)| at == now).unwrap_or(false) {
+                apply(&inputs.next().unwrap().1, &mut machine);
+            }
+            if now >= until {
+                break;
+            }
+            match machine.step() {
+                // The program can't get past a halt, so neither can the replay.
+                Some(Stop::Halted(_)) => break,
+                Some(stop) => {
+                    stops.push((now, stop));
+                    machine.resume();
+                }
+                None => {
+                    if machine.instructions() % self.instructions_per_frame as u64 == 0 {
+                        machine.timer_tick();
+                    }
+                }
+            }
+        }
+        (machine, stops)
+    }
+}
+
+
+#[test]
+fn test_replay() {
+    use machine::Breakpoint;
+
+    // V0 = 0, then count it up forever.
+    let program = vec![0x60, 0x00, 0x70, 0x01, 0x12, 0x02];
+    let mut history = History::new(ReplayStart {
+                                       rng: RngKind::XorShift,
+                                       seed: 0,
+                                       program: program,
+                                       opcode_policy: OpcodePolicy::Platform,
+                                   },
+                                   10);
+    history.record_register(5, Register::V(0), 0x40);
+    history.record_keys(7, &[true; 16]);
+
+    let (mut machine, _) = history.replay(9, None);
+    assert_eq!(machine.instructions(), 9);
+    // Two adds before the write at 5 and two after it.
+    assert_eq!(machine.register(0), 0x42);
+    assert_eq!(machine.keyboard(), [true; 16]);
+
+    let mut original = Machine::new(COSMAC_VIP, new_rng(RngKind::XorShift, 0));
+    original.set_breakpoint(Breakpoint::new(0x204));
+    let (_, stops) = history.replay(9, Some(&original));
+    assert_eq!(stops.iter().map(|&(at, _)| at).collect::<Vec<u64>>(), vec![2, 4, 6, 8]);
+
+    // Hits the original had already counted don't carry over.
+    let mut counted = Breakpoint::new(0x204);
+    counted.hits = 10;
+    counted.hit_count = Some(12);
+    original.set_breakpoint(counted);
+    let (machine, stops) = history.replay(9, Some(&original));
+    assert_eq!(stops.iter().map(|&(at, _)| at).collect::<Vec<u64>>(), vec![]);
+    assert_eq!(machine.breakpoint_details()[0].hits, 4);
+
+    history.truncate(6);
+    let (mut machine, _) = history.replay(9, None);
+    assert_eq!(machine.keyboard(), [false; 16]);
+}
@@ -0,0 +1,113 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the random bytes handed out by CXNN.
+pub trait Rng: Send {
+    fn next_byte(&mut self) -> u8;
+    /// Called once for every instruction fetched, before it executes.
+    fn tick(&mut self) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RngKind {
+    XorShift,
+    Vip,
+}
+
+impl RngKind {
+    pub fn from_name(name: &str) -> Option<RngKind> {
+        match name {
+            "xorshift" => Some(RngKind::XorShift),
+            "vip" => Some(RngKind::Vip),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RngKind::XorShift => "xorshift",
+            RngKind::Vip => "vip",
+        }
+    }
+}
+
+pub fn new_rng(kind: RngKind, seed: u64) -> Box<Rng> {
+    match kind {
+        RngKind::XorShift => Box::new(XorShiftRng::new(seed)),
+        RngKind::Vip => Box::new(VipRng::new(seed)),
+    }
+}
+
+/// A seed for runs where the user didn't ask for one.
+pub fn seed_from_time() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() ^ (now.subsec_nanos() as u64) << 32
+}
+
+
+/// Plain 32-bit xorshift. Fast, and every seed gives the same stream on every platform.
+pub struct XorShiftRng {
+    state: u32,
+}
+impl XorShiftRng {
+    pub fn new(seed: u64) -> XorShiftRng {
+        let state = (seed ^ (seed >> 32)) as u32;
+        XorShiftRng { state: if state == 0 { 0x2545F491 } else { state } }
+    }
+}
+impl Rng for XorShiftRng {
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 24) as u8
+    }
+}
+
+
+/// Follows the VIP interpreter, which keeps its random state in R9: the low byte counts
+/// instruction fetches and CXNN folds it into the high byte, which becomes the result.
+/// Sequences depend on how many instructions ran between calls, just like on the real
+/// machine.
+pub struct VipRng {
+    r9: u16,
+}
+impl VipRng {
+    pub fn new(seed: u64) -> VipRng {
+        VipRng { r9: seed as u16 }
+    }
+}
+impl Rng for VipRng {
+    fn next_byte(&mut self) -> u8 {
+        let lo = self.r9 as u8;
+        let hi = ((self.r9 >> 8) as u8).wrapping_add(lo).wrapping_add(1);
+        self.r9 = (hi as u16) << 8 | lo as u16;
+        hi
+    }
+
+    fn tick(&mut self) {
+        let lo = (self.r9 as u8).wrapping_add(1);
+        self.r9 = self.r9 & 0xFF00 | lo as u16;
+    }
+}
+
+
+#[test]
+fn test_same_seed_same_stream() {
+    for kind in &[RngKind::XorShift, RngKind::Vip] {
+        let mut a = new_rng(*kind, 1234);
+        let mut b = new_rng(*kind, 1234);
+        for _ in 0..1000 {
+            a.tick();
+            b.tick();
+            assert_eq!(a.next_byte(), b.next_byte());
+        }
+    }
+}
+
+#[test]
+fn test_vip_depends_on_fetches() {
+    let mut a = VipRng::new(0);
+    let mut b = VipRng::new(0);
+    b.tick();
+    assert!(a.next_byte() != b.next_byte());
+}
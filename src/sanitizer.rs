@@ -0,0 +1,237 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use disasm::{decode, Instruction};
+use machine::{Machine, Access, MEMORY_SIZE};
+use symbols::Symbols;
+
+/// Calls a program can have in progress before the stack overflows.
+const STACK_DEPTH: usize = 16;
+/// Where programs are loaded; below it are the interpreter and the font.
+const PROGRAM_START: u16 = 0x200;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SanitizePolicy {
+    /// Print each problem once per address and keep running.
+    Warn,
+    /// Stop before the instruction runs, like a breakpoint.
+    Break,
+}
+
+impl SanitizePolicy {
+    pub fn from_name(name: &str) -> Option<SanitizePolicy> {
+        match name {
+            "warn" => Some(SanitizePolicy::Warn),
+            "break" => Some(SanitizePolicy::Break),
+            _ => None,
+        }
+    }
+}
+
+/// A likely bug in the program, found just before the instruction that has it runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Issue {
+    /// A CALL with this many calls already in progress.
+    StackOverflow(usize),
+    /// A RET outside any subroutine.
+    StackUnderflow,
+    /// A read of a byte that was neither loaded nor written.
+    UninitializedRead(u16),
+    /// A write into the interpreter and font area.
+    WriteBelowProgram(u16),
+    /// Executing bytes that DXYN has drawn as sprite data.
+    ExecutesSpriteData,
+    /// A jump or call to an odd address.
+    OddJump(u16),
+    /// FX55, FX65, FX33 or DXYN using `len` bytes at I that run past the end of memory.
+    PointerOutOfRange(u16, u16),
+}
+
+impl Issue {
+    /// Names the kind of problem, so each is only warned about once per address.
+    fn kind(&self) -> &'static str {
+        match *self {
+            Issue::StackOverflow(_) => "stack overflow",
+            Issue::StackUnderflow => "stack underflow",
+            Issue::UninitializedRead(_) => "uninitialized read",
+            Issue::WriteBelowProgram(_) => "write below program",
+            Issue::ExecutesSpriteData => "executes sprite data",
+            Issue::OddJump(_) => "odd jump",
+            Issue::PointerOutOfRange(..) => "pointer out of range",
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Issue::StackOverflow(depth) => {
+                write!(f, "CALL with {} calls in progress overflows the stack", depth)
+            }
+            Issue::StackUnderflow => write!(f, "RET with no subroutine to return from"),
+            Issue::UninitializedRead(addr) => {
+                write!(f, "reads {:#05X}, which was never loaded or written", addr)
+            }
+            Issue::WriteBelowProgram(addr) => {
+                write!(f, "writes {:#05X}, in the interpreter and font area", addr)
+            }
+            Issue::ExecutesSpriteData => write!(f, "executes bytes that were drawn as a sprite"),
+            Issue::OddJump(target) => write!(f, "jumps to odd address {:#05X}", target),
+            Issue::PointerOutOfRange(addr, len) => {
+                write!(f, "uses {} bytes at I={:#05X}, past the end of memory", len, addr)
+            }
+        }
+    }
+}
+
+
+/// Checks each instruction for common CHIP-8 program bugs before it runs, and keeps track
+/// of which memory has been written and drawn from to do so.
+pub struct Sanitizer {
+    policy: SanitizePolicy,
+    /// Bytes that were loaded or have been written since.
+    written: Vec<bool>,
+    /// Bytes DXYN has read as sprite data.
+    drawn: Vec<bool>,
+    /// Problems already warned about, by address and kind.
+    warned: HashSet<(u16, &'static str)>,
+    symbols: Arc<Symbols>,
+}
+
+impl Sanitizer {
+    pub fn new(policy: SanitizePolicy, program_len: usize, symbols: Arc<Symbols>) -> Sanitizer {
+        let loaded = (PROGRAM_START as usize + program_len).min(MEMORY_SIZE);
+        Sanitizer {
+            policy: policy,
+            written: (0..MEMORY_SIZE).map(|addr| addr < loaded).collect(),
+            drawn: vec![false; MEMORY_SIZE],
+            warned: HashSet::new(),
+            symbols: symbols,
+        }
+    }
+
+    /// The first problem with running the instruction at `pc` now, if there is one.
+    pub fn check(&self, machine: &mut Machine, pc: u16, codeword: u16) -> Option<Issue> {
+        let drawn = |addr: u16| self.drawn.get(addr as usize).cloned().unwrap_or(false);
+        if drawn(pc) || drawn(pc.wrapping_add(1)) {
+            return Some(Issue::ExecutesSpriteData);
+        }
+
+        let instruction = decode(codeword);
+        let depth = machine.call_stack().len();
+        let target = match instruction {
+            Instruction::Call(_) if depth >= STACK_DEPTH => {
+                return Some(Issue::StackOverflow(depth))
+            }
+            Instruction::Ret if depth == 0 => return Some(Issue::StackUnderflow),
+            Instruction::Jump(nnn) | Instruction::Call(nnn) => Some(nnn),
+            Instruction::JumpV0(nnn) => Some(nnn.wrapping_add(machine.register(0) as u16)),
+            _ => None,
+        };
+        if let Some(target) = target {
+            if target % 2 == 1 {
+                return Some(Issue::OddJump(target));
+            }
+        }
+
+        let access = match machine.memory_access(instruction) {
+            Some(access) => access,
+            None => return None,
+        };
+        let end = access.addr as usize + access.len as usize;
+        match access.access {
+            Access::Pointer => None,
+            _ if end > MEMORY_SIZE => Some(Issue::PointerOutOfRange(access.addr, access.len)),
+            Access::Write if access.addr < PROGRAM_START => {
+                Some(Issue::WriteBelowProgram(access.addr))
+            }
+            Access::Write => None,
+            Access::Read => {
+                (access.addr as usize..end)
+                    .find(|&addr| !self.written[addr])
+                    .map(|addr| Issue::UninitializedRead(addr as u16))
+            }
+        }
+    }
+
+    /// What gets printed about a problem: where it is and the call stack, innermost first.
+    fn report(&self, machine: &Machine, pc: u16, issue: Issue) -> String {
+        let mut text = format!("sanitizer: {} at {}", issue, self.symbols.describe(pc));
+        for (depth, addr) in machine.call_stack().iter().enumerate().rev() {
+            text.push_str(&format!("\n  #{} return to {}", depth, self.symbols.describe(*addr)));
+        }
+        text
+    }
+
+    /// With the break policy, reports the problem with running the instruction at `pc` and
+    /// returns it so the machine can stop.
+    pub fn break_on(&self, machine: &mut Machine, pc: u16, codeword: u16) -> Option<Issue> {
+        if self.policy != SanitizePolicy::Break {
+            return None;
+        }
+        let issue = self.check(machine, pc, codeword);
+        if let Some(issue) = issue {
+            let _ = writeln!(io::stderr(), "{}", self.report(machine, pc, issue));
+        }
+        issue
+    }
+
+    /// Called by the machine before it runs each instruction. Warns about problems with
+    /// the warn policy, then notes the memory the instruction writes or draws.
+    pub fn record(&mut self, machine: &mut Machine, pc: u16, codeword: u16) {
+        if self.policy == SanitizePolicy::Warn {
+            if let Some(issue) = self.check(machine, pc, codeword) {
+                if self.warned.insert((pc, issue.kind())) {
+                    let _ = writeln!(io::stderr(), "{}", self.report(machine, pc, issue));
+                }
+            }
+        }
+
+        let instruction = decode(codeword);
+        if let Some(access) = machine.memory_access(instruction) {
+            let marks = match (access.access, instruction) {
+                (Access::Write, _) => &mut self.written,
+                (Access::Read, Instruction::Draw(..)) => &mut self.drawn,
+                _ => return,
+            };
+            let end = (access.addr as usize + access.len as usize).min(MEMORY_SIZE);
+            for addr in access.addr as usize..end {
+                marks[addr] = true;
+            }
+        }
+    }
+}
+
+
+#[test]
+fn test_sanitizer() {
+    use chip8::config::COSMAC_VIP;
+    use rng::{new_rng, RngKind};
+
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(RngKind::XorShift, 0));
+    let mut sanitizer = Sanitizer::new(SanitizePolicy::Warn, 4, Arc::new(Symbols::new()));
+    assert_eq!(sanitizer.check(&mut machine, 0x200, 0x00EE), Some(Issue::StackUnderflow));
+    assert_eq!(sanitizer.check(&mut machine, 0x200, 0x1203), Some(Issue::OddJump(0x203)));
+    assert_eq!(sanitizer.check(&mut machine, 0x200, 0x1204), None);
+
+    machine.set_i(0x100);
+    assert_eq!(sanitizer.check(&mut machine, 0x200, 0xF055),
+               Some(Issue::WriteBelowProgram(0x100)));
+    machine.set_i(0x202);
+    assert_eq!(sanitizer.check(&mut machine, 0x200, 0xF365),
+               Some(Issue::UninitializedRead(0x204)));
+    machine.set_i(0xFFE);
+    assert_eq!(sanitizer.check(&mut machine, 0x200, 0xF365),
+               Some(Issue::PointerOutOfRange(0xFFE, 4)));
+
+    machine.set_i(0x300);
+    sanitizer.record(&mut machine, 0x200, 0xF155);
+    sanitizer.record(&mut machine, 0x202, 0xD012);
+    assert_eq!(sanitizer.check(&mut machine, 0x204, 0xF165), None);
+    assert_eq!(sanitizer.check(&mut machine, 0x300, 0x1200),
+               Some(Issue::ExecutesSpriteData));
+    assert_eq!(sanitizer.break_on(&mut machine, 0x300, 0x1200), None);
+}
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::{self, Write};
+
+use framebuffer::{WIDTH, HEIGHT};
+
+
+/// Renders a frame as text, one line per row, `#` for lit pixels and `.` for dark ones.
+pub fn to_text(vram: &[u8]) -> String {
+    let mut text = String::with_capacity((WIDTH + 1) * HEIGHT);
+    for row in vram.chunks(WIDTH) {
+        for dot in row {
+            text.push(if *dot > 0 { '#' } else { '.' });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Saves a frame as a PNG if the path ends in `.png`, otherwise as text.
+pub fn save(path: &str, vram: &[u8]) -> io::Result<()> {
+    let mut file = try!(File::create(path));
+    if path.to_lowercase().ends_with(".png") {
+        file.write_all(&encode_png(vram))
+    } else {
+        file.write_all(to_text(vram).as_bytes())
+    }
+}
+
+
+/// Encodes a frame as an 8-bit grayscale PNG. The image data is small enough that it is
+/// written as a single uncompressed deflate block, so no compression library is needed.
+pub fn encode_png(vram: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((WIDTH + 1) * HEIGHT);
+    for row in vram.chunks(WIDTH) {
+        raw.push(0); // filter type: none
+        for dot in row {
+            raw.push(if *dot > 0 { 0xFF } else { 0x00 });
+        }
+    }
+
+    let mut ihdr = Vec::new();
+    push_u32(&mut ihdr, WIDTH as u32);
+    push_u32(&mut ihdr, HEIGHT as u32);
+    ihdr.extend_from_slice(&[8, 0, 0, 0, 0]); // depth 8, grayscale, deflate, no filter/interlace
+
+    let mut zlib = vec![0x78, 0x01];
+    zlib.push(0x01); // final block, stored
+    let len = raw.len() as u16;
+    zlib.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+    zlib.extend_from_slice(&raw);
+    push_u32(&mut zlib, adler32(&raw));
+
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    push_chunk(&mut png, b"IHDR", &ihdr);
+    push_chunk(&mut png, b"IDAT", &zlib);
+    push_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8,
+                            value as u8]);
+}
+
+fn push_chunk(out: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    push_u32(out, data.len() as u32);
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    push_u32(out, crc);
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for byte in bytes {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b"IEND"), 0xAE426082);
+}
+
+#[test]
+fn test_to_text() {
+    let mut vram = vec![0; WIDTH * HEIGHT];
+    vram[1] = 1;
+    let text = to_text(&vram);
+    assert_eq!(text.lines().count(), HEIGHT);
+    assert!(text.starts_with(".#...."));
+}
@@ -0,0 +1,357 @@
+//! Lua scripts that watch and drive a run, for bots and tests, loaded with `--script`.
+//! Scripting needs vipchip built with the `scripting` feature, which brings in Lua.
+//!
+//! A script defines any of these functions, which are called as things happen:
+//!
+//! * `on_frame(frame)` after each frame, with the number of frames run so far
+//! * `on_instruction(pc)` before the instruction at an address given to `hook_instruction`
+//! * `on_write(addr, len)` before an instruction writes `len` bytes of memory at `addr`
+//! * `on_key(key, pressed)` when a key on the keypad goes down or up
+//!
+//! and can call these:
+//!
+//! * `register(name)` and `set_register(name, value)`, with names like the debugger's:
+//!   `v0`-`vf`, `i`, `pc`, `dt` and `st`
+//! * `peek(addr)` and `poke(addr, value)`
+//! * `press(key)` and `release(key)`
+//! * `screenshot(path)`, which saves the screen as a PNG
+//! * `stop(code)`, which ends the run with `code` as the exit status
+//! * `hook_instruction(addr)` and `address(name)`, which looks up a symbol
+//!
+//! Reads see the machine as it was when the hook was called; changes are made once the hook
+//! returns.
+
+#[cfg(feature = "scripting")]
+use std::cell::RefCell;
+#[cfg(feature = "scripting")]
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+#[cfg(feature = "scripting")]
+use std::mem;
+#[cfg(feature = "scripting")]
+use std::rc::Rc;
+use std::sync::Arc;
+
+#[cfg(feature = "scripting")]
+use hlua::{self, Lua};
+use chip8::Keyboard;
+
+#[cfg(feature = "scripting")]
+use debugger::{parse_register, Register};
+#[cfg(feature = "scripting")]
+use disasm::decode;
+use machine::{Machine, Hook};
+#[cfg(feature = "scripting")]
+use machine::{Registers, Access, MEMORY_SIZE};
+#[cfg(feature = "scripting")]
+use screenshot;
+use symbols::Symbols;
+
+
+/// A change a script asked for, made once its hook returns.
+#[cfg(feature = "scripting")]
+enum Change {
+    Memory(u16, u8),
+    Register(Register, u16),
+    Key(usize, bool),
+    Screenshot(String),
+}
+
+/// What the functions a script calls work with.
+#[cfg(feature = "scripting")]
+struct State {
+    /// The machine as it was when the current hook was called.
+    registers: Registers,
+    memory: Vec<u8>,
+    changes: Vec<Change>,
+    /// Addresses `on_instruction` is called at.
+    hooked: HashSet<u16>,
+    stop: Option<i32>,
+}
+
+#[cfg(feature = "scripting")]
+pub struct Script {
+    lua: Lua<'static>,
+    state: Rc<RefCell<State>>,
+    /// Which hooks the script defines, so the others cost nothing.
+    on_frame: bool,
+    on_instruction: bool,
+    on_write: bool,
+    on_key: bool,
+}
+
+/// Without the scripting feature no script can be loaded, so there are none to call.
+#[cfg(not(feature = "scripting"))]
+pub enum Script {}
+
+
+#[cfg(feature = "scripting")]
+fn register_value(registers: &Registers, register: Register) -> u32 {
+    match register {
+        Register::V(x) => registers.v[x] as u32,
+        Register::I => registers.i as u32,
+        Register::Pc => registers.pc as u32,
+        Register::DelayTimer => registers.delay_timer as u32,
+        Register::SoundTimer => registers.sound_timer as u32,
+    }
+}
+
+/// Makes the functions scripts can call.
+#[cfg(feature = "scripting")]
+fn add_functions(lua: &mut Lua<'static>, state: &Rc<RefCell<State>>, symbols: Arc<Symbols>) {
+    let s = state.clone();
+    lua.set("register",
+            hlua::function1(move |name: String| {
+                parse_register(&name)
+                    .map(|register| register_value(&s.borrow().registers, register))
+            }));
+    let s = state.clone();
+    lua.set("set_register",
+            hlua::function2(move |name: String, value: u32| {
+                match parse_register(&name) {
+                    Some(register) => {
+                        s.borrow_mut().changes.push(Change::Register(register, value as u16))
+                    }
+                    None => println!("script: '{}' is not a register", name),
+                }
+            }));
+    let s = state.clone();
+    lua.set("peek",
+            hlua::function1(move |addr: u32| {
+                s.borrow().memory.get(addr as usize).cloned().unwrap_or(0) as u32
+            }));
+    let s = state.clone();
+    lua.set("poke",
+            hlua::function2(move |addr: u32, value: u32| {
+                if addr as usize >= MEMORY_SIZE {
+                    println!("script: {:#X} is outside memory", addr);
+                } else {
+                    s.borrow_mut().changes.push(Change::Memory(addr as u16, value as u8))
+                }
+            }));
+    let s = state.clone();
+    lua.set("press",
+            hlua::function1(move |key: u32| {
+                s.borrow_mut().changes.push(Change::Key(key as usize & 0xF, true))
+            }));
+    let s = state.clone();
+    lua.set("release",
+            hlua::function1(move |key: u32| {
+                s.borrow_mut().changes.push(Change::Key(key as usize & 0xF, false))
+            }));
+    let s = state.clone();
+    lua.set("screenshot",
+            hlua::function1(move |path: String| {
+                s.borrow_mut().changes.push(Change::Screenshot(path))
+            }));
+    let s = state.clone();
+    lua.set("stop", hlua::function1(move |code: i32| s.borrow_mut().stop = Some(code)));
+    let s = state.clone();
+    lua.set("hook_instruction",
+            hlua::function1(move |addr: u32| {
+                if addr as usize >= MEMORY_SIZE {
+                    println!("script: {:#X} is outside memory", addr);
+                } else {
+                    s.borrow_mut().hooked.insert(addr as u16);
+                }
+            }));
+    lua.set("address",
+            hlua::function1(move |name: String| {
+                symbols.parse_address(&name).ok().map(|addr| addr as u32)
+            }));
+}
+
+#[cfg(feature = "scripting")]
+fn apply(change: Change, machine: &mut Machine) {
+    match change {
+        Change::Memory(addr, value) => machine.write_byte(addr, value),
+        Change::Register(register, value) => machine.set_register_value(register, value),
+        Change::Key(key, pressed) => {
+            let mut keys = machine.keyboard();
+            keys[key] = pressed;
+            machine.set_keyboard(&keys);
+        }
+        Change::Screenshot(path) => {
+            let vram: Vec<u8> = machine.vram().iter().cloned().collect();
+            if let Err(e) = screenshot::save(&path, &vram) {
+                println!("script: {}: {}", path, e);
+            }
+        }
+    }
+}
+
+impl Script {
+    pub fn load(path: &str, symbols: Arc<Symbols>) -> Result<Script, String> {
+        let mut code = String::new();
+        try!(File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut code))
+            .map_err(|e| format!("{}: {}", path, e)));
+        Script::new(&code, symbols).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+#[cfg(feature = "scripting")]
+impl Script {
+    /// Runs the script's top level, which defines its hooks.
+    pub fn new(code: &str, symbols: Arc<Symbols>) -> Result<Script, String> {
+        let state = Rc::new(RefCell::new(State {
+            registers: Registers {
+                v: [0; 16],
+                i: 0,
+                pc: 0,
+                delay_timer: 0,
+                sound_timer: 0,
+                stack: Vec::new(),
+            },
+            memory: Vec::new(),
+            changes: Vec::new(),
+            hooked: HashSet::new(),
+            stop: None,
+        }));
+        let mut lua = Lua::new();
+        lua.openlibs();
+        add_functions(&mut lua, &state, symbols);
+        try!(lua.execute::<()>(code).map_err(|e| format!("{:?}", e)));
+
+        let mut script = Script {
+            lua: lua,
+            state: state,
+            on_frame: false,
+            on_instruction: false,
+            on_write: false,
+            on_key: false,
+        };
+        script.on_frame = script.defines("on_frame");
+        script.on_instruction = script.defines("on_instruction");
+        script.on_write = script.defines("on_write");
+        script.on_key = script.defines("on_key");
+        Ok(script)
+    }
+
+    fn defines(&mut self, name: &str) -> bool {
+        self.lua.execute::<bool>(&format!("return {} ~= nil", name)).unwrap_or(false)
+    }
+
+    fn run(&mut self, code: &str) -> Result<(), String> {
+        self.lua.execute::<()>(code).map_err(|e| format!("{:?}", e))
+    }
+
+    /// Runs Lua `code` that calls a hook, then makes the changes it asked for. An error
+    /// in a hook stops the run with status 1.
+    fn call(&mut self, machine: &mut Machine, code: &str) {
+        {
+            let mut state = self.state.borrow_mut();
+            state.registers = machine.registers();
+            state.memory = machine.read_memory(0, MEMORY_SIZE);
+        }
+        if let Err(e) = self.run(code) {
+            println!("script: {}", e);
+            self.state.borrow_mut().stop = Some(1);
+        }
+        let changes = mem::replace(&mut self.state.borrow_mut().changes, Vec::new());
+        for change in changes {
+            apply(change, machine);
+        }
+    }
+
+    /// Called by the emulator after each frame.
+    pub fn end_frame(&mut self, machine: &mut Machine, frame: u64) {
+        if self.on_frame {
+            self.call(machine, &format!("on_frame({})", frame));
+        }
+    }
+
+    /// Called by the emulator when the keypad changes from `before` to `after`.
+    pub fn keys_changed(&mut self, machine: &mut Machine, before: &Keyboard, after: &Keyboard) {
+        if !self.on_key {
+            return;
+        }
+        for key in 0..16 {
+            if before[key] != after[key] {
+                self.call(machine, &format!("on_key({}, {})", key, after[key]));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "scripting")]
+impl Hook for Script {
+    fn before(&mut self, machine: &mut Machine, pc: u16, codeword: u16) {
+        let hooked = self.on_instruction && self.state.borrow().hooked.contains(&pc);
+        if hooked {
+            self.call(machine, &format!("on_instruction({})", pc));
+        }
+        if self.on_write {
+            if let Some(access) = machine.memory_access(decode(codeword)) {
+                if access.access == Access::Write {
+                    self.call(machine, &format!("on_write({}, {})", access.addr, access.len));
+                }
+            }
+        }
+    }
+
+    fn stopped(&self) -> Option<i32> {
+        self.state.borrow().stop
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+impl Script {
+    pub fn new(_: &str, _: Arc<Symbols>) -> Result<Script, String> {
+        Err("vipchip was built without the scripting feature".to_string())
+    }
+
+    pub fn end_frame(&mut self, _: &mut Machine, _: u64) {
+        match *self {}
+    }
+
+    pub fn keys_changed(&mut self, _: &mut Machine, _: &Keyboard, _: &Keyboard) {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+impl Hook for Script {
+    fn before(&mut self, _: &mut Machine, _: u16, _: u16) {
+        match *self {}
+    }
+
+    fn stopped(&self) -> Option<i32> {
+        match *self {}
+    }
+}
+
+
+#[cfg(feature = "scripting")]
+#[test]
+fn test_script_hooks() {
+    use chip8::config::COSMAC_VIP;
+    use machine::{Stop, Halt};
+    use rng::{new_rng, RngKind};
+
+    let code = "hook_instruction(address('loop'))\n\
+                function on_instruction(pc) poke(0x300, register('v0') + 1) end\n\
+                function on_key(key, pressed) if pressed then press(key + 1) end end\n\
+                function on_frame(frame) if frame == 2 then stop(3) end end\n";
+    let symbols = Symbols::parse("loop = 0x202").unwrap();
+    let mut script = Script::new(code, Arc::new(symbols)).unwrap();
+
+    // V0 = 7, then loop forever.
+    let mut machine = Machine::new(COSMAC_VIP, new_rng(RngKind::XorShift, 0));
+    machine.load_program(&[0x60, 0x07, 0x12, 0x02]);
+    assert_eq!(machine.step_n_with(2, Some(&mut script)), None);
+    assert_eq!(machine.read_byte(0x300), 8);
+
+    let mut keys = [false; 16];
+    keys[4] = true;
+    machine.set_keyboard(&keys);
+    script.keys_changed(&mut machine, &[false; 16], &keys);
+    assert!(machine.keyboard()[5]);
+
+    script.end_frame(&mut machine, 1);
+    assert_eq!(machine.step_with(Some(&mut script)), None);
+    script.end_frame(&mut machine, 2);
+    assert_eq!(machine.step_with(Some(&mut script)),
+               Some(Stop::Halted(Halt::Script(0x202, 3))));
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+const SLOWEST_DIVISOR: u32 = 4;
+
+
+/// Decides how fast emulated frames are produced. Timers are ticked once per emulated frame,
+/// so changing speed never changes how many instructions run between ticks.
+pub struct SpeedControl {
+    paused: bool,
+    advance: bool,
+    fast_forward: bool,
+    /// Speed-up while fast-forwarding. 0 means as fast as possible.
+    ff_multiplier: u32,
+    /// Slow motion divisor: 1 is normal speed, 2 is half speed, 4 is quarter speed.
+    slow_divisor: u32,
+}
+
+impl SpeedControl {
+    pub fn new(ff_multiplier: u32) -> SpeedControl {
+        SpeedControl {
+            paused: false,
+            advance: false,
+            fast_forward: false,
+            ff_multiplier: ff_multiplier,
+            slow_divisor: 1,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    /// Requests exactly one frame while paused. Pauses first if running.
+    pub fn advance_frame(&mut self) {
+        self.paused = true;
+        self.advance = true;
+    }
+
+    /// Returns true once for every `advance_frame` call.
+    pub fn take_advance(&mut self) -> bool {
+        let advance = self.advance;
+        self.advance = false;
+        advance
+    }
+
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+    }
+
+    pub fn slower(&mut self) {
+        if self.slow_divisor < SLOWEST_DIVISOR {
+            self.slow_divisor *= 2;
+        }
+    }
+
+    pub fn faster(&mut self) {
+        if self.slow_divisor > 1 {
+            self.slow_divisor /= 2;
+        }
+    }
+
+    /// Wall-clock time between emulated frames, or `None` while paused.
+    pub fn frame_period(&self) -> Option<Duration> {
+        let normal = Duration::new(0, 1000000000 / 60);
+        if self.paused {
+            None
+        } else if self.fast_forward {
+            match self.ff_multiplier {
+                0 => Some(Duration::new(0, 0)),
+                n => Some(normal / n),
+            }
+        } else {
+            Some(normal * self.slow_divisor)
+        }
+    }
+
+    /// Text for the on-screen mode indicator, or `None` at normal speed.
+    pub fn label(&self) -> Option<String> {
+        if self.paused {
+            Some("PAUSED".to_string())
+        } else if self.fast_forward {
+            match self.ff_multiplier {
+                0 => Some("FF >>".to_string()),
+                n => Some(format!("FF {}X", n)),
+            }
+        } else if self.slow_divisor > 1 {
+            Some(format!("SLOW 1/{}", self.slow_divisor))
+        } else {
+            None
+        }
+    }
+}
+
+
+#[test]
+fn test_slow_motion_limits() {
+    let mut speed = SpeedControl::new(0);
+    speed.slower();
+    speed.slower();
+    speed.slower();
+    assert_eq!(speed.frame_period(), Some(Duration::new(0, 1000000000 / 60) * 4));
+    speed.faster();
+    speed.faster();
+    speed.faster();
+    assert_eq!(speed.label(), None);
+}
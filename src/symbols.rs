@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+
+use rustc_serialize::json::Json;
+
+use machine::MEMORY_SIZE;
+use options::parse_number;
+
+
+/// Names for program addresses, shown wherever an address is and accepted wherever one is
+/// typed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+    addresses: HashMap<String, u16>,
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '=' || c == '+') ||
+       name.starts_with(|c: char| c.is_digit(10)) {
+        Err(format!("'{}' isn't a valid symbol name", name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Addresses have to be inside memory; anything bigger is a mistake rather than one to wrap.
+fn check_address(n: u64) -> Result<u16, String> {
+    if n < MEMORY_SIZE as u64 {
+        Ok(n as u16)
+    } else {
+        Err(format!("{:#X} is outside memory", n))
+    }
+}
+
+fn json_address(value: &Json) -> Option<Result<u16, String>> {
+    match *value {
+        Json::U64(n) => Some(check_address(n)),
+        Json::I64(n) if n >= 0 => Some(check_address(n as u64)),
+        Json::I64(n) => Some(Err(format!("{} is outside memory", n))),
+        Json::String(ref text) => parse_number(text).ok().map(|n| check_address(n as u64)),
+        _ => None,
+    }
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols {
+            names: BTreeMap::new(),
+            addresses: HashMap::new(),
+        }
+    }
+
+    /// Adds a symbol. When several share an address, the first one is the one shown.
+    pub fn insert(&mut self, name: &str, addr: u16) -> Result<(), String> {
+        try!(check_name(name));
+        self.addresses.insert(name.to_string(), addr);
+        self.names.entry(addr).or_insert(name.to_string());
+        Ok(())
+    }
+
+    /// Parses a JSON object of names to addresses, such as Octo's label export (which keeps
+    /// them under `labels`), or lines of `name = ADDR` or Octo's `:const name ADDR`. Blank
+    /// lines and lines starting with `#` or `;` are skipped.
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        if text.trim_left().starts_with('{') {
+            let json = try!(Json::from_str(text).map_err(|e| e.to_string()));
+            let labels = json.find("labels").unwrap_or(&json);
+            let object = try!(labels.as_object().ok_or("expected an object of labels"));
+            for (name, value) in object {
+                let addr = try!(json_address(value)
+                    .ok_or(format!("{} doesn't have an address", name)));
+                let addr = try!(addr.map_err(|e| format!("{}: {}", name, e)));
+                try!(symbols.insert(name, addr));
+            }
+            return Ok(symbols);
+        }
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (name, value) = if words.len() == 3 && words[0] == ":const" {
+                (words[1], words[2])
+            } else {
+                let mut parts = line.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) => (name.trim(), value.trim()),
+                    _ => return Err(format!("line {}: expected 'name = ADDR'", n + 1)),
+                }
+            };
+            let addr = try!(parse_number(value)
+                .and_then(|addr| check_address(addr as u64))
+                .map_err(|e| format!("line {}: {}", n + 1, e)));
+            try!(symbols.insert(name, addr).map_err(|e| format!("line {}: {}", n + 1, e)));
+        }
+        Ok(symbols)
+    }
+
+    pub fn load(path: &str) -> Result<Symbols, String> {
+        let mut text = String::new();
+        try!(File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("{}: {}", path, e)));
+        Symbols::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Loads several symbol files into one table.
+    pub fn load_all(paths: &[String]) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for path in paths {
+            let loaded = try!(Symbols::load(path));
+            // The names shown go in first so they stay the ones shown.
+            for (&addr, name) in &loaded.names {
+                try!(symbols.insert(name, addr));
+            }
+            for (name, &addr) in &loaded.addresses {
+                try!(symbols.insert(name, addr));
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn name_of(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(|name| name.as_str())
+    }
+
+    /// The nearest symbol at or before `addr`, and how far past it `addr` is.
+    pub fn locate(&self, addr: u16) -> Option<(&str, u16)> {
+        self.names
+            .iter()
+            .rev()
+            .find(|&(&a, _)| a <= addr)
+            .map(|(&a, name)| (name.as_str(), addr - a))
+    }
+
+    /// The address in hex, followed by where it is relative to the nearest symbol, like
+    /// `0x2A6 <player_move+2>`.
+    pub fn describe(&self, addr: u16) -> String {
+        match self.locate(addr) {
+            Some((name, 0)) => format!("{:#05X} <{}>", addr, name),
+            Some((name, offset)) => format!("{:#05X} <{}+{}>", addr, name, offset),
+            None => format!("{:#05X}", addr),
+        }
+    }
+
+    /// Parses a number, a symbol name, or `name+OFFSET`. The address has to be inside memory.
+    pub fn parse_address(&self, text: &str) -> Result<u16, String> {
+        if let Ok(n) = parse_number(text) {
+            return check_address(n as u64);
+        }
+        let mut parts = text.splitn(2, '+');
+        let name = parts.next().unwrap_or("");
+        let offset = match parts.next() {
+            Some(offset) => try!(parse_number(offset)),
+            None => 0,
+        };
+        match self.addresses.get(name) {
+            Some(&addr) => check_address(addr as u64 + offset as u64),
+            None => Err(format!("'{}' is not a number or a known symbol", text)),
+        }
+    }
+}
+
+#[test]
+fn test_parse_symbols() {
+    let symbols = Symbols::parse("# labels\nmain = 0x200\n:const player_move 0x2A4\n\n").unwrap();
+    assert_eq!(symbols.name_of(0x2A4), Some("player_move"));
+    assert_eq!(symbols.parse_address("player_move+2"), Ok(0x2A6));
+    assert_eq!(symbols.parse_address("0x300"), Ok(0x300));
+    assert!(symbols.parse_address("enemy").is_err());
+    assert_eq!(symbols.parse_address("0x12345"), Err("0x12345 is outside memory".to_string()));
+    assert!(symbols.parse_address("main+0xE00").is_err());
+    assert_eq!(symbols.describe(0x2A6), "0x2A6 <player_move+2>");
+    assert_eq!(symbols.describe(0x1FE), "0x1FE");
+
+    let json = Symbols::parse("{\"labels\": {\"main\": 512, \"draw\": \"0x210\"}}").unwrap();
+    assert_eq!(json.name_of(0x210), Some("draw"));
+    assert!(Symbols::parse("2fast = 0x200").is_err());
+    assert_eq!(Symbols::parse("main = 0x1200"),
+               Err("line 1: 0x1200 is outside memory".to_string()));
+    assert!(Symbols::parse("{\"main\": 4096}").is_err());
+}
@@ -0,0 +1,366 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use disasm::{decode, symbolic};
+use hash::fnv1a;
+use machine::{Machine, Access, MEMORY_SIZE};
+use options::parse_number;
+use symbols::Symbols;
+
+/// Start of a binary trace, followed by a flags byte saying which columns it has.
+pub const BINARY_MAGIC: &'static [u8] = b"VCTR\x01";
+pub const FLAG_REGISTERS: u8 = 1;
+pub const FLAG_TIMERS: u8 = 2;
+pub const FLAG_MEMORY: u8 = 4;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// One line per instruction, with `key=value` columns.
+    Text,
+    /// Fixed size little endian records; see `TraceRecord::to_binary`.
+    Binary,
+}
+
+/// When to start or stop tracing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// The instruction at this address is about to run.
+    Pc(u16),
+    /// This many instructions have run.
+    Instructions(u64),
+}
+
+/// Matches codewords against a pattern like `DXYN` or `8XY4`: hex digits must be equal,
+/// other characters match anything.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpcodePattern {
+    mask: u16,
+    value: u16,
+}
+
+pub struct TraceOptions {
+    pub path: String,
+    pub format: TraceFormat,
+    pub registers: bool,
+    pub timers: bool,
+    /// Adds a hash of all memory, to spot where runs start writing different data.
+    pub memory: bool,
+    /// Only instructions at addresses in this inclusive range.
+    pub range: Option<(u16, u16)>,
+    /// Only instructions matching one of these, if there are any.
+    pub opcodes: Vec<OpcodePattern>,
+    pub start: Option<Trigger>,
+    pub stop: Option<Trigger>,
+}
+
+/// Writes executed instructions to a trace file. The register columns show the state
+/// before the instruction runs.
+pub struct Tracer {
+    options: TraceOptions,
+    out: BufWriter<File>,
+    active: bool,
+    /// The stop trigger has fired, so nothing more will be written.
+    done: bool,
+    /// Hash of memory, only recomputed once something has written to it.
+    memory_hash: Option<u64>,
+    symbols: Arc<Symbols>,
+}
+
+
+/// Parses `pc=ADDR` or `instr=N`.
+pub fn parse_trigger(text: &str) -> Result<Trigger, String> {
+    let mut parts = text.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some("pc"), Some(addr)) => parse_number(addr).map(|a| Trigger::Pc(a as u16)),
+        (Some("instr"), Some(n)) => parse_number(n).map(|n| Trigger::Instructions(n as u64)),
+        _ => Err(format!("unknown trigger '{}'", text)),
+    }
+}
+
+pub fn parse_opcode_pattern(text: &str) -> Result<OpcodePattern, String> {
+    if text.len() != 4 {
+        return Err(format!("opcode pattern '{}' should be 4 characters", text));
+    }
+    let mut pattern = OpcodePattern {
+        mask: 0,
+        value: 0,
+    };
+    for c in text.chars() {
+        pattern.mask <<= 4;
+        pattern.value <<= 4;
+        if let Some(digit) = c.to_digit(16) {
+            pattern.mask |= 0xF;
+            pattern.value |= digit as u16;
+        }
+    }
+    Ok(pattern)
+}
+
+/// Parses a comma separated list of opcode patterns.
+pub fn parse_opcode_patterns(text: &str) -> Result<Vec<OpcodePattern>, String> {
+    text.split(',').map(|p| parse_opcode_pattern(p.trim())).collect()
+}
+
+/// Parses an inclusive address range `START-END`.
+pub fn parse_address_range(text: &str) -> Result<(u16, u16), String> {
+    let mut parts = text.splitn(2, '-');
+    match (parts.next(), parts.next()) {
+        (Some(start), Some(end)) => {
+            let start = try!(parse_number(start)) as u16;
+            let end = try!(parse_number(end)) as u16;
+            if end < start {
+                Err(format!("range '{}' ends before it starts", text))
+            } else {
+                Ok((start, end))
+            }
+        }
+        _ => Err(format!("expected START-END, got '{}'", text)),
+    }
+}
+
+impl OpcodePattern {
+    pub fn matches(&self, codeword: u16) -> bool {
+        codeword & self.mask == self.value
+    }
+}
+
+impl Trigger {
+    fn fired(&self, pc: u16, instructions: u64) -> bool {
+        match *self {
+            Trigger::Pc(addr) => pc == addr,
+            Trigger::Instructions(n) => instructions >= n,
+        }
+    }
+}
+
+
+/// The columns of one traced instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    /// Number of instructions run before this one.
+    pub index: u64,
+    pub pc: u16,
+    pub codeword: u16,
+    /// V0-VF, I and call depth.
+    pub registers: Option<([u8; 16], u16, u8)>,
+    /// Delay and sound timers.
+    pub timers: Option<(u8, u8)>,
+    /// FNV-1a of the first 4K of memory.
+    pub memory: Option<u64>,
+}
+
+impl TraceRecord {
+    /// Jump, call and I load targets are shown by name when `symbols` has one.
+    pub fn to_text(&self, symbols: &Symbols) -> String {
+        let mut line = format!("{} {:03X}: {:04X}  {:<20}",
+                               self.index,
+                               self.pc,
+                               self.codeword,
+                               symbolic(decode(self.codeword), symbols));
+        if let Some((v, i, sp)) = self.registers {
+            let v: Vec<String> = v.iter().map(|b| format!("{:02X}", b)).collect();
+            line.push_str(&format!(" v={} i={:03X} sp={}", v.concat(), i, sp));
+        }
+        if let Some((dt, st)) = self.timers {
+            line.push_str(&format!(" dt={:02X} st={:02X}", dt, st));
+        }
+        if let Some(memory) = self.memory {
+            line.push_str(&format!(" mem={:016X}", memory));
+        }
+        line.trim_right().to_string()
+    }
+
+    /// index u64, pc u16, codeword u16, then V0-VF, I u16 and SP u8 if the trace has
+    /// registers, DT and ST if it has timers, then the memory hash as u64 if it has memory.
+    /// All little endian.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(33);
+        for n in 0..8 {
+            bytes.push((self.index >> (n * 8)) as u8);
+        }
+        bytes.extend_from_slice(&[self.pc as u8,
+                                  (self.pc >> 8) as u8,
+                                  self.codeword as u8,
+                                  (self.codeword >> 8) as u8]);
+        if let Some((v, i, sp)) = self.registers {
+            bytes.extend_from_slice(&v);
+            bytes.extend_from_slice(&[i as u8, (i >> 8) as u8, sp]);
+        }
+        if let Some((dt, st)) = self.timers {
+            bytes.extend_from_slice(&[dt, st]);
+        }
+        if let Some(memory) = self.memory {
+            for n in 0..8 {
+                bytes.push((memory >> (n * 8)) as u8);
+            }
+        }
+        bytes
+    }
+}
+
+
+impl Tracer {
+    pub fn create(options: TraceOptions, symbols: Arc<Symbols>) -> Result<Tracer, String> {
+        let file = try!(File::create(&options.path)
+            .map_err(|e| format!("{}: {}", options.path, e)));
+        let mut out = BufWriter::new(file);
+        if options.format == TraceFormat::Binary {
+            let mut flags = 0;
+            if options.registers {
+                flags |= FLAG_REGISTERS;
+            }
+            if options.timers {
+                flags |= FLAG_TIMERS;
+            }
+            if options.memory {
+                flags |= FLAG_MEMORY;
+            }
+            try!(out.write_all(BINARY_MAGIC)
+                .and_then(|_| out.write_all(&[flags]))
+                .map_err(|e| e.to_string()));
+        }
+        Ok(Tracer {
+            active: options.start.is_none(),
+            done: false,
+            memory_hash: None,
+            options: options,
+            out: out,
+            symbols: symbols,
+        })
+    }
+
+    fn wants(&self, pc: u16, codeword: u16) -> bool {
+        let in_range = match self.options.range {
+            Some((start, end)) => pc >= start && pc <= end,
+            None => true,
+        };
+        let opcodes = &self.options.opcodes;
+        in_range && (opcodes.is_empty() || opcodes.iter().any(|p| p.matches(codeword)))
+    }
+
+    /// Called by the machine before it runs each instruction.
+    pub fn record(&mut self, machine: &mut Machine, pc: u16, codeword: u16) {
+        if self.done {
+            return;
+        }
+        let writes = self.options.memory &&
+                     match machine.memory_access(decode(codeword)) {
+            Some(access) => access.access == Access::Write,
+            None => false,
+        };
+        self.record_instruction(machine, pc, codeword);
+        if writes {
+            self.memory_hash = None;
+        }
+    }
+
+    /// Called by the machine when memory changes other than by an instruction, such as
+    /// from a debugger or a script.
+    pub fn memory_changed(&mut self) {
+        self.memory_hash = None;
+    }
+
+    fn record_instruction(&mut self, machine: &mut Machine, pc: u16, codeword: u16) {
+        let index = machine.instructions();
+        if !self.active {
+            match self.options.start {
+                Some(trigger) if trigger.fired(pc, index) => self.active = true,
+                _ => return,
+            }
+        }
+        if let Some(trigger) = self.options.stop {
+            if trigger.fired(pc, index) {
+                self.done = true;
+                self.flush();
+                return;
+            }
+        }
+        if !self.wants(pc, codeword) {
+            return;
+        }
+
+        let registers = if self.options.registers {
+            let mut v = [0; 16];
+            for x in 0..16 {
+                v[x] = machine.register(x);
+            }
+            Some((v, machine.i(), machine.call_stack().len() as u8))
+        } else {
+            None
+        };
+        let timers = if self.options.timers {
+            Some((machine.delay_timer(), machine.sound_timer()))
+        } else {
+            None
+        };
+        let memory = if self.options.memory {
+            let hash = match self.memory_hash {
+                Some(hash) => hash,
+                None => fnv1a(&machine.read_memory(0, MEMORY_SIZE)),
+            };
+            self.memory_hash = Some(hash);
+            Some(hash)
+        } else {
+            None
+        };
+        let record = TraceRecord {
+            index: index,
+            pc: pc,
+            codeword: codeword,
+            registers: registers,
+            timers: timers,
+            memory: memory,
+        };
+        let written = match self.options.format {
+            TraceFormat::Text => writeln!(self.out, "{}", record.to_text(&self.symbols)),
+            TraceFormat::Binary => self.out.write_all(&record.to_binary()),
+        };
+        if let Err(e) = written {
+            println!("trace: {}", e);
+            self.done = true;
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.out.flush() {
+            println!("trace: {}", e);
+        }
+    }
+}
+
+#[test]
+fn test_opcode_pattern() {
+    let draw = parse_opcode_pattern("DXYN").unwrap();
+    assert!(draw.matches(0xD125));
+    assert!(!draw.matches(0xA125));
+    let add = parse_opcode_pattern("8xy4").unwrap();
+    assert!(add.matches(0x8AB4));
+    assert!(!add.matches(0x8AB5));
+    assert!(parse_opcode_pattern("DXY").is_err());
+}
+
+#[test]
+fn test_parse_trigger() {
+    assert_eq!(parse_trigger("pc=0x2A4"), Ok(Trigger::Pc(0x2A4)));
+    assert_eq!(parse_trigger("instr=1000"), Ok(Trigger::Instructions(1000)));
+    assert!(parse_trigger("frame=3").is_err());
+    assert_eq!(parse_address_range("0x200-0x2FF"), Ok((0x200, 0x2FF)));
+}
+
+#[test]
+fn test_record_to_text() {
+    let record = TraceRecord {
+        index: 7,
+        pc: 0x200,
+        codeword: 0x6A0F,
+        registers: Some(([0; 16], 0x300, 1)),
+        timers: Some((5, 0)),
+        memory: None,
+    };
+    assert_eq!(record.to_text(&Symbols::new()),
+               "7 200: 6A0F  LD VA, 0x0F          v=00000000000000000000000000000000 i=300 \
+                sp=1 dt=05 st=00");
+    assert_eq!(record.to_binary().len(), 8 + 4 + 19 + 2);
+}
@@ -0,0 +1,350 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::num::ParseIntError;
+
+use symbols::Symbols;
+use trace::{TraceRecord, BINARY_MAGIC, FLAG_REGISTERS, FLAG_TIMERS, FLAG_MEMORY};
+
+
+/// Reads records back from a text or binary trace file.
+pub struct TraceReader<R: BufRead> {
+    input: R,
+    /// Column flags of a binary trace, or `None` for a text trace.
+    binary: Option<u8>,
+    line: usize,
+}
+
+/// Result of comparing two traces.
+pub struct Comparison {
+    /// Number of records that matched.
+    pub matched: u64,
+    /// Report of the first difference with surrounding records, if there is one.
+    pub divergence: Option<String>,
+}
+
+
+pub fn open(path: &str) -> Result<TraceReader<BufReader<File>>, String> {
+    let file = try!(File::open(path).map_err(|e| format!("{}: {}", path, e)));
+    TraceReader::new(BufReader::new(file)).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn parse_hex<T>(key: &str,
+                text: &str,
+                parse: fn(&str, u32) -> Result<T, ParseIntError>)
+                -> Result<T, String> {
+    parse(text, 16).map_err(|_| format!("bad value for {}: '{}'", key, text))
+}
+
+/// Parses a line written by `TraceRecord::to_text`.
+pub fn parse_text_record(line: &str) -> Result<TraceRecord, String> {
+    let mut tokens = line.split_whitespace();
+    let index = try!(tokens.next()
+        .and_then(|t| t.parse::<u64>().ok())
+        .ok_or("missing instruction count".to_string()));
+    let pc = try!(tokens.next()
+        .map(|t| t.trim_right_matches(':'))
+        .ok_or("missing address".to_string())
+        .and_then(|t| parse_hex("address", t, u16::from_str_radix)));
+    let codeword = try!(tokens.next()
+        .ok_or("missing codeword".to_string())
+        .and_then(|t| parse_hex("codeword", t, u16::from_str_radix)));
+
+    let (mut v, mut i, mut sp, mut dt, mut st, mut memory) = (None, None, None, None, None, None);
+    // The mnemonic comes next; its words never contain '='.
+    for token in tokens.filter(|t| t.contains('=')) {
+        let mut parts = token.splitn(2, '=');
+        let key = parts.next().unwrap();
+        let value = parts.next().unwrap();
+        match key {
+            "v" => {
+                if value.len() != 32 || value.chars().count() != 32 {
+                    return Err(format!("bad value for v: '{}'", value));
+                }
+                let mut registers = [0; 16];
+                for x in 0..16 {
+                    let digits = &value[x * 2..x * 2 + 2];
+                    registers[x] = try!(parse_hex("v", digits, u8::from_str_radix));
+                }
+                v = Some(registers);
+            }
+            "i" => i = Some(try!(parse_hex("i", value, u16::from_str_radix))),
+            "sp" => {
+                sp = Some(try!(value.parse::<u8>()
+                    .map_err(|_| format!("bad value for sp: '{}'", value))))
+            }
+            "dt" => dt = Some(try!(parse_hex("dt", value, u8::from_str_radix))),
+            "st" => st = Some(try!(parse_hex("st", value, u8::from_str_radix))),
+            "mem" => memory = Some(try!(parse_hex("mem", value, u64::from_str_radix))),
+            _ => return Err(format!("unknown column '{}'", key)),
+        }
+    }
+
+    Ok(TraceRecord {
+        index: index,
+        pc: pc,
+        codeword: codeword,
+        registers: match (v, i, sp) {
+            (Some(v), Some(i), Some(sp)) => Some((v, i, sp)),
+            (None, None, None) => None,
+            _ => return Err("v, i and sp must appear together".to_string()),
+        },
+        timers: match (dt, st) {
+            (Some(dt), Some(st)) => Some((dt, st)),
+            (None, None) => None,
+            _ => return Err("dt and st must appear together".to_string()),
+        },
+        memory: memory,
+    })
+}
+
+fn le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, &b| n << 8 | b as u64)
+}
+
+/// Parses one record of a binary trace with the given column flags.
+pub fn parse_binary_record(flags: u8, bytes: &[u8]) -> TraceRecord {
+    let mut record = TraceRecord {
+        index: le(&bytes[0..8]),
+        pc: le(&bytes[8..10]) as u16,
+        codeword: le(&bytes[10..12]) as u16,
+        registers: None,
+        timers: None,
+        memory: None,
+    };
+    let mut at = 12;
+    if flags & FLAG_REGISTERS != 0 {
+        let mut v = [0; 16];
+        v.copy_from_slice(&bytes[at..at + 16]);
+        record.registers = Some((v, le(&bytes[at + 16..at + 18]) as u16, bytes[at + 18]));
+        at += 19;
+    }
+    if flags & FLAG_TIMERS != 0 {
+        record.timers = Some((bytes[at], bytes[at + 1]));
+        at += 2;
+    }
+    if flags & FLAG_MEMORY != 0 {
+        record.memory = Some(le(&bytes[at..at + 8]));
+    }
+    record
+}
+
+fn binary_record_size(flags: u8) -> usize {
+    let mut size = 12;
+    if flags & FLAG_REGISTERS != 0 {
+        size += 19;
+    }
+    if flags & FLAG_TIMERS != 0 {
+        size += 2;
+    }
+    if flags & FLAG_MEMORY != 0 {
+        size += 8;
+    }
+    size
+}
+
+impl<R: BufRead> TraceReader<R> {
+    /// Checks for the binary header; anything else is read as text.
+    pub fn new(mut input: R) -> Result<TraceReader<R>, String> {
+        let binary = {
+            let start = try!(input.fill_buf().map_err(|e| e.to_string()));
+            start.starts_with(BINARY_MAGIC)
+        };
+        let flags = if binary {
+            let mut header = [0; 6];
+            try!(input.read_exact(&mut header).map_err(|e| e.to_string()));
+            Some(header[5])
+        } else {
+            None
+        };
+        Ok(TraceReader {
+            input: input,
+            binary: flags,
+            line: 0,
+        })
+    }
+
+    /// The next record, or `None` at the end of the trace.
+    pub fn next_record(&mut self) -> Result<Option<TraceRecord>, String> {
+        match self.binary {
+            Some(flags) => {
+                let mut bytes = vec![0; binary_record_size(flags)];
+                let mut read = 0;
+                while read < bytes.len() {
+                    match try!(self.input.read(&mut bytes[read..]).map_err(|e| e.to_string())) {
+                        0 if read == 0 => return Ok(None),
+                        0 => return Err("trace ends in the middle of a record".to_string()),
+                        n => read += n,
+                    }
+                }
+                Ok(Some(parse_binary_record(flags, &bytes)))
+            }
+            None => {
+                loop {
+                    let mut line = String::new();
+                    if try!(self.input.read_line(&mut line).map_err(|e| e.to_string())) == 0 {
+                        return Ok(None);
+                    }
+                    self.line += 1;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    return parse_text_record(&line)
+                        .map(Some)
+                        .map_err(|e| format!("line {}: {}", self.line, e));
+                }
+            }
+        }
+    }
+}
+
+
+/// Lists the columns that differ, comparing only the columns both records have.
+pub fn differences(a: &TraceRecord, b: &TraceRecord) -> Vec<String> {
+    let mut found = Vec::new();
+    if a.index != b.index {
+        found.push(format!("instruction count: {} vs {}", a.index, b.index));
+    }
+    if a.pc != b.pc {
+        found.push(format!("pc: {:03X} vs {:03X}", a.pc, b.pc));
+    }
+    if a.codeword != b.codeword {
+        found.push(format!("codeword: {:04X} vs {:04X}", a.codeword, b.codeword));
+    }
+    if let (Some((va, ia, spa)), Some((vb, ib, spb))) = (a.registers, b.registers) {
+        for x in 0..16 {
+            if va[x] != vb[x] {
+                found.push(format!("v{:X}: {:02X} vs {:02X}", x, va[x], vb[x]));
+            }
+        }
+        if ia != ib {
+            found.push(format!("i: {:03X} vs {:03X}", ia, ib));
+        }
+        if spa != spb {
+            found.push(format!("sp: {} vs {}", spa, spb));
+        }
+    }
+    if let (Some((dta, sta)), Some((dtb, stb))) = (a.timers, b.timers) {
+        if dta != dtb {
+            found.push(format!("dt: {:02X} vs {:02X}", dta, dtb));
+        }
+        if sta != stb {
+            found.push(format!("st: {:02X} vs {:02X}", sta, stb));
+        }
+    }
+    if let (Some(ma), Some(mb)) = (a.memory, b.memory) {
+        if ma != mb {
+            found.push(format!("memory: {:016X} vs {:016X}", ma, mb));
+        }
+    }
+    found
+}
+
+fn read_after<R: BufRead>(trace: &mut TraceReader<R>,
+                          context: usize,
+                          prefix: &str,
+                          symbols: &Symbols,
+                          report: &mut String) {
+    for _ in 0..context {
+        match trace.next_record() {
+            Ok(Some(record)) => {
+                report.push_str(&format!("{} {}\n", prefix, record.to_text(symbols)))
+            }
+            _ => break,
+        }
+    }
+}
+
+/// Reads both traces until the first record that differs. The report shows up to
+/// `context` matching records before it, then the rest of each trace after it in the style
+/// of a unified diff, with the records shown using `symbols`.
+pub fn compare<R: BufRead, S: BufRead>(a: &mut TraceReader<R>,
+                                       b: &mut TraceReader<S>,
+                                       context: usize,
+                                       symbols: &Symbols)
+                                       -> Result<Comparison, String> {
+    let mut before = VecDeque::with_capacity(context + 1);
+    let mut matched = 0;
+    loop {
+        let ra = try!(a.next_record().map_err(|e| format!("first trace: {}", e)));
+        let rb = try!(b.next_record().map_err(|e| format!("second trace: {}", e)));
+        let (summary, ra, rb) = match (ra, rb) {
+            (None, None) => {
+                return Ok(Comparison {
+                    matched: matched,
+                    divergence: None,
+                })
+            }
+            (Some(ra), Some(rb)) => {
+                let found = differences(&ra, &rb);
+                if found.is_empty() {
+                    matched += 1;
+                    before.push_back(ra);
+                    if before.len() > context {
+                        before.pop_front();
+                    }
+                    continue;
+                }
+                (found.join("\n  "), Some(ra), Some(rb))
+            }
+            (None, rb) => ("first trace ends here".to_string(), None, rb),
+            (ra, None) => ("second trace ends here".to_string(), ra, None),
+        };
+
+        let mut report = format!("traces differ after {} matching records:\n  {}\n\n",
+                                 matched,
+                                 summary);
+        for record in &before {
+            report.push_str(&format!("  {}\n", record.to_text(symbols)));
+        }
+        if let Some(ra) = ra {
+            report.push_str(&format!("- {}\n", ra.to_text(symbols)));
+            read_after(a, context, "-", symbols, &mut report);
+        }
+        if let Some(rb) = rb {
+            report.push_str(&format!("+ {}\n", rb.to_text(symbols)));
+            read_after(b, context, "+", symbols, &mut report);
+        }
+        return Ok(Comparison {
+            matched: matched,
+            divergence: Some(report),
+        });
+    }
+}
+
+#[test]
+fn test_parse_records() {
+    let record = TraceRecord {
+        index: 42,
+        pc: 0x2A4,
+        codeword: 0xD125,
+        registers: Some(([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 0xFF], 0x3F0, 2)),
+        timers: Some((0x3C, 0)),
+        memory: Some(0x0123456789ABCDEF),
+    };
+    assert_eq!(parse_text_record(&record.to_text(&Symbols::new())), Ok(record.clone()));
+    assert_eq!(parse_binary_record(FLAG_REGISTERS | FLAG_TIMERS | FLAG_MEMORY,
+                                   &record.to_binary()),
+               record);
+    assert!(parse_text_record("3 200: 00E0  CLS i=200").is_err());
+}
+
+#[test]
+fn test_compare() {
+    use std::io::Cursor;
+    let a = "0 200: 6001  LD V0, 0x01\n1 202: 7001  ADD V0, 0x01\n2 204: 1204  JP 0x204\n";
+    let b = "0 200: 6001  LD V0, 0x01\n1 202: 7002  ADD V0, 0x02\n2 204: 1204  JP 0x204\n";
+    let mut ra = TraceReader::new(Cursor::new(a)).unwrap();
+    let mut rb = TraceReader::new(Cursor::new(b)).unwrap();
+    let comparison = compare(&mut ra, &mut rb, 1, &Symbols::new()).unwrap();
+    assert_eq!(comparison.matched, 1);
+    let report = comparison.divergence.unwrap();
+    assert!(report.contains("codeword: 7001 vs 7002"));
+    assert!(report.contains("- 2 204: 1204"));
+
+    let mut ra = TraceReader::new(Cursor::new(a)).unwrap();
+    let short = "0 200: 6001  LD V0, 0x01\n1 202: 7001  ADD V0, 0x01\n";
+    let mut rb = TraceReader::new(Cursor::new(short)).unwrap();
+    let report = compare(&mut ra, &mut rb, 1, &Symbols::new()).unwrap().divergence.unwrap();
+    assert!(report.contains("second trace ends here"));
+}
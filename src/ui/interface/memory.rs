@@ -0,0 +1,120 @@
+//! State of the memory panel: which part of memory is shown, the edit cursor, and how
+//! recently each byte changed.
+
+/// Bytes per row of the memory panel.
+pub const ROW_BYTES: u16 = 8;
+
+/// Number of draws a changed byte stays highlighted for.
+const FLASH_DRAWS: u8 = 30;
+
+
+pub struct MemoryEditor {
+    pub cursor: u16,
+    /// Address of the first row shown.
+    pub top: u16,
+    /// Typing hex digits changes the byte under the cursor instead of pressing keypad keys.
+    pub editing: bool,
+    /// The high nibble typed so far, if any.
+    pending: Option<u8>,
+    last_memory: Vec<u8>,
+    /// Draws left to highlight each byte for since it last changed.
+    flash: Vec<u8>,
+}
+
+impl MemoryEditor {
+    pub fn new() -> MemoryEditor {
+        MemoryEditor {
+            cursor: 0x200,
+            top: 0x200,
+            editing: false,
+            pending: None,
+            last_memory: Vec::new(),
+            flash: Vec::new(),
+        }
+    }
+
+    /// Notes which bytes changed since the last draw.
+    pub fn update(&mut self, memory: &[u8]) {
+        if self.last_memory.len() != memory.len() {
+            self.last_memory = memory.to_vec();
+            self.flash = vec![0; memory.len()];
+            return;
+        }
+        for (addr, &byte) in memory.iter().enumerate() {
+            if self.last_memory[addr] != byte {
+                self.last_memory[addr] = byte;
+                self.flash[addr] = FLASH_DRAWS;
+            } else if self.flash[addr] > 0 {
+                self.flash[addr] -= 1;
+            }
+        }
+    }
+
+    /// How recently the byte changed, from 1.0 for just now down to 0.0.
+    pub fn flash(&self, addr: u16) -> f32 {
+        self.flash.get(addr as usize).map(|&n| n as f32 / FLASH_DRAWS as f32).unwrap_or(0.0)
+    }
+
+    /// Moves the cursor by `delta` bytes, staying inside `size` bytes of memory, and
+    /// scrolls so that it stays within `rows` rows.
+    pub fn move_cursor(&mut self, delta: i32, size: usize, rows: usize) {
+        let cursor = (self.cursor as i32 + delta).max(0).min(size as i32 - 1);
+        self.cursor = cursor as u16;
+        self.pending = None;
+
+        let row = self.cursor - self.cursor % ROW_BYTES;
+        let shown = rows as u16 * ROW_BYTES;
+        if row < self.top {
+            self.top = row;
+        } else if row >= self.top + shown {
+            self.top = row + ROW_BYTES - shown;
+        }
+    }
+
+    pub fn toggle_editing(&mut self) {
+        self.editing = !self.editing;
+        self.pending = None;
+    }
+
+    /// Takes a typed hex digit. The second digit of a byte returns the address and value to
+    /// write, and moves on to the next byte.
+    pub fn type_digit(&mut self, digit: u8, size: usize, rows: usize) -> Option<(u16, u8)> {
+        match self.pending.take() {
+            None => {
+                self.pending = Some(digit);
+                None
+            }
+            Some(high) => {
+                let addr = self.cursor;
+                self.move_cursor(1, size, rows);
+                Some((addr, high << 4 | digit))
+            }
+        }
+    }
+
+    /// The high nibble typed so far at the cursor.
+    pub fn pending(&self) -> Option<u8> {
+        self.pending
+    }
+}
+
+#[test]
+fn test_memory_editor() {
+    let mut editor = MemoryEditor::new();
+    editor.move_cursor(-(ROW_BYTES as i32), 0x1000, 4);
+    assert_eq!((editor.cursor, editor.top), (0x1F8, 0x1F8));
+    editor.move_cursor(4 * ROW_BYTES as i32, 0x1000, 4);
+    assert_eq!((editor.cursor, editor.top), (0x218, 0x200));
+    editor.move_cursor(0x2000, 0x1000, 4);
+    assert_eq!(editor.cursor, 0xFFF);
+
+    editor.cursor = 0x300;
+    assert_eq!(editor.type_digit(0xA, 0x1000, 4), None);
+    assert_eq!(editor.type_digit(0x5, 0x1000, 4), Some((0x300, 0xA5)));
+    assert_eq!(editor.cursor, 0x301);
+
+    editor.update(&[0, 0, 0]);
+    editor.update(&[0, 7, 0]);
+    assert_eq!(editor.flash(1), 1.0);
+    assert_eq!(editor.flash(0), 0.0);
+}
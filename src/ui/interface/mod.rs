@@ -1,3 +1,10 @@
+mod font;
+mod memory;
+mod panels;
+mod sprites;
+
+use std::sync::Arc;
+
 use sdl2;
 use sdl2::Sdl;
 use sdl2::render::Renderer;
@@ -6,7 +13,14 @@ use sdl2::pixels::Color;
 use sdl2::event::Event;
 use sdl2::keyboard::Scancode;
 
-use chip8::{Vram, Keyboard, Simulate};
+use chip8::Keyboard;
+
+use framebuffer::Frame;
+use machine::MEMORY_SIZE;
+use symbols::Symbols;
+
+use self::memory::{MemoryEditor, ROW_BYTES};
+use self::sprites::SpriteViewer;
 
 
 
@@ -33,9 +47,34 @@ const KEYBOARD_XOFFSET: u32 = SCREEN_WIDTH + KEYBOARD_SEP_WIDTH;
 
 
 
+const STATUS_SCALE: u32 = 3;
+
+const PANEL_XOFFSET: u32 = WINDOW_WIDTH + KEYBOARD_SEP_WIDTH;
+const DEBUG_WINDOW_WIDTH: u32 = PANEL_XOFFSET + panels::WIDTH;
+
+
+/// Hotkeys and window events that are meant for the UI rather than the emulated keypad.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UiEvent {
+    Quit,
+    TogglePause,
+    AdvanceFrame,
+    /// Reported on every poll while the fast-forward key is held.
+    FastForward,
+    SlowDown,
+    SpeedUp,
+    ToggleDebugPanels,
+    /// A byte typed into the memory panel.
+    PokeMemory(u16, u8),
+}
+
 pub trait Interface {
-    fn draw_screen(&mut self, sim: &Simulate);
-    fn handle_input(&mut self, sim: &mut Simulate) -> bool;
+    fn draw_screen(&mut self, frame: &Frame);
+    /// Polls window events, filling in `keys` with the emulated keypad state.
+    fn handle_input(&mut self, keys: &mut Keyboard) -> Vec<UiEvent>;
+    fn set_status(&mut self, status: Option<String>);
+    /// Widens the window to make room for the debug panels, or shrinks it back.
+    fn set_debug_panels(&mut self, on: bool);
 }
 
 
@@ -43,9 +82,48 @@ pub trait Interface {
 pub struct InterfaceSdl2 {
     sdl_context: Sdl,
     renderer: Renderer<'static>,
+    status: Option<String>,
+    debug_panels: bool,
+    panel: Panel,
+    memory: MemoryEditor,
+    sprites: SpriteViewer,
+    /// From the last debug view drawn, so the sprite panel can jump to it.
+    last_sprite: Option<(u16, u8)>,
+    symbols: Arc<Symbols>,
+}
+
+/// What the debug panels show next to the registers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Panel {
+    Code,
+    Memory,
+    Sprites,
 }
+
+fn hex_digit(scancode: Scancode) -> Option<u8> {
+    match scancode {
+        Scancode::Num0 => Some(0x0),
+        Scancode::Num1 => Some(0x1),
+        Scancode::Num2 => Some(0x2),
+        Scancode::Num3 => Some(0x3),
+        Scancode::Num4 => Some(0x4),
+        Scancode::Num5 => Some(0x5),
+        Scancode::Num6 => Some(0x6),
+        Scancode::Num7 => Some(0x7),
+        Scancode::Num8 => Some(0x8),
+        Scancode::Num9 => Some(0x9),
+        Scancode::A => Some(0xA),
+        Scancode::B => Some(0xB),
+        Scancode::C => Some(0xC),
+        Scancode::D => Some(0xD),
+        Scancode::E => Some(0xE),
+        Scancode::F => Some(0xF),
+        _ => None,
+    }
+}
+
 impl InterfaceSdl2 {
-    pub fn new() -> InterfaceSdl2 {
+    pub fn new(symbols: Arc<Symbols>) -> InterfaceSdl2 {
         let sdl_context = sdl2::init().unwrap();
         let video_subsys = sdl_context.video().unwrap();
         let window = video_subsys.window("vipchip", WINDOW_WIDTH, WINDOW_HEIGHT)
@@ -60,10 +138,17 @@ impl InterfaceSdl2 {
         InterfaceSdl2 {
             sdl_context: sdl_context,
             renderer: renderer,
+            status: None,
+            debug_panels: false,
+            panel: Panel::Code,
+            memory: MemoryEditor::new(),
+            sprites: SpriteViewer::new(),
+            last_sprite: None,
+            symbols: symbols,
         }
 
     }
-    fn render_vram(&mut self, vram: &Vram) {
+    fn render_vram(&mut self, vram: &[u8]) {
         // pixels: &[[u8; 32]; 64]) {
         let mut x = 0;
         let mut y = 0;
@@ -108,29 +193,193 @@ impl InterfaceSdl2 {
             }
         }
     }
+
+    fn draw_text(&mut self, text: &str, x: i32, y: i32, scale: u32, color: Color) {
+        self.renderer.set_draw_color(color);
+        let advance = ((font::GLYPH_WIDTH + 1) * scale) as i32;
+        for (n, c) in text.chars().enumerate() {
+            let rows = font::glyph(c);
+            for (row, bits) in rows.iter().enumerate() {
+                for col in 0..font::GLYPH_WIDTH {
+                    if bits & (1 << (font::GLYPH_WIDTH - 1 - col)) != 0 {
+                        let rect = Rect::new(x + advance * n as i32 + (col * scale) as i32,
+                                             y + (row as u32 * scale) as i32,
+                                             scale,
+                                             scale);
+                        self.renderer.fill_rect(rect).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    fn panel_shown(&self, panel: Panel) -> bool {
+        self.debug_panels && self.panel == panel
+    }
+
+    fn next_panel(&mut self) {
+        self.panel = match self.panel {
+            Panel::Code => Panel::Memory,
+            Panel::Memory => Panel::Sprites,
+            Panel::Sprites => Panel::Code,
+        };
+    }
+
+    /// Scrolls the sprite panel and changes the sprite size. Returns false if the key isn't
+    /// for the sprite panel.
+    fn sprite_key(&mut self, scancode: Scancode) -> bool {
+        let (per_row, rows) = panels::sprite_layout(&self.sprites);
+        let row = (per_row * self.sprites.sprite_bytes() as usize) as i32;
+        let delta = match scancode {
+            Scancode::Left => -1,
+            Scancode::Right => 1,
+            Scancode::Up => -row,
+            Scancode::Down => row,
+            Scancode::PageUp => -row * rows as i32,
+            Scancode::PageDown => row * rows as i32,
+            Scancode::LeftBracket => {
+                self.sprites.change_height(-1);
+                return true;
+            }
+            Scancode::RightBracket => {
+                self.sprites.change_height(1);
+                return true;
+            }
+            Scancode::Home => {
+                if let Some(sprite) = self.last_sprite {
+                    self.sprites.show(sprite);
+                }
+                return true;
+            }
+            _ => return false,
+        };
+        self.sprites.move_by(delta, MEMORY_SIZE);
+        true
+    }
+
+    /// Moves the memory panel cursor, or types into memory while editing. Returns false if
+    /// the key isn't for the memory panel.
+    fn memory_key(&mut self, scancode: Scancode, ui_events: &mut Vec<UiEvent>) -> bool {
+        let rows = panels::ROWS;
+        let page = rows as i32 * ROW_BYTES as i32;
+        let delta = match scancode {
+            Scancode::Left => -1,
+            Scancode::Right => 1,
+            Scancode::Up => -(ROW_BYTES as i32),
+            Scancode::Down => ROW_BYTES as i32,
+            Scancode::PageUp => -page,
+            Scancode::PageDown => page,
+            Scancode::Return => {
+                self.memory.toggle_editing();
+                return true;
+            }
+            _ => 0,
+        };
+        if delta != 0 {
+            self.memory.move_cursor(delta, MEMORY_SIZE, rows);
+            return true;
+        }
+        if !self.memory.editing {
+            return false;
+        }
+        if let Some(digit) = hex_digit(scancode) {
+            if let Some((addr, value)) = self.memory.type_digit(digit, MEMORY_SIZE, rows) {
+                ui_events.push(UiEvent::PokeMemory(addr, value));
+            }
+        }
+        // While editing, every key but the panel hotkeys goes to the editor.
+        scancode != Scancode::F1 && scancode != Scancode::F2
+    }
+
+    fn draw_status(&mut self) {
+        let text = match self.status {
+            Some(ref status) => status.clone(),
+            None => return,
+        };
+        let width = (font::GLYPH_WIDTH + 1) * STATUS_SCALE * text.len() as u32 + STATUS_SCALE;
+        let height = (font::GLYPH_HEIGHT + 2) * STATUS_SCALE;
+        self.renderer.set_draw_color(Color::RGB(0x20, 0x20, 0x80));
+        self.renderer.fill_rect(Rect::new(0, 0, width, height)).unwrap();
+        self.draw_text(&text,
+                       STATUS_SCALE as i32,
+                       STATUS_SCALE as i32,
+                       STATUS_SCALE,
+                       Color::RGB(0xf0, 0xf0, 0x40));
+    }
 }
 impl Interface for InterfaceSdl2 {
-    fn draw_screen(&mut self, sim: &Simulate) {
-        self.render_vram(&sim.vram().unwrap());
-        self.draw_ui(&sim.keyboard().unwrap());
+    fn draw_screen(&mut self, frame: &Frame) {
+        self.render_vram(&frame.vram);
+        self.draw_ui(&frame.keyboard);
+        self.draw_status();
+        if self.debug_panels {
+            if let Some(ref view) = frame.debug {
+                self.draw_panels(view);
+            }
+        }
         self.renderer.present();
     }
 
-    fn handle_input(&mut self, sim: &mut Simulate) -> bool {
+    fn set_status(&mut self, status: Option<String>) {
+        self.status = status;
+    }
+
+    fn set_debug_panels(&mut self, on: bool) {
+        self.debug_panels = on;
+        let width = if on { DEBUG_WINDOW_WIDTH } else { WINDOW_WIDTH };
+        if let Some(window) = self.renderer.window_mut() {
+            window.set_size(width, WINDOW_HEIGHT).unwrap();
+        }
+        self.renderer.set_draw_color(Color::RGB(0x00, 0x00, 0x00));
+        self.renderer.clear();
+    }
+
+    fn handle_input(&mut self, key_state: &mut Keyboard) -> Vec<UiEvent> {
+        let mut ui_events = Vec::new();
+        let editing = self.panel_shown(Panel::Memory) && self.memory.editing;
         let mut events = self.sdl_context.event_pump().unwrap();
         for event in events.poll_iter() {
             match event {
-                Event::Quit { .. } => return false,
+                Event::Quit { .. } => ui_events.push(UiEvent::Quit),
+                Event::KeyDown { scancode: Some(scancode), repeat, .. } => {
+                    if self.panel_shown(Panel::Memory) &&
+                       self.memory_key(scancode, &mut ui_events) {
+                        continue;
+                    }
+                    if self.panel_shown(Panel::Sprites) && self.sprite_key(scancode) {
+                        continue;
+                    }
+                    if repeat {
+                        continue;
+                    }
+                    match scancode {
+                        Scancode::P => ui_events.push(UiEvent::TogglePause),
+                        Scancode::N => ui_events.push(UiEvent::AdvanceFrame),
+                        Scancode::Minus => ui_events.push(UiEvent::SlowDown),
+                        Scancode::Equals => ui_events.push(UiEvent::SpeedUp),
+                        Scancode::F1 => ui_events.push(UiEvent::ToggleDebugPanels),
+                        Scancode::F2 => self.next_panel(),
+                        _ => (),
+                    }
+                }
                 _ => (),
             }
         }
 
-        let mut key_state = [false; 16];
+        *key_state = [false; 16];
+        // Typing into the memory panel shouldn't press keypad keys too.
+        if editing {
+            return ui_events;
+        }
 
         for scancode in events.keyboard_state().pressed_scancodes() {
             match scancode {
                 Scancode::Backspace | Scancode::Escape => {
-                    return true;
+                    ui_events.push(UiEvent::Quit);
+                    return ui_events;
+                }
+                Scancode::Tab => {
+                    ui_events.push(UiEvent::FastForward);
                 }
                 Scancode::Num1 => {
                     key_state[0x1] = true;
@@ -183,7 +432,6 @@ impl Interface for InterfaceSdl2 {
                 _ => (),
             }
         }
-        sim.set_keyboard(&key_state);
-        false
+        ui_events
     }
 }
@@ -0,0 +1,325 @@
+//! Debug panels drawn to the right of the keypad: registers and the call stack, then a
+//! disassembly of the code around PC, the memory editor or the sprite viewer.
+
+use sdl2::rect::Rect;
+use sdl2::pixels::Color;
+
+use disasm::listing_line;
+use framebuffer::DebugView;
+use machine::Registers;
+use symbols::Symbols;
+
+use super::{font, InterfaceSdl2, Panel, PANEL_XOFFSET, WINDOW_HEIGHT, KEYBOARD_SEP_WIDTH};
+use super::memory::ROW_BYTES;
+use super::sprites::SpriteViewer;
+
+
+const SCALE: u32 = 2;
+const CHAR_WIDTH: u32 = (font::GLYPH_WIDTH + 1) * SCALE;
+const LINE_HEIGHT: u32 = (font::GLYPH_HEIGHT + 2) * SCALE;
+pub const ROWS: usize = (WINDOW_HEIGHT / LINE_HEIGHT) as usize;
+
+/// In characters, to cut long symbol names off at.
+const REGISTERS_COLUMNS: usize = 14;
+const DISASSEMBLY_COLUMNS: usize = 30;
+const REGISTERS_WIDTH: u32 = CHAR_WIDTH * REGISTERS_COLUMNS as u32;
+const DISASSEMBLY_WIDTH: u32 = CHAR_WIDTH * DISASSEMBLY_COLUMNS as u32;
+const DISASSEMBLY_XOFFSET: u32 = PANEL_XOFFSET + REGISTERS_WIDTH + KEYBOARD_SEP_WIDTH;
+
+/// Width of all the panels together.
+pub const WIDTH: u32 = REGISTERS_WIDTH + KEYBOARD_SEP_WIDTH + DISASSEMBLY_WIDTH;
+
+const SPRITE_SCALE: u32 = 3;
+const SPRITE_GAP: u32 = 6;
+/// Room for the address in front of each row of sprites.
+const SPRITE_LABEL_WIDTH: u32 = CHAR_WIDTH * 4;
+
+
+/// One row of the disassembly panel.
+#[derive(Debug, PartialEq)]
+pub struct CodeLine {
+    pub addr: u16,
+    pub text: String,
+    pub breakpoint: bool,
+}
+
+fn truncate(text: String, columns: usize) -> String {
+    text.chars().take(columns).collect()
+}
+
+/// The registers panel, with the call stack innermost first under it. Each return address
+/// is followed by the name of the subroutine it is in, if there's a symbol before it.
+pub fn register_lines(registers: &Registers, symbols: &Symbols) -> Vec<String> {
+    let mut lines: Vec<String> = (0..8)
+        .map(|x| {
+            format!("V{:X} {:02X}  V{:X} {:02X}",
+                    x,
+                    registers.v[x],
+                    x + 8,
+                    registers.v[x + 8])
+        })
+        .collect();
+    lines.push(String::new());
+    lines.push(format!("I  {:03X}", registers.i));
+    lines.push(format!("PC {:03X}", registers.pc));
+    lines.push(format!("SP {}", registers.stack.len()));
+    lines.push(format!("DT {:02X}  ST {:02X}", registers.delay_timer, registers.sound_timer));
+    lines.push(String::new());
+    lines.push("STACK".to_string());
+    for addr in registers.stack.iter().rev() {
+        let line = match symbols.locate(*addr) {
+            Some((name, _)) => format!("{:03X} {}", addr, name),
+            None => format!("{:03X}", addr),
+        };
+        lines.push(truncate(line, REGISTERS_COLUMNS));
+    }
+    lines
+}
+
+/// How many sprites fit in each row of the sprite panel, and how many rows fit under its
+/// heading.
+pub fn sprite_layout(viewer: &SpriteViewer) -> (usize, usize) {
+    let (width, height) = viewer.size();
+    let per_row = (DISASSEMBLY_WIDTH - CHAR_WIDTH - SPRITE_LABEL_WIDTH) /
+                  (width * SPRITE_SCALE + SPRITE_GAP);
+    let rows = (WINDOW_HEIGHT - LINE_HEIGHT) / (height * SPRITE_SCALE + SPRITE_GAP);
+    (per_row as usize, rows as usize)
+}
+
+/// `rows` instructions with PC in the middle, or as near as the start of memory allows.
+pub fn code_lines(view: &DebugView, rows: usize, symbols: &Symbols) -> Vec<CodeLine> {
+    let pc = view.registers.pc;
+    let start = pc.saturating_sub(2 * (rows / 2) as u16);
+    (0..rows as u16)
+        .map(|n| start + 2 * n)
+        .take_while(|&addr| (addr as usize) + 1 < view.memory.len())
+        .map(|addr| {
+            CodeLine {
+                addr: addr,
+                // One column goes to the breakpoint marker.
+                text: truncate(listing_line(addr, view.codeword(addr), symbols),
+                               DISASSEMBLY_COLUMNS - 1),
+                breakpoint: view.breakpoints.contains(&addr),
+            }
+        })
+        .collect()
+}
+
+impl InterfaceSdl2 {
+    pub fn draw_panels(&mut self, view: &DebugView) {
+        self.renderer.set_draw_color(Color::RGB(0x00, 0x00, 0x00));
+        self.renderer
+            .fill_rect(Rect::new(PANEL_XOFFSET as i32, 0, WIDTH, WINDOW_HEIGHT))
+            .unwrap();
+
+        let text_color = Color::RGB(0xc0, 0xc0, 0xc0);
+        let lines = register_lines(&view.registers, &self.symbols);
+        for (row, line) in lines.iter().take(ROWS).enumerate() {
+            self.draw_text(line,
+                           PANEL_XOFFSET as i32,
+                           (row as u32 * LINE_HEIGHT) as i32,
+                           SCALE,
+                           text_color);
+        }
+
+        match self.panel {
+            Panel::Code => self.draw_code(view),
+            Panel::Memory => self.draw_memory(view),
+            Panel::Sprites => self.draw_sprites(view),
+        }
+    }
+
+    fn draw_code(&mut self, view: &DebugView) {
+        let text_color = Color::RGB(0xc0, 0xc0, 0xc0);
+        // Leave room for a breakpoint marker in front of each line.
+        let x = (DISASSEMBLY_XOFFSET + CHAR_WIDTH) as i32;
+        for (row, line) in code_lines(view, ROWS, &self.symbols).iter().enumerate() {
+            let y = (row as u32 * LINE_HEIGHT) as i32;
+            if line.addr == view.registers.pc {
+                self.renderer.set_draw_color(Color::RGB(0x20, 0x20, 0x80));
+                self.renderer
+                    .fill_rect(Rect::new(DISASSEMBLY_XOFFSET as i32,
+                                         y - SCALE as i32,
+                                         DISASSEMBLY_WIDTH,
+                                         LINE_HEIGHT))
+                    .unwrap();
+            }
+            if line.breakpoint {
+                self.draw_text("*",
+                               DISASSEMBLY_XOFFSET as i32,
+                               y,
+                               SCALE,
+                               Color::RGB(0xf0, 0x40, 0x40));
+            }
+            self.draw_text(&line.text, x, y, SCALE, text_color);
+        }
+    }
+
+    /// Background for a byte of the memory panel: the cursor, then PC, I and the return
+    /// addresses on the call stack.
+    fn byte_background(&self, view: &DebugView, addr: u16) -> Option<Color> {
+        let registers = &view.registers;
+        let covers = |start: u16, len: u16| addr >= start && addr < start.saturating_add(len);
+        if addr == self.memory.cursor {
+            Some(if self.memory.editing {
+                Color::RGB(0x80, 0x60, 0x00)
+            } else {
+                Color::RGB(0x50, 0x50, 0x50)
+            })
+        } else if covers(registers.pc, 2) {
+            Some(Color::RGB(0x20, 0x20, 0x80))
+        } else if addr == registers.i {
+            Some(Color::RGB(0x20, 0x60, 0x20))
+        } else if registers.stack.iter().any(|&ret| covers(ret, 2)) {
+            Some(Color::RGB(0x60, 0x20, 0x60))
+        } else {
+            None
+        }
+    }
+
+    fn draw_memory(&mut self, view: &DebugView) {
+        self.memory.update(&view.memory);
+        let x = (DISASSEMBLY_XOFFSET + CHAR_WIDTH) as i32;
+        for row in 0..ROWS as u16 {
+            let addr = self.memory.top + row * ROW_BYTES;
+            if addr as usize >= view.memory.len() {
+                break;
+            }
+            let y = (row as u32 * LINE_HEIGHT) as i32;
+            self.draw_text(&format!("{:03X}:", addr),
+                           x,
+                           y,
+                           SCALE,
+                           Color::RGB(0x80, 0x80, 0x80));
+            for col in 0..ROW_BYTES {
+                let addr = addr + col;
+                let byte = match view.memory.get(addr as usize) {
+                    Some(&byte) => byte,
+                    None => break,
+                };
+                let cell_x = x + (CHAR_WIDTH * (5 + 3 * col as u32)) as i32;
+                if let Some(color) = self.byte_background(view, addr) {
+                    self.renderer.set_draw_color(color);
+                    self.renderer
+                        .fill_rect(Rect::new(cell_x - SCALE as i32,
+                                             y - SCALE as i32,
+                                             CHAR_WIDTH * 2 + SCALE,
+                                             LINE_HEIGHT))
+                        .unwrap();
+                }
+                let text = match self.memory.pending() {
+                    Some(high) if addr == self.memory.cursor => format!("{:X}_", high),
+                    _ => format!("{:02X}", byte),
+                };
+                let color = flash_color(self.memory.flash(addr));
+                self.draw_text(&text, cell_x, y, SCALE, color);
+            }
+        }
+    }
+
+    /// Draws sprites from the viewer's offset, outlining any that overlap the one DXYN drew
+    /// last.
+    fn draw_sprites(&mut self, view: &DebugView) {
+        self.last_sprite = view.last_sprite;
+        let (width, height) = self.sprites.size();
+        let (per_row, rows) = sprite_layout(&self.sprites);
+        let bytes = self.sprites.sprite_bytes() as usize;
+        let offset = self.sprites.offset as usize;
+        let x = (DISASSEMBLY_XOFFSET + CHAR_WIDTH) as i32;
+        let label_color = Color::RGB(0x80, 0x80, 0x80);
+        self.draw_text(&format!("{}X{} AT {:03X}", width, height, offset),
+                       x,
+                       0,
+                       SCALE,
+                       label_color);
+
+        let last = view.last_sprite.map(|(addr, n)| {
+            let len = if n == 0 { 32 } else { n as usize };
+            (addr as usize, addr as usize + len)
+        });
+        let cell_width = width * SPRITE_SCALE;
+        let cell_height = height * SPRITE_SCALE;
+        for row in 0..rows {
+            let row_addr = offset + row * per_row * bytes;
+            if row_addr >= view.memory.len() {
+                break;
+            }
+            let y = (LINE_HEIGHT + row as u32 * (cell_height + SPRITE_GAP)) as i32;
+            self.draw_text(&format!("{:03X}", row_addr), x, y, SCALE, label_color);
+            for col in 0..per_row {
+                let addr = row_addr + col * bytes;
+                if addr >= view.memory.len() {
+                    break;
+                }
+                let cell_x = x +
+                             (SPRITE_LABEL_WIDTH + col as u32 * (cell_width + SPRITE_GAP)) as i32;
+                if let Some((start, end)) = last {
+                    if addr < end && start < addr + bytes {
+                        self.renderer.set_draw_color(Color::RGB(0xf0, 0xf0, 0x40));
+                        let outline =
+                            Rect::new(cell_x - 2, y - 2, cell_width + 4, cell_height + 4);
+                        self.renderer.draw_rect(outline).unwrap();
+                    }
+                }
+                self.renderer.set_draw_color(Color::RGB(0x18, 0x18, 0x18));
+                self.renderer.fill_rect(Rect::new(cell_x, y, cell_width, cell_height)).unwrap();
+                self.renderer.set_draw_color(Color::RGB(0xf0, 0xf0, 0xf0));
+                let pixels = self.sprites.pixels(&view.memory, addr as u16);
+                for (py, line) in pixels.iter().enumerate() {
+                    for (px, &lit) in line.iter().enumerate() {
+                        if lit {
+                            let rect = Rect::new(cell_x + (px as u32 * SPRITE_SCALE) as i32,
+                                                 y + (py as u32 * SPRITE_SCALE) as i32,
+                                                 SPRITE_SCALE,
+                                                 SPRITE_SCALE);
+                            self.renderer.fill_rect(rect).unwrap();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Fades from yellow for a byte that just changed back to the normal text color.
+fn flash_color(flash: f32) -> Color {
+    let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * flash) as u8;
+    Color::RGB(mix(0xc0, 0xf0), mix(0xc0, 0xf0), mix(0xc0, 0x40))
+}
+
+#[test]
+fn test_code_lines() {
+    let mut memory = vec![0; 0x1000];
+    memory[0x200] = 0x12;
+    memory[0x201] = 0x08;
+    let view = DebugView {
+        registers: Registers {
+            v: [0; 16],
+            i: 0,
+            pc: 0x202,
+            delay_timer: 0,
+            sound_timer: 0,
+            stack: vec![0x20A],
+        },
+        memory: memory,
+        breakpoints: vec![0x200],
+        last_sprite: None,
+    };
+    let symbols = Symbols::parse("a_very_long_name = 0x208").unwrap();
+    let lines = code_lines(&view, 5, &Symbols::new());
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0],
+               CodeLine {
+                   addr: 0x1FE,
+                   text: "0x1FE: 0000  SYS 0x000".to_string(),
+                   breakpoint: false,
+               });
+    assert!(lines[1].breakpoint);
+    assert_eq!(lines[2].addr, 0x202);
+    assert_eq!(register_lines(&view.registers, &Symbols::new()).last(),
+               Some(&"20A".to_string()));
+    assert_eq!(code_lines(&view, 5, &symbols)[1].text,
+               "0x200: 1208  JP a_very_long_n");
+    assert_eq!(register_lines(&view.registers, &symbols).last(),
+               Some(&"20A a_very_lon".to_string()));
+}
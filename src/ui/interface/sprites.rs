@@ -0,0 +1,95 @@
+//! State of the sprite panel, which draws a range of memory as a grid of sprites the way
+//! DXYN would.
+
+/// Largest height of an 8 pixel wide sprite; one more switches to SCHIP 16x16 sprites.
+const MAX_HEIGHT: u8 = 15;
+
+
+pub struct SpriteViewer {
+    /// Address of the first sprite shown.
+    pub offset: u16,
+    /// Rows per sprite, or 0 for 16x16 sprites like DXY0.
+    pub height: u8,
+}
+
+impl SpriteViewer {
+    pub fn new() -> SpriteViewer {
+        SpriteViewer {
+            offset: 0x200,
+            height: 8,
+        }
+    }
+
+    /// Width and height in pixels.
+    pub fn size(&self) -> (u32, u32) {
+        match self.height {
+            0 => (16, 16),
+            n => (8, n as u32),
+        }
+    }
+
+    /// Bytes of memory in each sprite.
+    pub fn sprite_bytes(&self) -> u16 {
+        match self.height {
+            0 => 32,
+            n => n as u16,
+        }
+    }
+
+    /// Steps through heights 1-15, then 16x16, wrapping around at either end.
+    pub fn change_height(&mut self, delta: i32) {
+        let steps = MAX_HEIGHT as i32 + 1;
+        // Heights 1-15 are steps 0-14 and 16x16 is step 15.
+        let step = (self.height as i32 + steps - 1) % steps;
+        let step = ((step + delta) % steps + steps) % steps;
+        self.height = ((step + 1) % steps) as u8;
+    }
+
+    pub fn move_by(&mut self, delta: i32, size: usize) {
+        let offset = (self.offset as i32 + delta).max(0).min(size as i32 - 1);
+        self.offset = offset as u16;
+    }
+
+    /// Shows the given sprite first, at its own height.
+    pub fn show(&mut self, sprite: (u16, u8)) {
+        self.offset = sprite.0;
+        self.height = sprite.1;
+    }
+
+    /// The pixels of the sprite at `addr`, row by row. Bytes past the end of memory are
+    /// blank.
+    pub fn pixels(&self, memory: &[u8], addr: u16) -> Vec<Vec<bool>> {
+        let (width, height) = self.size();
+        let bytes_per_row = width / 8;
+        (0..height)
+            .map(|row| {
+                (0..width)
+                    .map(|col| {
+                        let at = addr as usize + (row * bytes_per_row + col / 8) as usize;
+                        let byte = memory.get(at).cloned().unwrap_or(0);
+                        byte & (0x80 >> (col % 8)) != 0
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_sprite_viewer() {
+    let mut viewer = SpriteViewer::new();
+    viewer.height = 15;
+    viewer.change_height(1);
+    assert_eq!((viewer.size(), viewer.sprite_bytes()), ((16, 16), 32));
+    viewer.change_height(1);
+    assert_eq!(viewer.height, 1);
+    viewer.change_height(-1);
+    assert_eq!(viewer.height, 0);
+
+    viewer.height = 2;
+    let pixels = viewer.pixels(&[0x81, 0x40], 0);
+    assert_eq!(pixels[0][0], true);
+    assert_eq!(pixels[0][7], true);
+    assert_eq!(pixels[1][1], true);
+    assert_eq!(pixels[1].iter().filter(|&&p| p).count(), 1);
+}
@@ -1,50 +1,119 @@
 pub mod interface;
 
 use std::thread;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender, Receiver};
 
-use chip8::{SimulatorTask, Simulate};
+use emulator::{Command, EmulatorEvent};
+use debugger::{DebugRequest, DebugReply};
+use framebuffer::FrameReader;
+use symbols::Symbols;
 
-use self::interface::{Interface, InterfaceSdl2};
+use self::interface::{Interface, InterfaceSdl2, UiEvent};
 use std::time::{Duration, SystemTime};
 
 
 
+/// The render thread side: polls input, forwards it to the emulation thread and draws the
+/// most recent frame the emulator published.
 pub struct Ui {
-    simulator: SimulatorTask,
+    commands: Sender<Command>,
+    events: Receiver<EmulatorEvent>,
+    frames: FrameReader,
     interface: Box<Interface>,
+    /// Replies to the requests sent for memory edits.
+    replies: (Sender<DebugReply>, Receiver<DebugReply>),
 }
 
 impl Ui {
-    pub fn new(simulator: SimulatorTask) -> Ui {
+    pub fn new(commands: Sender<Command>,
+               events: Receiver<EmulatorEvent>,
+               frames: FrameReader,
+               symbols: Arc<Symbols>)
+               -> Ui {
         Ui {
-            simulator: simulator,
-            interface: Box::new(InterfaceSdl2::new()),
+            commands: commands,
+            events: events,
+            frames: frames,
+            interface: Box::new(InterfaceSdl2::new(symbols)),
+            replies: mpsc::channel(),
         }
     }
 
-    pub fn run(&mut self) {
+    /// Runs until the user quits or the emulator exits, returning the process exit status.
+    pub fn run(&mut self) -> i32 {
         let park_duration = Duration::new(0, 5);
-        let frame_period = Duration::new(0, 1000000000 / 60);
-        let mut last_frame = SystemTime::now();
+        let draw_period = Duration::new(0, 1000000000 / 60);
+        let mut last_draw = SystemTime::now();
+        let mut keys = [false; 16];
+        let mut fast_forward = false;
+        let mut debug_panels = false;
         'running: loop {
-            {
-                match self.interface.handle_input(&mut self.simulator) {
-                    true => break 'running,
-                    _ => (),
+            let mut new_keys = keys;
+            let ui_events = self.interface.handle_input(&mut new_keys);
+            for event in &ui_events {
+                let command = match *event {
+                    UiEvent::Quit => break 'running,
+                    UiEvent::TogglePause => Command::TogglePause,
+                    UiEvent::AdvanceFrame => Command::AdvanceFrame,
+                    UiEvent::SlowDown => Command::SlowDown,
+                    UiEvent::SpeedUp => Command::SpeedUp,
+                    UiEvent::ToggleDebugPanels => {
+                        debug_panels = !debug_panels;
+                        self.interface.set_debug_panels(debug_panels);
+                        Command::ShowDebugView(debug_panels)
+                    }
+                    UiEvent::PokeMemory(addr, value) => {
+                        Command::Debug(DebugRequest::WriteMemory(addr, vec![value]),
+                                       self.replies.0.clone())
+                    }
+                    UiEvent::FastForward => continue,
+                };
+                self.commands.send(command).unwrap();
+            }
+            if ui_events.contains(&UiEvent::FastForward) != fast_forward {
+                fast_forward = !fast_forward;
+                self.commands.send(Command::FastForward(fast_forward)).unwrap();
+            }
+            if new_keys != keys {
+                keys = new_keys;
+                self.commands.send(Command::SetKeys(keys)).unwrap();
+            }
+
+            while let Ok(reply) = self.replies.1.try_recv() {
+                if let DebugReply::Error(e) = reply {
+                    println!("memory edit failed: {}", e);
                 }
             }
-            match last_frame.elapsed() {
+
+            while let Ok(event) = self.events.try_recv() {
+                match event {
+                    EmulatorEvent::Status(status) => self.interface.set_status(status),
+                    EmulatorEvent::Stopped(stop) => println!("stopped: {:?}", stop),
+                    EmulatorEvent::Exited(code) => return code,
+                }
+            }
+
+            match last_draw.elapsed() {
                 Ok(elapsed) => {
-                    if elapsed > frame_period {
-                        self.interface.draw_screen(&self.simulator);
-                        last_frame += frame_period;
-                        self.simulator.timer_tick().unwrap();
+                    if elapsed > draw_period {
+                        last_draw = SystemTime::now();
+                        let frame = self.frames.latest();
+                        self.interface.draw_screen(&frame);
                     }
                 }
                 _ => (),
             }
             thread::park_timeout(park_duration);
-            self.simulator.step_n(10).unwrap();
         }
+
+        // Let the emulator finish up (e.g. flush a movie) before we report that we're done.
+        self.commands.send(Command::Quit).unwrap();
+        while let Ok(event) = self.events.recv() {
+            if let EmulatorEvent::Exited(code) = event {
+                return code;
+            }
+        }
+        0
     }
 }